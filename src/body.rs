@@ -0,0 +1,158 @@
+// src/body.rs
+//
+// Text of a message body, for `body:` predicates. Bodies are fetched lazily
+// (see `IMAPClientOps::fetch_bodies`) and decoded here: the first inline
+// text/plain part wins, otherwise the first text/html part is reduced to its
+// text. Transfer encodings and charsets are undone by mailparse.
+
+use mailparse::{parse_mail, DispositionType, ParsedMail};
+
+/// Decode a raw message (header block followed by the body) into the text
+/// body predicates match against. Undecodable input is read as UTF-8.
+pub fn body_text(raw: &[u8]) -> String {
+    let Ok(mail) = parse_mail(raw) else {
+        return String::from_utf8_lossy(raw).into_owned();
+    };
+    let inline_part = |mimetype: &str| {
+        mail.parts().find(|part| {
+            part.ctype.mimetype.eq_ignore_ascii_case(mimetype)
+                && part.get_content_disposition().disposition != DispositionType::Attachment
+        })
+    };
+    if let Some(plain) = inline_part("text/plain") {
+        return decoded(plain);
+    }
+    inline_part("text/html")
+        .map(|html| strip_html(&decoded(html)))
+        .unwrap_or_default()
+}
+
+fn decoded(part: &ParsedMail<'_>) -> String {
+    part.get_body()
+        .unwrap_or_else(|_| String::from_utf8_lossy(part.raw_bytes).into_owned())
+}
+
+/// Reduce HTML to its visible text: tags become whitespace, `<script>` and
+/// `<style>` contents are dropped, common entities are decoded and runs of
+/// whitespace collapse to one space.
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        text.push_str(&rest[..open]);
+        text.push(' ');
+        let tag = &rest[open + 1..];
+        let Some(close) = tag.find('>') else {
+            rest = "";
+            break;
+        };
+        let name: String = tag
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        rest = &tag[close + 1..];
+        if name == "script" || name == "style" {
+            let end = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&end) {
+                Some(at) => rest[at..].find('>').map_or("", |gt| &rest[at + gt + 1..]),
+                None => "",
+            };
+        }
+    }
+    text.push_str(rest);
+    decode_entities(&text).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let entity = &rest[amp + 1..];
+        let decoded = entity.find(';').filter(|end| *end <= 8).and_then(|end| {
+            let c = match &entity[..end] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                num => num
+                    .strip_prefix("#x")
+                    .or_else(|| num.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| num.strip_prefix('#').map(str::parse))
+                    .and_then(|n| n.ok())
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &entity[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = entity;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text_is_decoded() {
+        let raw = b"Subject: hi\r\n\
+                    Content-Type: text/plain; charset=iso-8859-1\r\n\
+                    Content-Transfer-Encoding: quoted-printable\r\n\
+                    \r\n\
+                    Gr=FC=DFe, your build failed\r\n";
+        assert_eq!(body_text(raw).trim(), "Grüße, your build failed");
+    }
+
+    #[test]
+    fn test_multipart_prefers_plain_over_html() {
+        let raw = b"Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+                    \r\n\
+                    --b\r\n\
+                    Content-Type: text/html\r\n\
+                    \r\n\
+                    <p>HTML version</p>\r\n\
+                    --b\r\n\
+                    Content-Type: text/plain\r\n\
+                    Content-Transfer-Encoding: base64\r\n\
+                    \r\n\
+                    VGlja2V0IE9QUy0xMjM=\r\n\
+                    --b--\r\n";
+        assert_eq!(body_text(raw).trim(), "Ticket OPS-123");
+    }
+
+    #[test]
+    fn test_html_only_is_stripped() {
+        let raw = b"Content-Type: text/html; charset=utf-8\r\n\
+                    \r\n\
+                    <html><style>p { color: red }</style><body><p>Build&nbsp;<b>failed</b> &amp; \
+                    retried &#8212; see <a href=\"x\">logs</a></p><script>track()</script></body></html>\r\n";
+        assert_eq!(body_text(raw), "Build failed & retried \u{2014} see logs");
+    }
+
+    #[test]
+    fn test_attachments_are_ignored() {
+        let raw = b"Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+                    \r\n\
+                    --b\r\n\
+                    Content-Type: text/plain\r\n\
+                    Content-Disposition: attachment; filename=\"log.txt\"\r\n\
+                    \r\n\
+                    build failed\r\n\
+                    --b--\r\n";
+        assert_eq!(body_text(raw), "");
+    }
+}
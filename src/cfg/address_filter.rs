@@ -0,0 +1,302 @@
+// src/cfg/address_filter.rs
+//
+// Address predicates for message filters, one per address header plus
+// `recipients`, which spans every header naming a recipient. An empty
+// pattern list requires the header to name nobody. The `*-name` predicates
+// read display names instead, and `spoofed-from` catches a From display name
+// that carries some other address. `from-domain`/`to-domain` and
+// `internal-sender` compare domains, subdomains included.
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::cfg::domain::{self, Domain};
+use crate::cfg::message_filter::compile_patterns;
+use crate::cfg::pattern::Pattern;
+use crate::message::{EmailAddress, Message};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct AddressFilter {
+    pub patterns: Vec<Pattern>,
+}
+
+impl AddressFilter {
+    /// Returns true if **any** of the `emails` matches **any** of `self.patterns`.
+    pub fn matches(&self, emails: &[String]) -> bool {
+        self.patterns
+            .iter()
+            .any(|pat| emails.iter().any(|email| pat.is_match(email)))
+    }
+
+    /// With patterns, some value must match; without, there must be none.
+    fn holds<'a>(&self, values: impl Iterator<Item = &'a str>) -> bool {
+        let values: Vec<String> = values.map(str::to_string).collect();
+        if self.patterns.is_empty() {
+            values.is_empty()
+        } else {
+            self.matches(&values)
+        }
+    }
+}
+
+/// Every address predicate; an absent one always holds.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AddressFilters {
+    pub to: Option<AddressFilter>,
+    pub cc: Option<AddressFilter>,
+    pub bcc: Option<AddressFilter>,
+    pub from: Option<AddressFilter>,
+    pub sender: Option<AddressFilter>,
+    pub reply_to: Option<AddressFilter>,
+    pub delivered_to: Option<AddressFilter>,
+    pub return_path: Option<AddressFilter>,
+    pub list_post: Option<AddressFilter>,
+    /// To, Cc, Bcc and Delivered-To together.
+    pub recipients: Option<AddressFilter>,
+    /// Display names; `[]` requires the header to carry none.
+    pub from_name: Option<AddressFilter>,
+    pub sender_name: Option<AddressFilter>,
+    pub reply_to_name: Option<AddressFilter>,
+    /// `true` requires a From display name holding an address other than
+    /// the one it labels, `false` forbids it.
+    pub spoofed_from: Option<bool>,
+    /// Some From address must be in one of these domains or below.
+    pub from_domain: Vec<Domain>,
+    /// Some To address must be in one of these domains or below.
+    pub to_domain: Vec<Domain>,
+    /// `true` requires a From address in `internal_domains`, `false` forbids it.
+    pub internal_sender: Option<bool>,
+    /// The config's `internal-domains`; filled in by the engine, not parsed.
+    pub internal_domains: Vec<Domain>,
+}
+
+impl AddressFilters {
+    pub fn matches(&self, msg: &Message) -> bool {
+        let holds = |filter: &Option<AddressFilter>, addrs: &[EmailAddress]| {
+            filter
+                .as_ref()
+                .is_none_or(|af| af.holds(addrs.iter().map(|a| a.email.as_str())))
+        };
+        let names = |filter: &Option<AddressFilter>, addrs: &[EmailAddress]| {
+            let named = addrs.iter().map(|a| a.name.as_str()).filter(|name| !name.is_empty());
+            filter.as_ref().is_none_or(|af| af.holds(named))
+        };
+        holds(&self.to, &msg.to)
+            && holds(&self.cc, &msg.cc)
+            && holds(&self.bcc, &msg.bcc)
+            && holds(&self.from, &msg.from)
+            && holds(&self.sender, &msg.sender)
+            && holds(&self.reply_to, &msg.reply_to)
+            && holds(&self.delivered_to, &msg.delivered_to)
+            && holds(&self.return_path, &msg.return_path)
+            && holds(&self.list_post, &msg.list_post)
+            && self
+                .recipients
+                .as_ref()
+                .is_none_or(|af| af.holds(msg.recipients().map(|a| a.email.as_str())))
+            && names(&self.from_name, &msg.from)
+            && names(&self.sender_name, &msg.sender)
+            && names(&self.reply_to_name, &msg.reply_to)
+            && self
+                .spoofed_from
+                .is_none_or(|wanted| msg.from.iter().any(EmailAddress::name_spoofs_address) == wanted)
+            && (self.from_domain.is_empty() || in_domains(&msg.from, &self.from_domain))
+            && (self.to_domain.is_empty() || in_domains(&msg.to, &self.to_domain))
+            && self
+                .internal_sender
+                .is_none_or(|wanted| in_domains(&msg.from, &self.internal_domains) == wanted)
+    }
+}
+
+/// Whether any of `addrs` is in one of `domains`, subdomains included.
+fn in_domains(addrs: &[EmailAddress], domains: &[Domain]) -> bool {
+    addrs
+        .iter()
+        .filter_map(|a| Domain::of_address(&a.email))
+        .any(|d| domains.iter().any(|parent| d.is_within(parent)))
+}
+
+impl<'de> Deserialize<'de> for AddressFilters {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Fields {
+            #[serde(default)]
+            to: Option<Value>,
+            #[serde(default)]
+            cc: Option<Value>,
+            #[serde(default)]
+            bcc: Option<Value>,
+            #[serde(default)]
+            from: Option<Value>,
+            #[serde(default)]
+            sender: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "reply-to")]
+            reply_to: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "delivered-to")]
+            delivered_to: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "return-path")]
+            return_path: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "list-post")]
+            list_post: Option<Value>,
+            #[serde(default)]
+            recipients: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "from-name")]
+            from_name: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "sender-name")]
+            sender_name: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "reply-to-name")]
+            reply_to_name: Option<Value>,
+            #[serde(default)]
+            #[serde(rename = "spoofed-from")]
+            spoofed_from: Option<bool>,
+            #[serde(default)]
+            #[serde(rename = "from-domain")]
+            #[serde(deserialize_with = "domain::deserialize_domains")]
+            from_domain: Vec<Domain>,
+            #[serde(default)]
+            #[serde(rename = "to-domain")]
+            #[serde(deserialize_with = "domain::deserialize_domains")]
+            to_domain: Vec<Domain>,
+            #[serde(default)]
+            #[serde(rename = "internal-sender")]
+            internal_sender: Option<bool>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        Ok(AddressFilters {
+            to: address_filter("to", f.to)?,
+            cc: address_filter("cc", f.cc)?,
+            bcc: address_filter("bcc", f.bcc)?,
+            from: address_filter("from", f.from)?,
+            sender: address_filter("sender", f.sender)?,
+            reply_to: address_filter("reply-to", f.reply_to)?,
+            delivered_to: address_filter("delivered-to", f.delivered_to)?,
+            return_path: address_filter("return-path", f.return_path)?,
+            list_post: address_filter("list-post", f.list_post)?,
+            recipients: address_filter("recipients", f.recipients)?,
+            from_name: address_filter("from-name", f.from_name)?,
+            sender_name: address_filter("sender-name", f.sender_name)?,
+            reply_to_name: address_filter("reply-to-name", f.reply_to_name)?,
+            spoofed_from: f.spoofed_from,
+            from_domain: f.from_domain,
+            to_domain: f.to_domain,
+            internal_sender: f.internal_sender,
+            internal_domains: Vec::new(),
+        })
+    }
+}
+
+/// A pattern, a list of them, or `{ patterns: [...] }`; `field` names the
+/// field in errors.
+fn address_filter<E: de::Error>(field: &str, v: Option<Value>) -> Result<Option<AddressFilter>, E> {
+    match v {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Mapping(mut map)) => match map.remove("patterns") {
+            Some(patterns) if map.is_empty() => Ok(Some(AddressFilter {
+                patterns: compile_patterns(field, patterns)?,
+            })),
+            _ => Err(E::custom(format!("`{}`: expected only `patterns`", field))),
+        },
+        Some(other) => Ok(Some(AddressFilter {
+            patterns: compile_patterns(field, other)?,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(headers: &str) -> Message {
+        Message::new(
+            1,
+            1,
+            format!("{}\r\n\r\n", headers).into_bytes(),
+            vec![],
+            "2024-01-01T00:00:00+00:00".parse().ok(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_each_header_has_its_own_predicate() {
+        let msg = message(
+            "From: list@example.org\r\nSender: owner-dev@example.org\r\nReply-To: dev@example.org\r\n\
+             Return-Path: <bounces@example.org>\r\nList-Post: <mailto:dev@example.org?subject=hi>\r\n\
+             To: dev@example.org",
+        );
+        let filter: AddressFilters = serde_yaml::from_str(
+            "{ sender: 'owner-*', reply-to: dev@example.org, return-path: 'bounces@*', list-post: dev@example.org }",
+        )
+        .unwrap();
+        assert!(filter.matches(&msg));
+
+        let wrong: AddressFilters = serde_yaml::from_str("{ reply-to: list@example.org }").unwrap();
+        assert!(!wrong.matches(&msg));
+        // an empty list requires the header to name nobody
+        let no_bcc: AddressFilters = serde_yaml::from_str("{ bcc: [], delivered-to: [] }").unwrap();
+        assert!(no_bcc.matches(&msg));
+        assert!(serde_yaml::from_str::<AddressFilters>("{ sender: { pattern: x } }").is_err());
+    }
+
+    #[test]
+    fn test_recipients_span_to_cc_bcc_and_delivered_to() {
+        let undisclosed = message("From: a@example.com\r\nDelivered-To: me@example.com");
+        let only_to_me: AddressFilters = serde_yaml::from_str("to: me@example.com").unwrap();
+        assert!(!only_to_me.matches(&undisclosed));
+
+        let to_me: AddressFilters = serde_yaml::from_str("recipients: me@example.com").unwrap();
+        assert!(to_me.matches(&undisclosed));
+        assert!(to_me.matches(&message("From: a@example.com\r\nBcc: me@example.com")));
+        assert!(!to_me.matches(&message("From: a@example.com\r\nTo: you@example.com")));
+
+        let nobody: AddressFilters = serde_yaml::from_str("recipients: []").unwrap();
+        assert!(!nobody.matches(&undisclosed));
+        assert!(nobody.matches(&message("From: a@example.com")));
+    }
+
+    #[test]
+    fn test_display_names_and_spoofed_from() {
+        let jenkins = message("From: \"Jenkins (ci)\" <ci@example.com>");
+        let filter: AddressFilters = serde_yaml::from_str("from-name: ['*Jenkins*']").unwrap();
+        assert!(filter.matches(&jenkins));
+        assert!(!filter.matches(&message("From: ci@example.com")));
+        let unnamed: AddressFilters = serde_yaml::from_str("from-name: []").unwrap();
+        assert!(unnamed.matches(&message("From: ci@example.com")));
+        assert!(!unnamed.matches(&jenkins));
+
+        let spoofed: AddressFilters = serde_yaml::from_str("spoofed-from: true").unwrap();
+        assert!(spoofed.matches(&message("From: \"ceo@example.com\" <ceo.office@evil.example>")));
+        assert!(spoofed.matches(&message("From: \"Ann Lee (ann@example.com)\" <x@evil.example>")));
+        // the name repeating the real address, or no address at all, is fine
+        assert!(!spoofed.matches(&message("From: \"CEO@Example.com\" <ceo@example.com>")));
+        assert!(!spoofed.matches(&message("From: \"Ann @ Example\" <ann@example.com>")));
+        assert!(!spoofed.matches(&jenkins));
+    }
+
+    #[test]
+    fn test_domains_and_internal_sender() {
+        let filter: AddressFilters =
+            serde_yaml::from_str("{ from-domain: tatari.tv, to-domain: [example.org] }").unwrap();
+        assert!(filter.matches(&message("From: ann@mail.tatari.tv\r\nTo: me@example.org")));
+        assert!(!filter.matches(&message("From: ann@nottatari.tv\r\nTo: me@example.org")));
+        assert!(!filter.matches(&message("From: ann@tatari.tv\r\nTo: me@example.com")));
+        assert!(serde_yaml::from_str::<AddressFilters>("from-domain: co.uk").is_err());
+
+        let mut external: AddressFilters = serde_yaml::from_str("internal-sender: false").unwrap();
+        external.internal_domains = vec![Domain::parse("tatari.tv").unwrap()];
+        assert!(external.matches(&message("From: ann@evil.example")));
+        assert!(!external.matches(&message("From: ann@ops.tatari.tv")));
+    }
+}
@@ -6,14 +6,16 @@ use secure_string::SecureString;
 use serde::de::{self, Deserializer};
 use serde::Deserialize;
 use serde_yaml::{from_value, Value};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
+use crate::cfg::domain::{self, Domain};
 use crate::cfg::message_filter::MessageFilter;
 use crate::cfg::secure;
 use crate::cfg::state_filter::StateFilter;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     #[serde(alias = "imap-domain")]
     pub imap_domain: Option<String>,
@@ -42,17 +44,193 @@ pub struct Config {
     )]
     pub oauth2_refresh_token: Option<SecureString>,
 
+    /// Mailboxes to scan, in order. Defaults to INBOX only.
+    #[serde(default = "default_mailboxes", alias = "mailbox")]
+    #[serde(deserialize_with = "deserialize_string_list")]
+    pub mailboxes: Vec<String>,
+
+    /// The organization's own domains, for `internal-sender`.
+    #[serde(rename = "internal-domains", alias = "internal-domain", default)]
+    #[serde(deserialize_with = "domain::deserialize_domains")]
+    pub internal_domains: Vec<Domain>,
+
     /// flatten name + body into Vec<MessageFilter>
-    #[serde(rename = "message-filters")]
+    #[serde(rename = "message-filters", default)]
     #[serde(deserialize_with = "deserialize_named_filters")]
     pub message_filters: Vec<MessageFilter>,
 
     /// flatten name + body into Vec<StateFilter>
-    #[serde(rename = "state-filters")]
+    #[serde(rename = "state-filters", default)]
+    #[serde(deserialize_with = "deserialize_named_states")]
+    pub state_filters: Vec<StateFilter>,
+
+    /// Shared filter groups that accounts reference by name
+    #[serde(rename = "filter-sets", default)]
+    pub filter_sets: BTreeMap<String, FilterSet>,
+
+    /// flatten name + body into Vec<AccountConfig>; empty means the top-level
+    /// connection and filters form the only account
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_named_accounts")]
+    pub accounts: Vec<AccountConfig>,
+}
+
+/// A named group of filters that several accounts can share.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FilterSet {
+    #[serde(rename = "message-filters", default)]
+    #[serde(deserialize_with = "deserialize_named_filters")]
+    pub message_filters: Vec<MessageFilter>,
+
+    #[serde(rename = "state-filters", default)]
+    #[serde(deserialize_with = "deserialize_named_states")]
+    pub state_filters: Vec<StateFilter>,
+}
+
+/// One entry under `accounts:`. Connection fields left out fall back to the
+/// top-level ones.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountConfig {
+    /// Map‐key → this field is set in `deserialize_named_accounts`
+    #[serde(skip_deserializing, default)]
+    pub name: String,
+
+    #[serde(alias = "imap-domain", default)]
+    pub imap_domain: Option<String>,
+
+    #[serde(alias = "imap-username", default)]
+    pub imap_username: Option<String>,
+
+    #[serde(alias = "imap-password", default, deserialize_with = "secure::deserialize_opt")]
+    pub imap_password: Option<SecureString>,
+
+    #[serde(alias = "oauth2-client-id", default, deserialize_with = "secure::deserialize_opt")]
+    pub oauth2_client_id: Option<SecureString>,
+
+    #[serde(
+        alias = "oauth2-client-secret",
+        default,
+        deserialize_with = "secure::deserialize_opt"
+    )]
+    pub oauth2_client_secret: Option<SecureString>,
+
+    #[serde(
+        alias = "oauth2-refresh-token",
+        default,
+        deserialize_with = "secure::deserialize_opt"
+    )]
+    pub oauth2_refresh_token: Option<SecureString>,
+
+    /// Mailboxes to scan; defaults to the top-level `mailboxes`
+    #[serde(default, alias = "mailbox")]
+    #[serde(deserialize_with = "deserialize_string_list")]
+    pub mailboxes: Vec<String>,
+
+    /// Defaults to the top-level `internal-domains`
+    #[serde(rename = "internal-domains", alias = "internal-domain", default)]
+    #[serde(deserialize_with = "domain::deserialize_domains")]
+    pub internal_domains: Vec<Domain>,
+
+    /// Names of `filter-sets` to apply, after the account's own filters
+    #[serde(rename = "filter-sets", alias = "filter-set", default)]
+    #[serde(deserialize_with = "deserialize_string_list")]
+    pub filter_sets: Vec<String>,
+
+    #[serde(rename = "message-filters", default)]
+    #[serde(deserialize_with = "deserialize_named_filters")]
+    pub message_filters: Vec<MessageFilter>,
+
+    #[serde(rename = "state-filters", default)]
     #[serde(deserialize_with = "deserialize_named_states")]
     pub state_filters: Vec<StateFilter>,
 }
 
+/// A fully resolved account: its name and the single-account config the
+/// engine runs with.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub config: Config,
+}
+
+impl Config {
+    /// Split into the accounts to process. Without an `accounts:` section the
+    /// whole config is one account, named after its username. With one, each
+    /// account gets its own filters followed by those of every referenced
+    /// filter set, in order.
+    pub fn into_accounts(self) -> Result<Vec<Account>> {
+        if self.accounts.is_empty() {
+            validate(&self)?;
+            let name = self.imap_username.clone().unwrap_or_else(|| "default".to_string());
+            return Ok(vec![Account { name, config: self }]);
+        }
+        if !self.message_filters.is_empty() || !self.state_filters.is_empty() {
+            return Err(eyre!(
+                "Top-level filters cannot be combined with `accounts:`; move them into a filter set"
+            ));
+        }
+
+        let mut resolved: Vec<Account> = Vec::new();
+        for account in &self.accounts {
+            if resolved.iter().any(|a| a.name == account.name) {
+                return Err(eyre!("Account '{}' is defined more than once", account.name));
+            }
+
+            let mut message_filters = account.message_filters.clone();
+            let mut state_filters = account.state_filters.clone();
+            for set_name in &account.filter_sets {
+                let set = self.filter_sets.get(set_name).ok_or_else(|| {
+                    eyre!(
+                        "Account '{}' references unknown filter set '{}'",
+                        account.name,
+                        set_name
+                    )
+                })?;
+                message_filters.extend(set.message_filters.iter().cloned());
+                state_filters.extend(set.state_filters.iter().cloned());
+            }
+
+            let config = Config {
+                imap_domain: account.imap_domain.clone().or_else(|| self.imap_domain.clone()),
+                imap_username: account.imap_username.clone().or_else(|| self.imap_username.clone()),
+                imap_password: account.imap_password.clone().or_else(|| self.imap_password.clone()),
+                oauth2_client_id: account
+                    .oauth2_client_id
+                    .clone()
+                    .or_else(|| self.oauth2_client_id.clone()),
+                oauth2_client_secret: account
+                    .oauth2_client_secret
+                    .clone()
+                    .or_else(|| self.oauth2_client_secret.clone()),
+                oauth2_refresh_token: account
+                    .oauth2_refresh_token
+                    .clone()
+                    .or_else(|| self.oauth2_refresh_token.clone()),
+                mailboxes: if account.mailboxes.is_empty() {
+                    self.mailboxes.clone()
+                } else {
+                    account.mailboxes.clone()
+                },
+                internal_domains: if account.internal_domains.is_empty() {
+                    self.internal_domains.clone()
+                } else {
+                    account.internal_domains.clone()
+                },
+                message_filters,
+                state_filters,
+                filter_sets: BTreeMap::new(),
+                accounts: Vec::new(),
+            };
+            validate(&config).map_err(|e| eyre!("Account '{}': {}", account.name, e))?;
+            resolved.push(Account {
+                name: account.name.clone(),
+                config,
+            });
+        }
+        Ok(resolved)
+    }
+}
+
 pub fn load_config(config_path: &Path) -> Result<Config> {
     debug!("Loading configuration from {:?}", config_path);
 
@@ -66,10 +244,76 @@ pub fn load_config(config_path: &Path) -> Result<Config> {
         eyre!("Failed to parse YAML: {}", e)
     })?;
 
+    validate(&cfg)?;
+
     debug!("Successfully loaded configuration");
     Ok(cfg)
 }
 
+fn default_mailboxes() -> Vec<String> {
+    vec!["INBOX".to_string()]
+}
+
+/// Checks that need the whole config, not just one filter.
+fn validate(cfg: &Config) -> Result<()> {
+    validate_mailboxes(cfg)?;
+    for filter in &cfg.message_filters {
+        filter.validate_actions().inspect_err(|e| error!("{}", e))?;
+        if cfg.internal_domains.is_empty() && filter.uses_internal_sender() {
+            error!(
+                "Filter '{}' uses `internal-sender` without `internal-domains`",
+                filter.name
+            );
+            return Err(eyre!(
+                "Filter '{}' uses `internal-sender`, but no `internal-domains` are configured",
+                filter.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Every mailbox a filter is scoped to must be one the run scans, or the
+/// filter would silently never apply.
+fn validate_mailboxes(cfg: &Config) -> Result<()> {
+    let scoped = cfg
+        .message_filters
+        .iter()
+        .map(|f| (&f.name, &f.mailboxes))
+        .chain(cfg.state_filters.iter().map(|f| (&f.name, &f.mailboxes)));
+    for (name, mailboxes) in scoped {
+        if let Some(unknown) = mailboxes.iter().find(|m| !cfg.mailboxes.contains(m)) {
+            error!("Filter '{}' names mailbox '{}', which is not scanned", name, unknown);
+            return Err(eyre!(
+                "Filter '{}' names mailbox '{}', which is not in `mailboxes` {:?}",
+                name,
+                unknown,
+                cfg.mailboxes
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Accepts `key: Foo` or `key: [Foo, Bar]`.
+pub(crate) fn deserialize_string_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v = Value::deserialize(deserializer).map_err(de::Error::custom)?;
+    match v {
+        Value::String(s) => Ok(vec![s]),
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .map(|val| match val {
+                Value::String(s) => Ok(s),
+                _ => Err(de::Error::custom("Expected a string entry")),
+            })
+            .collect(),
+        _ => Err(de::Error::custom("Expected a string or a list of strings")),
+    }
+}
+
 fn deserialize_named_filters<'de, D>(deserializer: D) -> Result<Vec<MessageFilter>, D::Error>
 where
     D: Deserializer<'de>,
@@ -90,7 +334,8 @@ where
                 Value::String(s) => s,
                 _ => return Err(de::Error::custom("Filter name must be a string")),
             };
-            let mut filt: MessageFilter = from_value(v).map_err(de::Error::custom)?;
+            let mut filt: MessageFilter =
+                from_value(v).map_err(|e| de::Error::custom(format!("Filter '{}': {}", name, e)))?;
             filt.name = name.clone();
             out.push(filt);
         } else {
@@ -129,3 +374,174 @@ where
     }
     Ok(out)
 }
+
+fn deserialize_named_accounts<'de, D>(deserializer: D) -> Result<Vec<AccountConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v = Value::deserialize(deserializer).map_err(de::Error::custom)?;
+    let seq = match v {
+        Value::Sequence(s) => s,
+        _ => return Err(de::Error::custom("`accounts` must be a sequence")),
+    };
+    let mut out = Vec::new();
+    for entry in seq {
+        if let Value::Mapping(map) = entry {
+            if map.len() != 1 {
+                return Err(de::Error::custom("Each account must have exactly one name→body"));
+            }
+            let (k, v) = map.into_iter().next().unwrap();
+            let name = match k {
+                Value::String(s) => s,
+                _ => return Err(de::Error::custom("Account name must be a string")),
+            };
+            let mut account: AccountConfig = from_value(v).map_err(de::Error::custom)?;
+            account.name = name;
+            out.push(account);
+        } else {
+            return Err(de::Error::custom("Invalid entry in accounts list"));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mailboxes_default_to_inbox() {
+        let cfg: Config = serde_yaml::from_str("message-filters: []\nstate-filters: []\n").unwrap();
+        assert_eq!(cfg.mailboxes, vec!["INBOX"]);
+
+        let cfg: Config = serde_yaml::from_str("mailbox: Purgatory\nmessage-filters: []\nstate-filters: []\n").unwrap();
+        assert_eq!(cfg.mailboxes, vec!["Purgatory"]);
+    }
+
+    const ACCOUNTS: &str = r#"
+imap-domain: imap.gmail.com
+filter-sets:
+  cleanup:
+    state-filters:
+      - Cull:
+          ttl: 7d
+          action: Purgatory
+accounts:
+  - personal:
+      imap-username: me@gmail.com
+      imap-password: hunter2
+      filter-sets: [cleanup]
+      message-filters:
+        - from-mom:
+            from: 'mom@*'
+            action: Star
+  - shared:
+      imap-domain: imap.example.com
+      imap-username: team@example.com
+      mailboxes: [INBOX, Purgatory]
+      filter-set: cleanup
+"#;
+
+    #[test]
+    fn test_single_account_config_is_its_own_account() {
+        let cfg: Config =
+            serde_yaml::from_str("imap-username: me@gmail.com\nmessage-filters: []\nstate-filters: []\n").unwrap();
+        let accounts = cfg.into_accounts().unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].name, "me@gmail.com");
+    }
+
+    #[test]
+    fn test_accounts_resolve_filter_sets_and_connection() {
+        let cfg: Config = serde_yaml::from_str(ACCOUNTS).unwrap();
+        let accounts = cfg.into_accounts().unwrap();
+        assert_eq!(
+            accounts.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+            vec!["personal", "shared"]
+        );
+
+        let personal = &accounts[0].config;
+        assert_eq!(personal.imap_domain.as_deref(), Some("imap.gmail.com"));
+        assert_eq!(personal.imap_password.as_ref().unwrap().unsecure(), "hunter2");
+        assert_eq!(personal.message_filters[0].name, "from-mom");
+        assert_eq!(personal.state_filters[0].name, "Cull");
+        assert_eq!(personal.mailboxes, vec!["INBOX"]);
+
+        let shared = &accounts[1].config;
+        assert_eq!(shared.imap_domain.as_deref(), Some("imap.example.com"));
+        assert!(shared.imap_password.is_none());
+        assert!(shared.message_filters.is_empty());
+        assert_eq!(shared.state_filters[0].name, "Cull");
+        assert_eq!(shared.mailboxes, vec!["INBOX", "Purgatory"]);
+    }
+
+    #[test]
+    fn test_accounts_reject_unknown_filter_set() {
+        let yaml = ACCOUNTS.replace("filter-set: cleanup", "filter-set: missing");
+        let cfg: Config = serde_yaml::from_str(&yaml).unwrap();
+        let err = cfg.into_accounts().unwrap_err().to_string();
+        assert!(err.contains("'shared'"));
+        assert!(err.contains("'missing'"));
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected_at_load() {
+        let yaml = r#"
+message-filters:
+  - ops:
+      subject: ['re:^[ops']
+      action: Star
+"#;
+        let err = serde_yaml::from_str::<Config>(yaml).unwrap_err().to_string();
+        assert!(
+            err.contains("Filter 'ops': `subject`: Invalid regex 're:^[ops'"),
+            "{}",
+            err
+        );
+
+        let yaml = yaml.replace("subject: ['re:^[ops']", "headers: { List-Id: ['glob:[ops'] }");
+        let err = serde_yaml::from_str::<Config>(&yaml).unwrap_err().to_string();
+        assert!(err.contains("Filter 'ops': `headers.List-Id`: Invalid glob"), "{}", err);
+
+        let yaml = yaml.replace("headers: { List-Id: ['glob:[ops'] }", "any: [{ cc: 'ire:(' }]");
+        let err = serde_yaml::from_str::<Config>(&yaml).unwrap_err().to_string();
+        assert!(err.contains("Filter 'ops': `cc`: Invalid regex"), "{}", err);
+    }
+
+    #[test]
+    fn test_internal_sender_needs_internal_domains() {
+        let yaml = "message-filters:\n  - outside:\n      any: [{ internal-sender: false }]\n      action: External\n";
+        let err = serde_yaml::from_str::<Config>(yaml)
+            .unwrap()
+            .into_accounts()
+            .unwrap_err();
+        assert!(err.to_string().contains("internal-domains"));
+
+        let cfg: Config = serde_yaml::from_str(&format!("internal-domains: [tatari.tv, 食狮.中国]\n{}", yaml)).unwrap();
+        assert_eq!(cfg.internal_domains[1].as_str(), "xn--85x722f.xn--fiqs8s");
+        assert!(cfg.into_accounts().is_ok());
+        assert!(serde_yaml::from_str::<Config>("internal-domains: com\n").is_err());
+    }
+
+    #[test]
+    fn test_filter_mailboxes_must_be_scanned() {
+        let yaml = r#"
+mailboxes: [INBOX, Purgatory]
+message-filters: []
+state-filters:
+  - Purge:
+      mailbox: Purgatory
+      ttl: 3d
+      action: Oblivion
+  - Cull:
+      mailboxes: [INBOX, Archive]
+      ttl: 7d
+      action: Purgatory
+"#;
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.state_filters[0].mailboxes, vec!["Purgatory"]);
+        let err = validate_mailboxes(&cfg).unwrap_err().to_string();
+        assert!(err.contains("'Cull'"));
+        assert!(err.contains("'Archive'"));
+    }
+}
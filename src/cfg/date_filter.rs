@@ -0,0 +1,187 @@
+// src/cfg/date_filter.rs
+//
+// Time predicates for message filters: age relative to the run's clock,
+// absolute `before`/`after` bounds and the weekday. They read INTERNALDATE
+// (when the server received the message) unless `date: header` picks the
+// sender's `Date:` header instead.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc, Weekday};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::cfg::config::deserialize_string_list;
+use crate::message::{DateSource, Message};
+use crate::utils::parse_interval;
+
+/// Exclusive bounds on a message's age, written `{ over: 2d, under: 12h }`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AgeRange {
+    pub over: Option<Duration>,
+    pub under: Option<Duration>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct DateFilter {
+    #[serde(default)]
+    pub age: AgeRange,
+
+    /// The message must be dated strictly before this instant.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_instant")]
+    pub before: Option<DateTime<Utc>>,
+
+    /// The message must be dated at or after this instant.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_instant")]
+    pub after: Option<DateTime<Utc>>,
+
+    /// Days of the week, in UTC.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_weekdays")]
+    pub weekday: Vec<Weekday>,
+
+    #[serde(default)]
+    pub date: DateSource,
+}
+
+impl DateFilter {
+    /// Whether no time predicate is set.
+    pub fn is_empty(&self) -> bool {
+        self.age == AgeRange::default() && self.before.is_none() && self.after.is_none() && self.weekday.is_empty()
+    }
+
+    /// Whether `msg` satisfies every time predicate as of `now`. The other
+    /// timestamp stands in when `date` names a missing one; a message with
+    /// neither matches none.
+    pub fn matches(&self, msg: &Message, now: DateTime<Utc>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(at) = msg.date(self.date) else {
+            return false;
+        };
+        let age = now.signed_duration_since(at);
+        self.age.over.is_none_or(|over| age > over)
+            && self.age.under.is_none_or(|under| age < under)
+            && self.before.is_none_or(|before| at < before)
+            && self.after.is_none_or(|after| at >= after)
+            && (self.weekday.is_empty() || self.weekday.contains(&at.weekday()))
+    }
+}
+
+impl<'de> Deserialize<'de> for AgeRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Bounds {
+            #[serde(default)]
+            over: Option<String>,
+            #[serde(default)]
+            under: Option<String>,
+        }
+
+        let bounds = Bounds::deserialize(deserializer)?;
+        let bound = |v: Option<String>| -> Result<Option<Duration>, D::Error> {
+            v.map(|s| {
+                parse_interval(&s)
+                    .map_err(de::Error::custom)
+                    .and_then(|d| Duration::from_std(d).map_err(de::Error::custom))
+            })
+            .transpose()
+        };
+        Ok(AgeRange {
+            over: bound(bounds.over)?,
+            under: bound(bounds.under)?,
+        })
+    }
+}
+
+/// `2024-06-01` (midnight UTC) or a full RFC 3339 timestamp.
+fn deserialize_instant<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    if let Ok(day) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+        return Ok(Some(day.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()));
+    }
+    DateTime::parse_from_rfc3339(&s)
+        .map(|dt| Some(dt.with_timezone(&Utc)))
+        .map_err(|_| de::Error::custom(format!("Invalid date '{}'; expected YYYY-MM-DD or RFC 3339", s)))
+}
+
+fn deserialize_weekdays<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_string_list(deserializer)?
+        .iter()
+        .map(|day| {
+            day.parse::<Weekday>()
+                .map_err(|_| de::Error::custom(format!("Invalid weekday '{}'", day)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(internal_date: &str, date_header: &str) -> Message {
+        Message::new(
+            1,
+            1,
+            format!("From: a@example.com\r\nDate: {}\r\n\r\n", date_header).into_bytes(),
+            vec![],
+            internal_date.parse().ok(),
+            None,
+        )
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_age_is_exclusive_and_relative_to_now() {
+        let filter: DateFilter = serde_yaml::from_str("{ age: { over: 2d, under: 12h } }").unwrap();
+        assert!(filter.age.over.is_some() && filter.age.under.is_some());
+
+        let older: DateFilter = serde_yaml::from_str("age: { over: 2d }").unwrap();
+        let msg = message("2024-06-01T00:00:00+00:00", "Sat, 1 Jun 2024 00:00:00 +0000");
+        assert!(!older.matches(&msg, at("2024-06-03T00:00:00Z")));
+        assert!(older.matches(&msg, at("2024-06-03T00:00:01Z")));
+    }
+
+    #[test]
+    fn test_before_after_and_weekday() {
+        let filter: DateFilter =
+            serde_yaml::from_str("{ after: 2024-06-01, before: '2024-06-03T00:00:00+00:00', weekday: [Sat, sun] }")
+                .unwrap();
+        let now = at("2024-07-01T00:00:00Z");
+        // Saturday and Sunday in range; Monday is past `before`
+        assert!(filter.matches(&message("2024-06-01T00:00:00+00:00", ""), now));
+        assert!(filter.matches(&message("2024-06-02T23:59:59+00:00", ""), now));
+        assert!(!filter.matches(&message("2024-06-03T00:00:00+00:00", ""), now));
+        // Friday evening in New York is Saturday in UTC, and UTC decides
+        assert!(filter.matches(&message("2024-05-31T21:00:00-04:00", ""), now));
+
+        assert!(serde_yaml::from_str::<DateFilter>("before: next week").is_err());
+        assert!(serde_yaml::from_str::<DateFilter>("weekday: Caturday").is_err());
+    }
+
+    #[test]
+    fn test_header_date_source() {
+        let filter: DateFilter = serde_yaml::from_str("{ date: header, before: 2024-01-01 }").unwrap();
+        let now = at("2024-07-01T00:00:00Z");
+        // sent in 2023, delivered in 2024
+        let late = message("2024-06-01T00:00:00+00:00", "Sun, 31 Dec 2023 23:00:00 +0000");
+        assert!(filter.matches(&late, now));
+        // an unparsable header falls back to INTERNALDATE
+        assert!(!filter.matches(&message("2024-06-01T00:00:00+00:00", "garbage"), now));
+        assert!(filter.matches(&message("2023-06-01T00:00:00+00:00", "garbage"), now));
+        assert!(!filter.matches(&message("", "garbage"), now));
+    }
+}
@@ -0,0 +1,93 @@
+// src/cfg/domain.rs
+//
+// Domain names for the domain predicates. Both configured domains and
+// message addresses are normalized to lowercase ASCII, IDNs to punycode, so
+// `食狮.中国` and `xn--85x722f.xn--fiqs8s` compare equal.
+
+use serde::de::{self, Deserializer};
+
+use crate::cfg::config::deserialize_string_list;
+
+/// A normalized domain name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Domain(String);
+
+impl Domain {
+    /// Parse a configured domain. It must be registrable or below (`tatari.tv`,
+    /// `mail.tatari.tv`); a public suffix such as `co.uk` would match every
+    /// domain under it and is rejected.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let domain = Self::normalize(name).ok_or_else(|| format!("Invalid domain '{}'", name))?;
+        let parsed = addr::parse_domain_name(&domain.0).map_err(|e| format!("Invalid domain '{}': {}", name, e))?;
+        if parsed.root().is_none() {
+            return Err(format!("'{}' is a public suffix, not a domain", name));
+        }
+        Ok(domain)
+    }
+
+    /// The domain of an email address, if it has a valid one.
+    pub fn of_address(email: &str) -> Option<Self> {
+        let (_, domain) = email.rsplit_once('@')?;
+        Self::normalize(domain)
+    }
+
+    fn normalize(name: &str) -> Option<Self> {
+        let ascii = idna::domain_to_ascii(name.trim().trim_end_matches('.')).ok()?;
+        (!ascii.is_empty()).then_some(Domain(ascii))
+    }
+
+    /// Whether this is `parent` or one of its subdomains. Labels are
+    /// compared whole, so `nottatari.tv` is not within `tatari.tv`.
+    pub fn is_within(&self, parent: &Domain) -> bool {
+        self.0
+            .strip_suffix(&parent.0)
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Accepts `key: tatari.tv` or `key: [tatari.tv, example.org]`.
+pub fn deserialize_domains<'de, D>(deserializer: D) -> Result<Vec<Domain>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_string_list(deserializer)?
+        .iter()
+        .map(|name| Domain::parse(name).map_err(de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subdomains_match_on_label_boundaries() {
+        let tatari = Domain::parse("Tatari.TV").unwrap();
+        let within = |email: &str| Domain::of_address(email).is_some_and(|d| d.is_within(&tatari));
+        assert!(within("ann@tatari.tv"));
+        assert!(within("ann@mail.TATARI.tv"));
+        assert!(!within("ann@nottatari.tv"));
+        assert!(!within("ann@tatari.tv.example.com"));
+        assert!(!within("not-an-address"));
+
+        assert!(Domain::parse("co.uk").is_err());
+        assert!(Domain::parse("github.io").is_err());
+        assert!(Domain::parse("example.github.io").is_ok());
+        assert!(Domain::parse("bad domain").is_err());
+    }
+
+    #[test]
+    fn test_idn_forms_compare_equal() {
+        let unicode = Domain::parse("食狮.中国").unwrap();
+        assert_eq!(unicode, Domain::parse("xn--85x722f.xn--fiqs8s").unwrap());
+        assert_eq!(unicode.as_str(), "xn--85x722f.xn--fiqs8s");
+        assert!(Domain::of_address("a@www.食狮.中国").unwrap().is_within(&unicode));
+
+        let bucher = Domain::parse("xn--bcher-kva.example").unwrap();
+        assert!(Domain::of_address("a@Bücher.example").unwrap().is_within(&bucher));
+    }
+}
@@ -0,0 +1,122 @@
+// src/cfg/flag_filter.rs
+//
+// IMAP flag predicates shared by message and state filters. They read
+// `Message::flags`, which holds the same system flags on Gmail and other
+// servers, so `seen: false` means unread everywhere.
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::cfg::config::deserialize_string_list;
+use crate::message::Message;
+
+/// Keywords a message must carry (any of `included`) and must not carry
+/// (none of `excluded`). A bare string or list means `included`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KeywordsFilter {
+    pub included: Vec<String>,
+    pub excluded: Vec<String>,
+}
+
+/// `true` requires the flag, `false` requires its absence.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct FlagFilter {
+    #[serde(default)]
+    pub seen: Option<bool>,
+
+    #[serde(default)]
+    pub answered: Option<bool>,
+
+    #[serde(default)]
+    pub flagged: Option<bool>,
+
+    #[serde(default)]
+    pub draft: Option<bool>,
+
+    #[serde(default)]
+    pub keywords: KeywordsFilter,
+}
+
+impl FlagFilter {
+    pub fn matches(&self, msg: &Message) -> bool {
+        let flag = |wanted: Option<bool>, name: &str| wanted.is_none_or(|wanted| msg.has_flag(name) == wanted);
+        flag(self.seen, "\\Seen")
+            && flag(self.answered, "\\Answered")
+            && flag(self.flagged, "\\Flagged")
+            && flag(self.draft, "\\Draft")
+            && (self.keywords.included.is_empty() || self.keywords.included.iter().any(|k| msg.has_flag(k)))
+            && !self.keywords.excluded.iter().any(|k| msg.has_flag(k))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeywordsFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Sets {
+            #[serde(default)]
+            #[serde(deserialize_with = "deserialize_string_list")]
+            included: Vec<String>,
+            #[serde(default)]
+            #[serde(deserialize_with = "deserialize_string_list")]
+            excluded: Vec<String>,
+        }
+
+        let v = Value::deserialize(deserializer)?;
+        if let Value::Mapping(_) = v {
+            let sets: Sets = serde_yaml::from_value(v).map_err(de::Error::custom)?;
+            return Ok(KeywordsFilter {
+                included: sets.included,
+                excluded: sets.excluded,
+            });
+        }
+        Ok(KeywordsFilter {
+            included: deserialize_string_list(v).map_err(de::Error::custom)?,
+            excluded: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(flags: &[&str]) -> Message {
+        Message::new(
+            1,
+            1,
+            b"From: a@example.com\r\n\r\n".to_vec(),
+            flags.iter().map(|f| f.to_string()).collect(),
+            "2024-01-01T00:00:00+00:00".parse().ok(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_system_flags() {
+        let unanswered: FlagFilter = serde_yaml::from_str("{ seen: true, answered: false }").unwrap();
+        assert!(unanswered.matches(&message(&["\\Seen"])));
+        assert!(!unanswered.matches(&message(&["\\Seen", "\\Answered"])));
+        assert!(!unanswered.matches(&message(&[])));
+
+        // Gmail's \Starred label and the \Flagged flag are the same thing
+        let flagged: FlagFilter = serde_yaml::from_str("flagged: true").unwrap();
+        assert!(flagged.matches(&message(&["\\Starred"])));
+        assert!(flagged.matches(&message(&["\\FLAGGED"])));
+        assert!(FlagFilter::default().matches(&message(&[])));
+    }
+
+    #[test]
+    fn test_keywords() {
+        let any: FlagFilter = serde_yaml::from_str("keywords: ['$Forwarded', '$Label1']").unwrap();
+        assert!(any.matches(&message(&["$label1"])));
+        assert!(!any.matches(&message(&["\\Seen"])));
+
+        let sets: FlagFilter = serde_yaml::from_str("keywords: { excluded: $Junk }").unwrap();
+        assert!(sets.matches(&message(&["$NotJunk"])));
+        assert!(!sets.matches(&message(&["$Junk"])));
+    }
+}
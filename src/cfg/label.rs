@@ -1,6 +1,7 @@
 // src/cfg/label.rs
 
 use serde::Deserialize;
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Label {
@@ -33,6 +34,22 @@ impl Label {
     }
 }
 
+/// The raw form `Label::new` parses back into the same label.
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Inbox => write!(f, "\\Inbox"),
+            Label::Important => write!(f, "\\Important"),
+            Label::Starred => write!(f, "\\Starred"),
+            Label::Sent => write!(f, "\\Sent"),
+            Label::Draft => write!(f, "\\Draft"),
+            Label::Trash => write!(f, "\\Trash"),
+            Label::Spam => write!(f, "\\Spam"),
+            Label::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
 // manually deserialize any YAML string into our Label::new
 impl<'de> Deserialize<'de> for Label {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -116,4 +133,22 @@ mod tests {
         let label2: Label = serde_yaml::from_str(yaml2).unwrap();
         assert_eq!(label2, Label::Custom("CustomLabel".to_string()));
     }
+
+    #[test]
+    fn test_label_display_round_trips() {
+        for raw in [
+            "INBOX",
+            "\\Important",
+            "Starred",
+            "\\Sent",
+            "Draft",
+            "Trash",
+            "Spam",
+            "Seen",
+            "Work/Projects",
+        ] {
+            let label = Label::new(raw);
+            assert_eq!(Label::new(&label.to_string()), label);
+        }
+    }
 }
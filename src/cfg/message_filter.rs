@@ -1,18 +1,21 @@
 // src/cfg/message_filter.rs
 
+use crate::cfg::address_filter::AddressFilters;
+use crate::cfg::config::deserialize_string_list;
+use crate::cfg::date_filter::DateFilter;
+use crate::cfg::domain::Domain;
+use crate::cfg::flag_filter::FlagFilter;
 use crate::cfg::label::Label;
-use crate::message::{EmailAddress, Message};
-use globset::Glob;
+use crate::cfg::part_filter::{AttachmentFilter, SizeRange};
+use crate::cfg::pattern::Pattern;
+use crate::message::Message;
+use chrono::{DateTime, Utc};
+use eyre::eyre;
 use serde::de::{self, Deserializer};
 use serde::Deserialize;
-use serde_yaml::{from_value, Value};
+use serde_yaml::Value;
 use std::collections::HashMap;
 
-#[derive(Debug, PartialEq, Clone, Deserialize)]
-pub struct AddressFilter {
-    pub patterns: Vec<String>,
-}
-
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 pub enum FilterAction {
     Star,
@@ -20,6 +23,17 @@ pub enum FilterAction {
     Move(String),
 }
 
+/// What happens to a message's remaining actions when one of them fails.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Skip the message's later actions (the default).
+    #[default]
+    Abort,
+    /// Try the later actions anyway.
+    Continue,
+}
+
 /// Helper to deserialize the `labels:` section of your YAML.
 #[derive(Debug, Default, Clone, Deserialize)]
 #[serde(default)]
@@ -28,103 +42,269 @@ pub struct LabelsFilter {
     pub excluded: Vec<Label>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct MessageFilter {
-    #[serde(skip_deserializing)]
-    pub name: String,
+/// A node of a boolean condition tree. Its primitives (the same keys a
+/// filter takes) are ANDed, then combined with the branches: every `all`
+/// entry must match, at least one `any` entry must match, and `not` must
+/// not. Absent parts always hold.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Condition {
+    /// `to`, `cc`, `bcc`, `from`, `sender`, `reply-to`, `delivered-to`,
+    /// `return-path`, `list-post` and `recipients`.
+    #[serde(flatten)]
+    pub addresses: AddressFilters,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_subject")]
+    pub subject: Vec<Pattern>,
+
+    /// Patterns for the decoded body text. The body is only fetched for
+    /// messages the other predicates leave undecided.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_body")]
+    pub body: Vec<Pattern>,
 
+    /// Whether the message must (`true`) or must not (`false`) have an
+    /// attachment.
     #[serde(default)]
-    #[serde(deserialize_with = "deserialize_opt_address_filter")]
-    pub to: Option<AddressFilter>,
+    #[serde(rename = "has-attachment")]
+    pub has_attachment: Option<bool>,
 
+    /// Some attachment must match.
     #[serde(default)]
-    #[serde(deserialize_with = "deserialize_opt_address_filter")]
-    pub cc: Option<AddressFilter>,
+    pub attachment: Option<AttachmentFilter>,
 
+    /// Some MIME part, attached or inline, must have a matching type.
     #[serde(default)]
-    #[serde(deserialize_with = "deserialize_opt_address_filter")]
-    pub from: Option<AddressFilter>,
+    #[serde(rename = "mime-type")]
+    #[serde(deserialize_with = "deserialize_mime_type")]
+    pub mime_type: Vec<Pattern>,
 
+    /// Bounds on the whole message size (RFC822.SIZE).
     #[serde(default)]
-    pub subject: Vec<String>,
+    pub size: SizeRange,
+
+    /// `age`, `before`, `after`, `weekday` and the `date` they read.
+    #[serde(flatten)]
+    pub dates: DateFilter,
+
+    /// `seen`, `answered`, `flagged`, `draft` and `keywords`.
+    #[serde(flatten)]
+    pub flags: FlagFilter,
 
     #[serde(default)]
     #[serde(alias = "label")]
     #[serde(deserialize_with = "deserialize_labels_filter")]
     pub labels: LabelsFilter,
 
-    /// Custom header matching: header name -> glob patterns
+    /// Custom header matching: header name -> patterns
     /// Example: { "List-Id": ["*github*"], "X-Priority": ["1"] }
     #[serde(default)]
-    pub headers: HashMap<String, Vec<String>>,
+    #[serde(deserialize_with = "deserialize_headers")]
+    pub headers: HashMap<String, Vec<Pattern>>,
+
+    /// Branches, ANDed with the primitives above (which are themselves
+    /// shorthand for one more `all` entry).
+    #[serde(default)]
+    pub all: Vec<Condition>,
+
+    #[serde(default)]
+    pub any: Vec<Condition>,
+
+    #[serde(default)]
+    pub not: Option<Box<Condition>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageFilter {
+    #[serde(skip_deserializing)]
+    pub name: String,
+
+    /// What the filter matches: its primitives and condition tree.
+    #[serde(flatten)]
+    pub condition: Condition,
 
     #[serde(default)]
     #[serde(alias = "action")]
     #[serde(deserialize_with = "deserialize_actions")]
     pub actions: Vec<FilterAction>,
+
+    /// Applies when one of several `actions` fails for a message.
+    #[serde(default)]
+    #[serde(rename = "on-error")]
+    pub on_error: OnError,
+
+    /// Whether a match ends evaluation for the message. With `stop: false`
+    /// later filters may act on it too (Sieve-style fallthrough).
+    #[serde(default = "default_stop")]
+    pub stop: bool,
+
+    /// Evaluation order; lower runs first, ties keep config order.
+    #[serde(default)]
+    pub priority: i32,
+
+    /// Filters sharing a group are alternatives: only the first of them to
+    /// match acts on a message.
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// Mailboxes this filter applies to; empty means every scanned mailbox.
+    #[serde(default)]
+    #[serde(alias = "mailbox")]
+    #[serde(deserialize_with = "deserialize_string_list")]
+    pub mailboxes: Vec<String>,
 }
 
-impl AddressFilter {
-    /// Returns true if **any** of the `emails` matches **any** glob in `self.patterns`.
-    pub fn matches(&self, emails: &[String]) -> bool {
-        for pat in &self.patterns {
-            let matcher = Glob::new(pat).expect("invalid glob").compile_matcher();
-            for email in emails {
-                if matcher.is_match(email) {
-                    return true;
-                }
+fn default_stop() -> bool {
+    true
+}
+
+impl MessageFilter {
+    /// Whether this filter runs against messages fetched from `mailbox`.
+    pub fn applies_to(&self, mailbox: &str) -> bool {
+        self.mailboxes.is_empty() || self.mailboxes.iter().any(|m| m == mailbox)
+    }
+
+    /// Whether one of the actions moves the message out of its mailbox.
+    pub fn moves(&self) -> bool {
+        self.actions.iter().any(|a| matches!(a, FilterAction::Move(_)))
+    }
+
+    /// Reject action lists that cannot be applied in order: a repeated
+    /// action, more than one `Move`, or any action after a `Move` (the
+    /// message is no longer in the mailbox by then).
+    pub fn validate_actions(&self) -> eyre::Result<()> {
+        for (i, action) in self.actions.iter().enumerate() {
+            if self.actions[..i].contains(action) {
+                return Err(eyre!("Filter '{}': action {:?} is listed twice", self.name, action));
+            }
+            if let Some(FilterAction::Move(earlier)) =
+                self.actions[..i].iter().find(|a| matches!(a, FilterAction::Move(_)))
+            {
+                return Err(match action {
+                    FilterAction::Move(target) => {
+                        eyre!("Filter '{}' moves to both '{}' and '{}'", self.name, earlier, target)
+                    }
+                    _ => eyre!(
+                        "Filter '{}': {:?} comes after Move to '{}'; a move must be the last action",
+                        self.name,
+                        action,
+                        earlier
+                    ),
+                });
             }
         }
-        false
+        Ok(())
     }
-}
 
-impl MessageFilter {
-    /// Returns true if this filter matches the given message.
+    /// Returns true if this filter matches the given message at the current
+    /// time. A filter with `body` predicates needs the body; see `evaluate`.
     pub fn matches(&self, msg: &Message) -> bool {
-        // helper to extract just the email‑strings
-        let extract = |addrs: &Vec<EmailAddress>| addrs.iter().map(|ea| ea.email.clone()).collect::<Vec<_>>();
-
-        // TO
-        if let Some(ref af) = self.to {
-            let emails = extract(&msg.to);
-            if af.patterns.is_empty() {
-                if !emails.is_empty() {
-                    return false;
-                }
-            } else if !af.matches(&emails) {
-                return false;
-            }
+        self.evaluate(msg, None, Utc::now()) == Some(true)
+    }
+
+    /// Evaluate this filter against `msg` and its decoded `body`, if fetched,
+    /// with ages taken relative to `now`. `None` means the outcome depends on
+    /// the body, which was not given.
+    pub fn evaluate(&self, msg: &Message, body: Option<&str>, now: DateTime<Utc>) -> Option<bool> {
+        self.condition.evaluate(msg, body, now)
+    }
+
+    /// Every header name this filter matches on, including inside its
+    /// condition tree.
+    pub fn header_names(&self) -> Vec<&String> {
+        let mut names = Vec::new();
+        self.condition.collect_header_names(&mut names);
+        names
+    }
+
+    /// Give `internal-sender`, here and in the condition tree, the
+    /// organization's domains.
+    pub fn set_internal_domains(&mut self, domains: &[Domain]) {
+        self.condition.set_internal_domains(domains);
+    }
+
+    /// Whether `internal-sender` appears here or in the condition tree.
+    pub fn uses_internal_sender(&self) -> bool {
+        self.condition.uses_internal_sender()
+    }
+}
+
+impl Condition {
+    /// Evaluate this node, including its branches, as `MessageFilter::evaluate`.
+    pub fn evaluate(&self, msg: &Message, body: Option<&str>, now: DateTime<Utc>) -> Option<bool> {
+        all_of([
+            self.evaluate_primitives(msg, body, now),
+            self.evaluate_branches(msg, body, now),
+        ])
+    }
+
+    /// Every `all` entry and one `any` entry match, and `not` does not.
+    fn evaluate_branches(&self, msg: &Message, body: Option<&str>, now: DateTime<Utc>) -> Option<bool> {
+        let any = if self.any.is_empty() {
+            Some(true)
+        } else {
+            any_of(self.any.iter().map(|c| c.evaluate(msg, body, now)))
+        };
+        let not = self
+            .not
+            .as_ref()
+            .map_or(Some(true), |c| c.evaluate(msg, body, now).map(|matched| !matched));
+        all_of(self.all.iter().map(|c| c.evaluate(msg, body, now)).chain([any, not]))
+    }
+
+    /// Give `internal-sender`, here and in the branches, the organization's
+    /// domains.
+    pub fn set_internal_domains(&mut self, domains: &[Domain]) {
+        self.addresses.internal_domains = domains.to_vec();
+        for c in self.all.iter_mut().chain(&mut self.any).chain(self.not.as_deref_mut()) {
+            c.set_internal_domains(domains);
         }
-        // CC
-        if let Some(ref af) = self.cc {
-            let emails = extract(&msg.cc);
-            if af.patterns.is_empty() {
-                if !emails.is_empty() {
-                    return false;
-                }
-            } else if !af.matches(&emails) {
-                return false;
-            }
+    }
+
+    /// Whether `internal-sender` appears here or in the branches.
+    pub fn uses_internal_sender(&self) -> bool {
+        self.addresses.internal_sender.is_some()
+            || self
+                .all
+                .iter()
+                .chain(&self.any)
+                .chain(self.not.as_deref())
+                .any(Condition::uses_internal_sender)
+    }
+
+    fn collect_header_names<'a>(&'a self, names: &mut Vec<&'a String>) {
+        names.extend(self.headers.keys());
+        for c in self.all.iter().chain(&self.any).chain(self.not.as_deref()) {
+            c.collect_header_names(names);
         }
-        // FROM
-        if let Some(ref af) = self.from {
-            let emails = extract(&msg.from);
-            if af.patterns.is_empty() {
-                if !emails.is_empty() {
-                    return false;
-                }
-            } else if !af.matches(&emails) {
-                return false;
-            }
+    }
+
+    /// All primitives hold for `msg` and `body`; an empty one always does.
+    /// Unknown (`None`) only when the headers match and `body` is needed but
+    /// not given.
+    fn evaluate_primitives(&self, msg: &Message, body: Option<&str>, now: DateTime<Utc>) -> Option<bool> {
+        if !self.matches_headers(msg) || !self.dates.matches(msg, now) {
+            return Some(false);
+        }
+        if self.body.is_empty() {
+            return Some(true);
+        }
+        body.map(|text| self.body.iter().any(|pat| pat.is_match(text)))
+    }
+
+    /// Every primitive but `body` holds for `msg`. These only need what the
+    /// header fetch returns.
+    fn matches_headers(&self, msg: &Message) -> bool {
+        // ADDRESSES: an empty pattern list requires no address
+        if !self.addresses.matches(msg) {
+            return false;
         }
 
         // SUBJECT globs
         if !self.subject.is_empty() {
             let mut found = false;
             for pat in &self.subject {
-                let matcher = Glob::new(pat).unwrap().compile_matcher();
-                if matcher.is_match(&msg.subject) {
+                if pat.is_match(&msg.subject) {
                     found = true;
                     break;
                 }
@@ -142,23 +322,49 @@ impl MessageFilter {
             return false;
         }
 
+        // FLAGS
+        if !self.flags.matches(msg) {
+            return false;
+        }
+
+        // MIME structure, from BODYSTRUCTURE
+        let mut attachments = msg.parts.iter().filter(|p| p.attachment);
+        if self
+            .has_attachment
+            .is_some_and(|wanted| attachments.clone().next().is_some() != wanted)
+        {
+            return false;
+        }
+        if self
+            .attachment
+            .as_ref()
+            .is_some_and(|af| !attachments.any(|p| af.matches(p)))
+        {
+            return false;
+        }
+        if !self.mime_type.is_empty()
+            && !msg
+                .parts
+                .iter()
+                .any(|p| self.mime_type.iter().any(|pat| pat.is_match(&p.mime_type)))
+        {
+            return false;
+        }
+
+        // SIZE: whole message, from RFC822.SIZE
+        if !self.size.contains(msg.size) {
+            return false;
+        }
+
         // HEADERS: custom header matching
         for (header_name, patterns) in &self.headers {
-            if let Some(header_value) = msg.headers.get(header_name) {
-                // At least one pattern must match the header value
-                let mut matched = false;
-                for pat in patterns {
-                    let matcher = Glob::new(pat).expect("invalid glob").compile_matcher();
-                    if matcher.is_match(header_value) {
-                        matched = true;
-                        break;
-                    }
-                }
-                if !matched {
-                    return false;
-                }
-            } else {
-                // Header not present, patterns don't match
+            // At least one pattern must match one occurrence of the header;
+            // an absent header matches nothing
+            if !msg
+                .headers
+                .get_all(header_name)
+                .any(|value| patterns.iter().any(|pat| pat.is_match(value)))
+            {
                 return false;
             }
         }
@@ -167,33 +373,91 @@ impl MessageFilter {
     }
 }
 
-/// Custom deserializer for `to`, `cc`, `from`:
-fn deserialize_opt_address_filter<'de, D>(deserializer: D) -> Result<Option<AddressFilter>, D::Error>
+/// Three-valued AND: a known `false` wins over an unknown (`None`), which
+/// wins over `true`.
+fn all_of(results: impl IntoIterator<Item = Option<bool>>) -> Option<bool> {
+    let mut out = Some(true);
+    for result in results {
+        match result {
+            Some(false) => return Some(false),
+            None => out = None,
+            Some(true) => {}
+        }
+    }
+    out
+}
+
+/// Three-valued OR: a known `true` wins over an unknown, which wins over
+/// `false`.
+fn any_of(results: impl IntoIterator<Item = Option<bool>>) -> Option<bool> {
+    let mut out = Some(false);
+    for result in results {
+        match result {
+            Some(true) => return Some(true),
+            None => out = None,
+            Some(false) => {}
+        }
+    }
+    out
+}
+
+fn deserialize_subject<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let v = Value::deserialize(deserializer).map_err(de::Error::custom)?;
-    match v {
-        Value::Null => Ok(None),
-        Value::Sequence(seq) => {
-            let mut patterns = Vec::new();
-            for val in seq {
-                if let Value::String(s) = val {
-                    patterns.push(s);
-                } else {
-                    return Err(de::Error::custom("Invalid entry in address filter"));
-                }
-            }
-            Ok(Some(AddressFilter { patterns }))
-        }
-        Value::String(s) => Ok(Some(AddressFilter { patterns: vec![s] })),
-        other @ Value::Mapping(_) => {
-            // map mapping → AddressFilter via YAML
-            let af: AddressFilter = from_value(other).map_err(de::Error::custom)?;
-            Ok(Some(af))
+    compile_patterns("subject", Value::deserialize(deserializer)?)
+}
+
+fn deserialize_body<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    compile_patterns("body", Value::deserialize(deserializer)?)
+}
+
+fn deserialize_mime_type<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    compile_patterns("mime-type", Value::deserialize(deserializer)?)
+}
+
+fn deserialize_headers<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<Pattern>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, v)| {
+            let patterns = compile_patterns(&format!("headers.{}", name), v)?;
+            Ok((name, patterns))
+        })
+        .collect()
+}
+
+/// Compile one pattern or a list of them, naming `field` in any error so a
+/// typo is reported at config load rather than when a message is matched.
+pub(crate) fn compile_patterns<E: de::Error>(field: &str, v: Value) -> Result<Vec<Pattern>, E> {
+    let sources = match v {
+        Value::String(s) => vec![s],
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .map(|val| match val {
+                Value::String(s) => Ok(s),
+                _ => Err(E::custom(format!("`{}`: patterns must be strings", field))),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => {
+            return Err(E::custom(format!(
+                "`{}`: expected a pattern or a list of patterns",
+                field
+            )))
         }
-        _ => Err(de::Error::custom("Invalid address filter format")),
-    }
+    };
+    sources
+        .iter()
+        .map(|s| Pattern::new(s).map_err(|e| E::custom(format!("`{}`: {}", field, e))))
+        .collect()
 }
 
 fn deserialize_labels_filter<'de, D>(deserializer: D) -> Result<LabelsFilter, D::Error>
@@ -300,10 +564,23 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cfg::address_filter::AddressFilter;
+
+    fn pats(sources: &[&str]) -> Vec<Pattern> {
+        sources.iter().map(|s| Pattern::new(s).unwrap()).collect()
+    }
 
     fn make_test_message(to: Vec<&str>, cc: Vec<&str>, from: &str, subject: &str) -> Message {
-        let to_header = if to.is_empty() { String::new() } else { format!("To: {}\r\n", to.join(", ")) };
-        let cc_header = if cc.is_empty() { String::new() } else { format!("Cc: {}\r\n", cc.join(", ")) };
+        let to_header = if to.is_empty() {
+            String::new()
+        } else {
+            format!("To: {}\r\n", to.join(", "))
+        };
+        let cc_header = if cc.is_empty() {
+            String::new()
+        } else {
+            format!("Cc: {}\r\n", cc.join(", "))
+        };
 
         let headers = format!(
             "{}{}From: {}\r\nSubject: {}\r\n\r\n",
@@ -315,7 +592,7 @@ mod tests {
             1,
             headers.into_bytes(),
             vec![],
-            "2024-01-01T00:00:00+00:00".to_string(),
+            "2024-01-01T00:00:00+00:00".parse().ok(),
             None,
         )
     }
@@ -323,7 +600,7 @@ mod tests {
     #[test]
     fn test_address_filter_matches_exact() {
         let filter = AddressFilter {
-            patterns: vec!["test@example.com".to_string()],
+            patterns: pats(&["test@example.com"]),
         };
         assert!(filter.matches(&["test@example.com".to_string()]));
         assert!(!filter.matches(&["other@example.com".to_string()]));
@@ -332,7 +609,7 @@ mod tests {
     #[test]
     fn test_address_filter_matches_glob() {
         let filter = AddressFilter {
-            patterns: vec!["*@example.com".to_string()],
+            patterns: pats(&["*@example.com"]),
         };
         assert!(filter.matches(&["test@example.com".to_string()]));
         assert!(filter.matches(&["anyone@example.com".to_string()]));
@@ -342,7 +619,7 @@ mod tests {
     #[test]
     fn test_address_filter_multiple_patterns() {
         let filter = AddressFilter {
-            patterns: vec!["*@example.com".to_string(), "*@test.com".to_string()],
+            patterns: pats(&["*@example.com", "*@test.com"]),
         };
         assert!(filter.matches(&["user@example.com".to_string()]));
         assert!(filter.matches(&["user@test.com".to_string()]));
@@ -353,15 +630,21 @@ mod tests {
     fn test_message_filter_matches_to() {
         let filter = MessageFilter {
             name: "test".to_string(),
-            to: Some(AddressFilter {
-                patterns: vec!["me@example.com".to_string()],
-            }),
-            cc: None,
-            from: None,
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: HashMap::new(),
+            condition: Condition {
+                addresses: AddressFilters {
+                    to: Some(AddressFilter {
+                        patterns: pats(&["me@example.com"]),
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             actions: vec![FilterAction::Star],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         let msg = make_test_message(vec!["me@example.com"], vec![], "sender@example.com", "Test");
@@ -375,13 +658,19 @@ mod tests {
     fn test_message_filter_requires_empty_cc() {
         let filter = MessageFilter {
             name: "test".to_string(),
-            to: None,
-            cc: Some(AddressFilter { patterns: vec![] }), // empty = require no CC
-            from: None,
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: HashMap::new(),
+            condition: Condition {
+                addresses: AddressFilters {
+                    cc: Some(AddressFilter { patterns: vec![] }), // empty = require no CC
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             actions: vec![FilterAction::Star],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         // Message with no CC should match
@@ -402,15 +691,21 @@ mod tests {
     fn test_message_filter_matches_from() {
         let filter = MessageFilter {
             name: "test".to_string(),
-            to: None,
-            cc: None,
-            from: Some(AddressFilter {
-                patterns: vec!["*@company.com".to_string()],
-            }),
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: HashMap::new(),
+            condition: Condition {
+                addresses: AddressFilters {
+                    from: Some(AddressFilter {
+                        patterns: pats(&["*@company.com"]),
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             actions: vec![FilterAction::Star],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         let msg = make_test_message(vec!["me@example.com"], vec![], "boss@company.com", "Important");
@@ -424,13 +719,16 @@ mod tests {
     fn test_message_filter_matches_subject_glob() {
         let filter = MessageFilter {
             name: "test".to_string(),
-            to: None,
-            cc: None,
-            from: None,
-            subject: vec!["*urgent*".to_string()],
-            labels: LabelsFilter::default(),
-            headers: HashMap::new(),
+            condition: Condition {
+                subject: pats(&["*urgent*"]),
+                ..Default::default()
+            },
             actions: vec![FilterAction::Star],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         let msg = make_test_message(
@@ -450,17 +748,25 @@ mod tests {
         // Filter: emails to me, from @company.com, with no CC
         let filter = MessageFilter {
             name: "only-me-from-company".to_string(),
-            to: Some(AddressFilter {
-                patterns: vec!["me@example.com".to_string()],
-            }),
-            cc: Some(AddressFilter { patterns: vec![] }), // no CC
-            from: Some(AddressFilter {
-                patterns: vec!["*@company.com".to_string()],
-            }),
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: HashMap::new(),
+            condition: Condition {
+                addresses: AddressFilters {
+                    to: Some(AddressFilter {
+                        patterns: pats(&["me@example.com"]),
+                    }),
+                    cc: Some(AddressFilter { patterns: vec![] }), // no CC
+                    from: Some(AddressFilter {
+                        patterns: pats(&["*@company.com"]),
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
             actions: vec![FilterAction::Star],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         // Should match: to me, from company, no CC
@@ -485,17 +791,20 @@ mod tests {
     fn test_message_filter_matches_custom_header() {
         // Create a filter that requires List-Id header with github pattern
         let mut header_patterns = HashMap::new();
-        header_patterns.insert("List-Id".to_string(), vec!["*github*".to_string()]);
+        header_patterns.insert("List-Id".to_string(), pats(&["*github*"]));
 
         let filter = MessageFilter {
             name: "github-lists".to_string(),
-            to: None,
-            cc: None,
-            from: None,
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: header_patterns,
+            condition: Condition {
+                headers: header_patterns,
+                ..Default::default()
+            },
             actions: vec![FilterAction::Move("GitHub".to_string())],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         // Create a message with List-Id header
@@ -505,7 +814,7 @@ mod tests {
                         List-Id: <repo.github.com>\r\n\
                         \r\n"
             .to_vec();
-        let msg = Message::new(1, 1, headers, vec![], "2024-01-01T00:00:00+00:00".to_string(), None);
+        let msg = Message::new(1, 1, headers, vec![], "2024-01-01T00:00:00+00:00".parse().ok(), None);
         assert!(filter.matches(&msg));
 
         // Message without List-Id should NOT match
@@ -516,17 +825,20 @@ mod tests {
     #[test]
     fn test_message_filter_header_must_match_pattern() {
         let mut header_patterns = HashMap::new();
-        header_patterns.insert("X-Priority".to_string(), vec!["1".to_string()]);
+        header_patterns.insert("X-Priority".to_string(), pats(&["1"]));
 
         let filter = MessageFilter {
             name: "high-priority".to_string(),
-            to: None,
-            cc: None,
-            from: None,
-            subject: vec![],
-            labels: LabelsFilter::default(),
-            headers: header_patterns,
+            condition: Condition {
+                headers: header_patterns,
+                ..Default::default()
+            },
             actions: vec![FilterAction::Flag],
+            on_error: OnError::Abort,
+            stop: true,
+            priority: 0,
+            group: None,
+            mailboxes: vec![],
         };
 
         // High priority message
@@ -541,7 +853,7 @@ mod tests {
             1,
             high_priority,
             vec![],
-            "2024-01-01T00:00:00+00:00".to_string(),
+            "2024-01-01T00:00:00+00:00".parse().ok(),
             None,
         );
         assert!(filter.matches(&msg));
@@ -558,9 +870,147 @@ mod tests {
             2,
             low_priority,
             vec![],
-            "2024-01-01T00:00:00+00:00".to_string(),
+            "2024-01-01T00:00:00+00:00".parse().ok(),
             None,
         );
         assert!(!filter.matches(&msg2));
     }
+
+    fn filter_with_actions(actions: &str) -> MessageFilter {
+        let mut filter: MessageFilter = serde_yaml::from_str(&format!("action: {}", actions)).unwrap();
+        filter.name = "lists".to_string();
+        filter
+    }
+
+    #[test]
+    fn test_validate_actions() {
+        let filter = filter_with_actions("[Star, Flag, Lists]");
+        assert_eq!(
+            filter.actions,
+            vec![
+                FilterAction::Star,
+                FilterAction::Flag,
+                FilterAction::Move("Lists".to_string())
+            ]
+        );
+        assert_eq!(filter.on_error, OnError::Abort);
+        assert!(filter.validate_actions().is_ok());
+
+        let err = filter_with_actions("[Lists, Archive]").validate_actions().unwrap_err();
+        assert!(err.to_string().contains("moves to both 'Lists' and 'Archive'"));
+        let err = filter_with_actions("[Lists, Star]").validate_actions().unwrap_err();
+        assert!(err.to_string().contains("must be the last action"));
+        let err = filter_with_actions("[Star, Star]").validate_actions().unwrap_err();
+        assert!(err.to_string().contains("listed twice"));
+    }
+
+    #[test]
+    fn test_ordering_defaults_keep_first_match_wins() {
+        let filter = filter_with_actions("Star");
+        assert!(filter.stop);
+        assert_eq!(filter.priority, 0);
+        assert!(filter.group.is_none());
+
+        let filter: MessageFilter =
+            serde_yaml::from_str("{ action: Star, stop: false, priority: -1, group: triage }").unwrap();
+        assert!(!filter.stop);
+        assert_eq!(filter.priority, -1);
+        assert_eq!(filter.group.as_deref(), Some("triage"));
+    }
+
+    #[test]
+    fn test_condition_tree() {
+        let filter: MessageFilter = serde_yaml::from_str(
+            r#"
+            to: me@example.com
+            any:
+              - from: boss@example.com
+              - subject: ["*URGENT*"]
+                headers: { X-Priority: ["1"] }
+            not:
+              from: "*bot*"
+            action: Star
+            "#,
+        )
+        .unwrap();
+
+        let boss = make_test_message(vec!["me@example.com"], vec![], "boss@example.com", "Lunch");
+        let urgent = make_test_message(vec!["me@example.com"], vec![], "ann@example.com", "URGENT: deploy");
+        let bot = make_test_message(vec!["me@example.com"], vec![], "ci-bot@example.com", "URGENT: build");
+        let cc_only = make_test_message(vec!["you@example.com"], vec![], "boss@example.com", "Lunch");
+        assert!(filter.matches(&boss));
+        // the second `any` entry also needs its header
+        assert!(!filter.matches(&urgent));
+        assert!(!filter.matches(&bot));
+        assert!(!filter.matches(&cc_only));
+
+        let mut urgent = urgent;
+        urgent.headers.insert("X-Priority", "1");
+        assert!(filter.matches(&urgent));
+        assert_eq!(filter.header_names(), vec!["X-Priority"]);
+    }
+
+    #[test]
+    fn test_flat_fields_are_sugar_for_all() {
+        let flat: MessageFilter = serde_yaml::from_str("{ from: boss@example.com, subject: ['*report*'] }").unwrap();
+        let tree: MessageFilter =
+            serde_yaml::from_str("{ all: [{ from: boss@example.com }, { subject: ['*report*'] }] }").unwrap();
+        for (from, subject) in [
+            ("boss@example.com", "Weekly report"),
+            ("boss@example.com", "Lunch"),
+            ("ann@example.com", "Weekly report"),
+        ] {
+            let msg = make_test_message(vec!["me@example.com"], vec![], from, subject);
+            assert_eq!(flat.matches(&msg), tree.matches(&msg), "{} / {}", from, subject);
+        }
+    }
+
+    #[test]
+    fn test_patterns_apply_to_every_primitive() {
+        let filter: MessageFilter = serde_yaml::from_str(
+            r#"
+            from: 'iglob:*@tatari.tv'
+            subject: ['ire:^re: ']
+            headers: { X-Mailer: ['iexact:mutt'] }
+            "#,
+        )
+        .unwrap();
+
+        let mut msg = make_test_message(vec![], vec![], "Ann@Tatari.TV", "RE: budget");
+        assert!(!filter.matches(&msg));
+        msg.headers.insert("X-Mailer", "Mutt");
+        assert!(filter.matches(&msg));
+        msg.subject = "Fwd: RE: budget".to_string();
+        assert!(!filter.matches(&msg));
+    }
+
+    #[test]
+    fn test_body_predicates_are_undecided_without_the_body() {
+        let filter: MessageFilter = serde_yaml::from_str(
+            r#"
+            from: ci@example.com
+            any:
+              - subject: ['*FAILED*']
+              - body: ['*build failed*']
+            not:
+              body: ['re:(?i)flaky']
+            "#,
+        )
+        .unwrap();
+
+        let other = make_test_message(vec![], vec![], "ann@example.com", "Build FAILED");
+        let ci = make_test_message(vec![], vec![], "ci@example.com", "Build #12");
+        assert_eq!(filter.evaluate(&other, None, Utc::now()), Some(false));
+        assert_eq!(filter.evaluate(&ci, None, Utc::now()), None);
+        assert!(!filter.matches(&ci));
+        assert_eq!(
+            filter.evaluate(&ci, Some("your build failed\nsee logs"), Utc::now()),
+            Some(true)
+        );
+        assert_eq!(
+            filter.evaluate(&ci, Some("your build failed, flaky test"), Utc::now()),
+            Some(false)
+        );
+        assert_eq!(filter.evaluate(&ci, Some("all green"), Utc::now()), Some(false));
+    }
 }
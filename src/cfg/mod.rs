@@ -1,7 +1,13 @@
 // src/cfg/mod.rs
 
+pub mod address_filter;
 pub mod config;
+pub mod date_filter;
+pub mod domain;
+pub mod flag_filter;
 pub mod label;
 pub mod message_filter;
+pub mod part_filter;
+pub mod pattern;
 pub mod secure;
 pub mod state_filter;
@@ -0,0 +1,178 @@
+// src/cfg/part_filter.rs
+//
+// Predicates over a message's MIME structure: attachments, part types and
+// sizes. They read `Message::parts`, which the header fetch fills from
+// BODYSTRUCTURE, so none of them downloads a body.
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::cfg::message_filter::compile_patterns;
+use crate::cfg::pattern::Pattern;
+use crate::message::MimePart;
+use crate::utils::parse_size;
+
+/// An inclusive size range in bytes; either end may be open.
+/// Written as `{ min: 5MB, max: 20MB }`, or with the exclusive
+/// `{ over: 10MB, under: 1GB }`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SizeRange {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl SizeRange {
+    pub fn contains(&self, size: u64) -> bool {
+        self.min.is_none_or(|min| size >= min) && self.max.is_none_or(|max| size <= max)
+    }
+
+    /// Whether neither end is set, so every size is in range.
+    pub fn is_unbounded(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+}
+
+/// Matches one attachment; every field given must hold for the same part.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct AttachmentFilter {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_filename")]
+    pub filename: Vec<Pattern>,
+
+    /// Patterns for the lowercase `type/subtype`, e.g. `application/pdf`.
+    #[serde(default)]
+    #[serde(rename = "type")]
+    #[serde(deserialize_with = "deserialize_type")]
+    pub mime_type: Vec<Pattern>,
+
+    #[serde(default)]
+    pub size: SizeRange,
+}
+
+impl AttachmentFilter {
+    pub fn matches(&self, part: &MimePart) -> bool {
+        let any = |patterns: &[Pattern], value: &str| patterns.is_empty() || patterns.iter().any(|p| p.is_match(value));
+        part.attachment
+            && any(&self.filename, part.filename.as_deref().unwrap_or_default())
+            && any(&self.mime_type, &part.mime_type)
+            && self.size.contains(part.size)
+    }
+}
+
+impl<'de> Deserialize<'de> for SizeRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Bounds {
+            #[serde(default)]
+            min: Option<Value>,
+            #[serde(default)]
+            max: Option<Value>,
+            #[serde(default)]
+            over: Option<Value>,
+            #[serde(default)]
+            under: Option<Value>,
+        }
+
+        let bounds = Bounds::deserialize(deserializer)?;
+        let bound = |v: Option<Value>| -> Result<Option<u64>, D::Error> {
+            match v {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::Number(n)) => n
+                    .as_u64()
+                    .map(Some)
+                    .ok_or_else(|| de::Error::custom(format!("Invalid size {}", n))),
+                Some(Value::String(s)) => parse_size(&s).map(Some).map_err(de::Error::custom),
+                Some(_) => Err(de::Error::custom("A size must be a number or a string like '5MB'")),
+            }
+        };
+        // `over`/`under` exclude the bound; where both forms are given the
+        // narrower one wins
+        let over = bound(bounds.over)?.map(|n| n.saturating_add(1));
+        let under = bound(bounds.under)?
+            .map(|n| {
+                n.checked_sub(1)
+                    .ok_or_else(|| de::Error::custom("`under: 0` matches no message"))
+            })
+            .transpose()?;
+        Ok(SizeRange {
+            min: bound(bounds.min)?.into_iter().chain(over).max(),
+            max: bound(bounds.max)?.into_iter().chain(under).min(),
+        })
+    }
+}
+
+fn deserialize_filename<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    compile_patterns("attachment.filename", Value::deserialize(deserializer)?)
+}
+
+fn deserialize_type<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    compile_patterns("attachment.type", Value::deserialize(deserializer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(mime_type: &str, filename: Option<&str>, size: u64, attachment: bool) -> MimePart {
+        MimePart {
+            mime_type: mime_type.to_string(),
+            filename: filename.map(str::to_string),
+            size,
+            attachment,
+        }
+    }
+
+    #[test]
+    fn test_attachment_filter() {
+        let filter: AttachmentFilter =
+            serde_yaml::from_str("{ filename: 'iglob:*.pdf', type: ['application/*'], size: { min: 1MB } }").unwrap();
+        assert_eq!(
+            filter.size,
+            SizeRange {
+                min: Some(1 << 20),
+                max: None
+            }
+        );
+
+        assert!(filter.matches(&part("application/pdf", Some("Q3.PDF"), 2 << 20, true)));
+        assert!(!filter.matches(&part("application/pdf", Some("Q3.pdf"), 1000, true)));
+        assert!(!filter.matches(&part("application/pdf", Some("Q3.pdf"), 2 << 20, false)));
+        assert!(!filter.matches(&part("text/plain", Some("Q3.pdf"), 2 << 20, true)));
+
+        let any: AttachmentFilter = serde_yaml::from_str("{}").unwrap();
+        assert!(any.matches(&part("image/png", None, 10, true)));
+    }
+
+    #[test]
+    fn test_size_range_bounds() {
+        let range: SizeRange = serde_yaml::from_str("{ min: 100, max: 1KB }").unwrap();
+        assert!(!range.contains(99));
+        assert!(range.contains(100));
+        assert!(range.contains(1024));
+        assert!(!range.contains(1025));
+        assert!(SizeRange::default().contains(0));
+        assert!(serde_yaml::from_str::<SizeRange>("{ min: lots }").is_err());
+    }
+
+    #[test]
+    fn test_over_and_under_are_exclusive() {
+        let range: SizeRange = serde_yaml::from_str("{ over: 10MB, under: 1GB }").unwrap();
+        assert!(!range.contains(10 << 20));
+        assert!(range.contains((10 << 20) + 1));
+        assert!(!range.contains(1 << 30));
+
+        let narrowed: SizeRange = serde_yaml::from_str("{ min: 100, over: 500 }").unwrap();
+        assert_eq!(narrowed.min, Some(501));
+        assert!(serde_yaml::from_str::<SizeRange>("{ under: 0 }").is_err());
+    }
+}
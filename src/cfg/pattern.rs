@@ -0,0 +1,168 @@
+// src/cfg/pattern.rs
+//
+// Text patterns used by the address, subject and header primitives. A bare
+// pattern is a glob, as before. A prefix picks the kind explicitly:
+//
+//   glob:*@example.com    exact:Weekly report    re:^\[(ops|infra)\]
+//
+// and a leading `i` on any prefix (`iglob:`, `iexact:`, `ire:`) ignores case.
+// A prefix followed by whitespace is rejected: `re: *` was a glob for reply
+// subjects before prefixes existed, and must not quietly become a regex.
+// Patterns are compiled once, while the config is deserialized, so a bad
+// regex or glob fails config load instead of a run.
+
+use eyre::{eyre, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+    Exact(String),
+    /// Stored lowercased.
+    ExactNoCase(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The pattern as written in the config.
+    source: String,
+    matcher: Matcher,
+}
+
+impl Pattern {
+    /// Parse and compile `source`.
+    pub fn new(source: &str) -> Result<Self> {
+        let (kind, body) = match source.split_once(':') {
+            Some((kind @ ("glob" | "iglob" | "re" | "ire" | "exact" | "iexact"), body)) => {
+                if body.starts_with(char::is_whitespace) {
+                    return Err(eyre!(
+                        "Ambiguous pattern '{}': write 'glob:{}' for the glob, or drop the space after '{}:'",
+                        source,
+                        source,
+                        kind
+                    ));
+                }
+                (kind, body)
+            }
+            _ => ("glob", source),
+        };
+        let matcher = match kind {
+            "glob" | "iglob" => GlobBuilder::new(body)
+                .case_insensitive(kind == "iglob")
+                .build()
+                .map(|g| Matcher::Glob(g.compile_matcher()))
+                .map_err(|e| eyre!("Invalid glob '{}': {}", source, e))?,
+            "re" | "ire" => RegexBuilder::new(body)
+                .case_insensitive(kind == "ire")
+                .build()
+                .map(Matcher::Regex)
+                .map_err(|e| eyre!("Invalid regex '{}': {}", source, e))?,
+            "exact" => Matcher::Exact(body.to_string()),
+            _ => Matcher::ExactNoCase(body.to_lowercase()),
+        };
+        Ok(Pattern {
+            source: source.to_string(),
+            matcher,
+        })
+    }
+
+    /// Whether `text` matches. Globs and exact patterns must match the whole
+    /// text; a regex matches anywhere unless anchored with `^`/`$`.
+    pub fn is_match(&self, text: &str) -> bool {
+        match &self.matcher {
+            Matcher::Glob(glob) => glob.is_match(text),
+            Matcher::Regex(re) => re.is_match(text),
+            Matcher::Exact(s) => s == text,
+            Matcher::ExactNoCase(s) => *s == text.to_lowercase(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        Pattern::new(&source).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bare_pattern_is_case_sensitive_glob() {
+        let p = Pattern::new("*@tatari.tv").unwrap();
+        assert!(p.is_match("ann@tatari.tv"));
+        assert!(!p.is_match("ann@Tatari.tv"));
+        assert_eq!(p, Pattern::new("*@tatari.tv").unwrap());
+        assert_eq!(p.as_str(), "*@tatari.tv");
+    }
+
+    #[test]
+    fn test_prefixes_and_case_folding() {
+        assert!(Pattern::new("iglob:Re: *").unwrap().is_match("RE: status"));
+        assert!(!Pattern::new("glob:Re: *").unwrap().is_match("RE: status"));
+
+        let re = Pattern::new(r"re:^\[(ops|infra)\]").unwrap();
+        assert!(re.is_match("[ops] disk full"));
+        assert!(!re.is_match("fwd: [ops] disk full"));
+        assert!(Pattern::new("ire:urgent").unwrap().is_match("Re: URGENT fix"));
+
+        assert!(Pattern::new("exact:Weekly *").unwrap().is_match("Weekly *"));
+        assert!(!Pattern::new("exact:Weekly *").unwrap().is_match("Weekly report"));
+        assert!(Pattern::new("iexact:BOSS@Example.com")
+            .unwrap()
+            .is_match("boss@example.COM"));
+
+        // an unknown prefix is part of a glob
+        assert!(Pattern::new("Re: *").unwrap().is_match("Re: lunch"));
+    }
+
+    #[test]
+    fn test_prefix_followed_by_space_is_rejected() {
+        // A reply glob written before prefixes existed must not become the
+        // regex ` *`, which matches everything
+        let err = Pattern::new("re: *").unwrap_err();
+        assert!(err.to_string().contains("Ambiguous pattern 're: *'"));
+        assert!(err.to_string().contains("'glob:re: *'"));
+        assert!(Pattern::new("iexact: Weekly").is_err());
+
+        let reply = Pattern::new("glob:re: *").unwrap();
+        assert!(reply.is_match("re: lunch"));
+        assert!(!reply.is_match("lunch"));
+        assert!(Pattern::new("Re: *").unwrap().is_match("Re: lunch"));
+        assert!(Pattern::new(" *").unwrap().is_match(" lunch"));
+    }
+
+    #[test]
+    fn test_invalid_patterns_fail_to_parse() {
+        let err = Pattern::new("re:(unclosed").unwrap_err();
+        assert!(err.to_string().contains("Invalid regex 're:(unclosed'"));
+        assert!(Pattern::new("glob:[a-").is_err());
+        assert!(serde_yaml::from_str::<Vec<Pattern>>("['ire:ok', 're:[']").is_err());
+    }
+}
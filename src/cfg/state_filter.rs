@@ -1,15 +1,16 @@
 // src/cfg/state_filter.rs
 
 use chrono;
-use chrono::{DateTime, Utc};
-use eyre::eyre;
 use serde::de::{self, Deserializer};
 use serde::Deserialize;
 use serde_yaml::Value;
 
+use crate::cfg::config::deserialize_string_list;
+use crate::cfg::flag_filter::FlagFilter;
 use crate::cfg::label::Label;
+use crate::cfg::part_filter::SizeRange;
 use crate::client_ops::Clock;
-use crate::message::Message;
+use crate::message::{DateSource, Message};
 use crate::utils::parse_days;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -110,32 +111,69 @@ pub struct StateFilter {
     /// optional, defaults to false
     #[serde(default)]
     pub nerf: bool,
+
+    /// Mailboxes this filter applies to; empty means every scanned mailbox.
+    #[serde(default)]
+    #[serde(alias = "mailbox")]
+    #[serde(deserialize_with = "deserialize_string_list")]
+    pub mailboxes: Vec<String>,
+
+    /// Bounds on the message size (RFC822.SIZE).
+    #[serde(default)]
+    pub size: SizeRange,
+
+    /// Bounds on the summed size of every message in the thread.
+    #[serde(default)]
+    #[serde(rename = "thread-size")]
+    pub thread_size: SizeRange,
+
+    /// `seen`, `answered`, `flagged`, `draft` and `keywords`.
+    #[serde(flatten)]
+    pub flags: FlagFilter,
+
+    /// The timestamp that ages messages and picks a thread's newest one.
+    #[serde(default)]
+    pub date: DateSource,
 }
 
 impl StateFilter {
-    /// Only messages carrying _any_ of these labels (or all if empty) participate.
+    /// Whether this filter runs against messages fetched from `mailbox`.
+    pub fn applies_to(&self, mailbox: &str) -> bool {
+        self.mailboxes.is_empty() || self.mailboxes.iter().any(|m| m == mailbox)
+    }
+
+    /// Only messages carrying _any_ of these labels (or all if empty), within
+    /// the `size` bounds and with the required flags, participate.
     pub fn matches(&self, msg: &Message) -> bool {
+        if !self.size.contains(msg.size) || !self.flags.matches(msg) {
+            return false;
+        }
         if self.labels.is_empty() {
             return true;
         }
         msg.labels.iter().any(|l| self.labels.contains(l))
     }
 
+    /// Whether the thread's total size is within `thread-size`. The total is
+    /// only computed when a bound is set.
+    pub fn matches_thread_size(&self, thread_size: impl FnOnce() -> u64) -> bool {
+        self.thread_size.is_unbounded() || self.thread_size.contains(thread_size())
+    }
+
     /// Returns:
-    ///  - `Ok(None)` if TTL == Keep or not yet expired
-    ///  - `Ok(Some(action))` if TTL expired and we should apply `action`
+    ///  - `None` if TTL == Keep, not yet expired, or the message has no date
+    ///  - `Some(action)` if TTL expired and we should apply `action`
     ///
-    /// Accepts a clock parameter to allow testing with virtual time.
-    pub fn evaluate_ttl<C: Clock>(&self, msg: &Message, clock: &C) -> eyre::Result<Option<StateAction>> {
+    /// Ages by `date`, falling back to the other timestamp when that one is
+    /// missing. Accepts a clock parameter to allow testing with virtual time.
+    pub fn evaluate_ttl<C: Clock>(&self, msg: &Message, clock: &C) -> Option<StateAction> {
         use log::debug;
 
-        let now = clock.now();
-        // parse the stored RFC3339 date back into a chrono DateTime
-        let internal: DateTime<Utc> = DateTime::parse_from_rfc3339(&msg.date)
-            .map_err(|e| eyre!("Bad INTERNALDATE '{}': {}", msg.date, e))?
-            .with_timezone(&Utc);
-
-        let age = now.signed_duration_since(internal);
+        let Some(dated) = msg.date(self.date) else {
+            debug!("      [ttl] UID {} has no usable date, never expires", msg.uid);
+            return None;
+        };
+        let age = clock.now().signed_duration_since(dated);
         debug!(
             "      [ttl] UID {} age={} days ({} hours)",
             msg.uid,
@@ -144,16 +182,13 @@ impl StateFilter {
         );
 
         // Check if message is read (has \Seen flag)
-        let is_read = msg
-            .labels
-            .iter()
-            .any(|l| matches!(l, Label::Custom(s) if s == "Seen" || s == "\\Seen"));
+        let is_read = msg.has_flag("\\Seen");
         debug!("      [ttl] is_read={}", is_read);
 
         let ttl_duration = match &self.ttl {
             Ttl::Keep => {
                 debug!("      [ttl] TTL=Keep, returning None");
-                return Ok(None);
+                return None;
             }
             Ttl::Days(dur) => {
                 debug!("      [ttl] TTL=Days({} days)", dur.num_days());
@@ -179,11 +214,7 @@ impl StateFilter {
             expired
         );
 
-        if expired {
-            Ok(Some(self.action.clone()))
-        } else {
-            Ok(None)
-        }
+        expired.then(|| self.action.clone())
     }
 }
 
@@ -250,7 +281,7 @@ fn default_action() -> StateAction {
 mod tests {
     use super::*;
     use crate::client_ops::RealClock;
-    use chrono::Duration;
+    use chrono::{Duration, Utc};
 
     fn make_test_message(date: &str, labels: Vec<&str>) -> Message {
         Message::new(
@@ -258,7 +289,7 @@ mod tests {
             1,
             b"From: test@example.com\r\nTo: recipient@example.com\r\n\r\n".to_vec(),
             labels.into_iter().map(String::from).collect(),
-            date.to_string(),
+            date.parse().ok(),
             None,
         )
     }
@@ -271,13 +302,18 @@ mod tests {
             ttl: Ttl::Keep,
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         let msg = make_test_message("2020-01-01T00:00:00+00:00", vec![]);
         let clock = RealClock;
 
         // Even with a very old message, Keep should never expire
-        assert!(filter.evaluate_ttl(&msg, &clock).unwrap().is_none());
+        assert!(filter.evaluate_ttl(&msg, &clock).is_none());
     }
 
     #[test]
@@ -288,6 +324,11 @@ mod tests {
             ttl: Ttl::Days(Duration::days(7)),
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Message from 10 days ago
@@ -295,7 +336,7 @@ mod tests {
         let msg = make_test_message(&ten_days_ago.to_rfc3339(), vec![]);
         let clock = RealClock;
 
-        let result = filter.evaluate_ttl(&msg, &clock).unwrap();
+        let result = filter.evaluate_ttl(&msg, &clock);
         assert!(result.is_some());
         assert_eq!(result.unwrap(), StateAction::Move("Archive".to_string()));
     }
@@ -308,6 +349,11 @@ mod tests {
             ttl: Ttl::Days(Duration::days(7)),
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Message from 3 days ago
@@ -315,7 +361,7 @@ mod tests {
         let msg = make_test_message(&three_days_ago.to_rfc3339(), vec![]);
         let clock = RealClock;
 
-        assert!(filter.evaluate_ttl(&msg, &clock).unwrap().is_none());
+        assert!(filter.evaluate_ttl(&msg, &clock).is_none());
     }
 
     #[test]
@@ -329,14 +375,19 @@ mod tests {
             },
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Read message from 10 days ago (past read TTL of 7 days)
         let ten_days_ago = Utc::now() - Duration::days(10);
-        let msg = make_test_message(&ten_days_ago.to_rfc3339(), vec!["Seen"]);
+        let msg = make_test_message(&ten_days_ago.to_rfc3339(), vec!["\\Seen"]);
         let clock = RealClock;
 
-        let result = filter.evaluate_ttl(&msg, &clock).unwrap();
+        let result = filter.evaluate_ttl(&msg, &clock);
         assert!(result.is_some());
     }
 
@@ -351,6 +402,11 @@ mod tests {
             },
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Unread message from 10 days ago (not past unread TTL of 21 days)
@@ -359,7 +415,7 @@ mod tests {
         let clock = RealClock;
 
         // Should NOT be expired - 10 days < 21 days unread TTL
-        assert!(filter.evaluate_ttl(&msg, &clock).unwrap().is_none());
+        assert!(filter.evaluate_ttl(&msg, &clock).is_none());
     }
 
     #[test]
@@ -373,6 +429,11 @@ mod tests {
             },
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Unread message from 25 days ago (past unread TTL of 21 days)
@@ -380,7 +441,7 @@ mod tests {
         let msg = make_test_message(&twenty_five_days_ago.to_rfc3339(), vec![]);
         let clock = RealClock;
 
-        let result = filter.evaluate_ttl(&msg, &clock).unwrap();
+        let result = filter.evaluate_ttl(&msg, &clock);
         assert!(result.is_some());
     }
 
@@ -392,6 +453,11 @@ mod tests {
             ttl: Ttl::Keep,
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         // Message with Starred label should match
@@ -411,12 +477,53 @@ mod tests {
             ttl: Ttl::Keep,
             action: StateAction::Move("Archive".to_string()),
             nerf: false,
+            mailboxes: vec![],
+            size: SizeRange::default(),
+            thread_size: SizeRange::default(),
+            flags: FlagFilter::default(),
+            date: DateSource::default(),
         };
 
         let msg = make_test_message("2024-01-01T00:00:00+00:00", vec!["anything"]);
         assert!(filter.matches(&msg));
     }
 
+    #[test]
+    fn test_state_filter_size_bounds() {
+        let filter: StateFilter =
+            serde_yaml::from_str("{ ttl: 7d, size: { over: 1KB }, thread-size: { over: 10MB } }").unwrap();
+
+        let mut msg = make_test_message("2024-01-01T00:00:00+00:00", vec![]);
+        msg.size = 1024;
+        assert!(!filter.matches(&msg));
+        msg.size = 1025;
+        assert!(filter.matches(&msg));
+
+        assert!(!filter.matches_thread_size(|| 10 << 20));
+        assert!(filter.matches_thread_size(|| 11 << 20));
+        let unbounded: StateFilter = serde_yaml::from_str("{ ttl: 7d }").unwrap();
+        assert!(unbounded.matches_thread_size(|| unreachable!()));
+    }
+
+    #[test]
+    fn test_ttl_without_a_date_never_expires() {
+        let filter: StateFilter = serde_yaml::from_str("{ ttl: 1d, action: Archive }").unwrap();
+        let undated = make_test_message("", vec![]);
+        assert_eq!(undated.internal_date, None);
+        assert_eq!(filter.evaluate_ttl(&undated, &RealClock), None);
+
+        // `date: header` ages by the Date: header, else by INTERNALDATE
+        let by_header: StateFilter = serde_yaml::from_str("{ ttl: 7d, date: header, action: Archive }").unwrap();
+        let mut msg = make_test_message(&Utc::now().to_rfc3339(), vec![]);
+        assert_eq!(by_header.evaluate_ttl(&msg, &RealClock), None);
+        msg.header_date = Some(Utc::now() - Duration::days(8));
+        assert_eq!(
+            by_header.evaluate_ttl(&msg, &RealClock),
+            Some(StateAction::Move("Archive".to_string()))
+        );
+        assert_eq!(filter.evaluate_ttl(&msg, &RealClock), None);
+    }
+
     #[test]
     fn test_ttl_deserialize_keep() {
         let yaml = "Keep";
@@ -1,8 +1,10 @@
 // src/cli.rs
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use imap_filter::utils::parse_interval;
 use secure_string::SecureString;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Command-line interface options for imap-filter.
 #[derive(Parser, Debug)]
@@ -13,8 +15,11 @@ use std::path::PathBuf;
     long_about = None
 )]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to config file
-    #[arg(short, long, default_value = "imap-filter.yml")]
+    #[arg(short, long, default_value = "imap-filter.yml", global = true)]
     pub config: PathBuf,
 
     /// IMAP server domain
@@ -43,4 +48,31 @@ pub struct Cli {
 
     #[arg(short, long, help = "turn on client.debug logging")]
     pub debug: bool,
+
+    /// Print every action that would be taken without modifying the mailbox
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Write the run's plan of actions as JSON to this path
+    #[arg(long, value_name = "PATH")]
+    pub plan_json: Option<PathBuf>,
+
+    /// Run incrementally: remember processed UIDs in this state file so
+    /// message filters only see new mail
+    #[arg(long, value_name = "PATH", conflicts_with = "daemon")]
+    pub state_file: Option<PathBuf>,
+
+    /// Stay connected and filter new mail as it arrives (IMAP IDLE)
+    #[arg(long, conflicts_with = "plan_json")]
+    pub daemon: bool,
+
+    /// How often the daemon runs the state-filter sweep (e.g. 30m, 1h, 1d)
+    #[arg(long, value_name = "INTERVAL", default_value = "1h", value_parser = parse_interval)]
+    pub sweep_interval: Duration,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate the config file without connecting to any server
+    Check,
 }
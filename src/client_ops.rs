@@ -1,9 +1,21 @@
 // src/client_ops.rs
 //
-// Clock trait abstraction for time-based operations.
-// Allows production code to use real time or virtual time for testing.
+// Trait abstractions for the mail store and for time-based operations.
+// Allows production code to run against a real IMAP session or an in-memory
+// mock, and against real time or virtual time for testing.
 
 use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
+use imap::extensions::idle::{stop_on_any, WaitOutcome};
+use imap::types::{Fetch, UnsolicitedResponse};
+use imap::{ImapConnection, Session};
+use imap_proto::types::{BodyParams, BodyStructure, ContentDisposition};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::message::{Message, MimePart};
+use crate::utils::{compress_uid_set, mark_deleted, remove_label, set_label, uid_move_gmail};
 
 /// Trait for time providers.
 /// Allows production code to use real time or virtual time for testing.
@@ -22,10 +34,423 @@ impl Clock for RealClock {
     }
 }
 
+/// What a SELECT reports about the newly selected mailbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxStatus {
+    pub exists: u32,
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    /// Only reported once CONDSTORE is enabled.
+    pub highest_modseq: Option<u64>,
+}
+
+/// Which RFC 7162 change-tracking extension the session has enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeTracking {
+    /// Neither is available; changes can only be found by re-fetching.
+    Unavailable,
+    /// CONDSTORE: `CHANGEDSINCE` fetches, but expunges must be inferred.
+    CondStore,
+    /// QRESYNC: CONDSTORE plus `VANISHED` reports of removed UIDs.
+    QResync,
+}
+
+/// RFC 6154 special-use attribute of a mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialUse {
+    All,
+    Archive,
+    Drafts,
+    Flagged,
+    Junk,
+    Sent,
+    Trash,
+}
+
+impl SpecialUse {
+    /// Parse an attribute such as `\Trash` (the backslash is optional).
+    pub fn parse(attribute: &str) -> Option<Self> {
+        match attribute.trim_start_matches('\\').to_ascii_uppercase().as_str() {
+            "ALL" => Some(SpecialUse::All),
+            "ARCHIVE" => Some(SpecialUse::Archive),
+            "DRAFTS" => Some(SpecialUse::Drafts),
+            "FLAGGED" => Some(SpecialUse::Flagged),
+            "JUNK" => Some(SpecialUse::Junk),
+            "SENT" => Some(SpecialUse::Sent),
+            "TRASH" => Some(SpecialUse::Trash),
+            _ => None,
+        }
+    }
+}
+
+/// One mailbox/label as reported by LIST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxInfo {
+    pub name: String,
+    pub special_use: Option<SpecialUse>,
+}
+
+/// A message's current labels and flags, as reported by a change fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelUpdate {
+    pub uid: u32,
+    pub labels: Vec<String>,
+}
+
+/// Everything that changed in the selected mailbox since a mod-sequence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxChanges {
+    pub updated: Vec<LabelUpdate>,
+    /// UIDs that left the mailbox; `None` unless QRESYNC is enabled.
+    pub vanished: Option<Vec<u32>>,
+}
+
+/// Trait abstracting the mail-store operations the filter engine needs.
+/// `IMAPFilter` and `ThreadProcessor` are generic over it, so the same engine
+/// runs against a live `imap::Session` or the mock client in the test harness.
+///
+/// All message-level operations address messages by UID within the
+/// currently selected mailbox. Mutating operations take a set of UIDs so an
+/// implementation can send them as batched commands.
+pub trait IMAPClientOps {
+    /// Select a mailbox; subsequent operations apply to it.
+    fn select_mailbox(&mut self, name: &str) -> Result<MailboxStatus>;
+
+    /// Return the UIDs of every message in the selected mailbox.
+    fn search_all(&mut self) -> Result<Vec<u32>>;
+
+    /// Return the UIDs greater than `uid` in the selected mailbox, i.e. the
+    /// messages that arrived after `uid` was seen.
+    fn search_since(&mut self, uid: u32) -> Result<Vec<u32>>;
+
+    /// Block on the selected mailbox (IMAP IDLE) until the server reports a
+    /// change or `timeout` elapses. Returns `true` if the mailbox changed.
+    fn wait_for_changes(&mut self, timeout: Duration) -> Result<bool>;
+
+    /// Enable the best change-tracking extension the server advertises.
+    /// Must be called before `select_mailbox` for HIGHESTMODSEQ to be reported.
+    fn enable_change_tracking(&mut self) -> Result<ChangeTracking>;
+
+    /// Fetch labels and flags of every message in the selected mailbox whose
+    /// mod-sequence is above `since_modseq`. With `vanished`, also ask for
+    /// the UIDs removed since then (requires QRESYNC).
+    fn fetch_changes(&mut self, since_modseq: u64, vanished: bool) -> Result<MailboxChanges>;
+
+    /// Fetch and parse the given UIDs into `Message`s.
+    fn fetch_messages(&mut self, uids: &[u32]) -> Result<Vec<Message>>;
+
+    /// Fetch the given UIDs at most `chunk` at a time, handing each parsed
+    /// chunk to `sink` before the next is fetched, so neither the command
+    /// line nor the responses held in memory grow with the mailbox.
+    fn fetch_chunked(
+        &mut self,
+        uids: &[u32],
+        chunk: usize,
+        sink: &mut dyn FnMut(Vec<Message>) -> Result<()>,
+    ) -> Result<()> {
+        for part in uids.chunks(chunk.max(1)) {
+            sink(self.fetch_messages(part)?)?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the raw header and text of the given UIDs, keyed by UID, for
+    /// decoding with `body::body_text`. Must not mark the messages `\Seen`.
+    fn fetch_bodies(&mut self, uids: &[u32]) -> Result<HashMap<u32, Vec<u8>>>;
+
+    /// Add a label/flag (e.g. `\Starred`, `\Important`) to messages.
+    fn add_label(&mut self, uids: &[u32], label: &str) -> Result<()>;
+
+    /// Remove a label/flag from messages.
+    fn remove_label(&mut self, uids: &[u32], label: &str) -> Result<()>;
+
+    /// Move messages out of the selected mailbox into `destination`.
+    fn move_messages(&mut self, uids: &[u32], destination: &str) -> Result<()>;
+
+    /// Mark messages as deleted (add `\Deleted`).
+    fn delete_messages(&mut self, uids: &[u32]) -> Result<()>;
+
+    /// List every mailbox/label, with its special-use attribute if any.
+    fn list_mailboxes(&mut self) -> Result<Vec<MailboxInfo>>;
+
+    /// Create a mailbox/label.
+    fn create_mailbox(&mut self, name: &str) -> Result<()>;
+
+    /// End the session.
+    fn logout(&mut self) -> Result<()>;
+}
+
+impl<C: ImapConnection> IMAPClientOps for Session<C> {
+    fn select_mailbox(&mut self, name: &str) -> Result<MailboxStatus> {
+        let mailbox = self.select(name)?;
+        Ok(MailboxStatus {
+            exists: mailbox.exists,
+            uid_validity: mailbox.uid_validity,
+            uid_next: mailbox.uid_next,
+            highest_modseq: mailbox.highest_mod_seq,
+        })
+    }
+
+    fn search_all(&mut self) -> Result<Vec<u32>> {
+        let uids = self.uid_search("ALL")?;
+        let mut uids: Vec<u32> = uids.into_iter().collect();
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    fn search_since(&mut self, uid: u32) -> Result<Vec<u32>> {
+        // `UID n:*` always matches the newest message, even when its UID is
+        // below n, so filter the result rather than trusting the range
+        let uids = self.uid_search(format!("UID {}:*", uid.saturating_add(1)))?;
+        let mut uids: Vec<u32> = uids.into_iter().filter(|u| *u > uid).collect();
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    fn wait_for_changes(&mut self, timeout: Duration) -> Result<bool> {
+        // keepalive off: hand control back on timeout instead of silently
+        // re-issuing IDLE, so the caller can run its own schedule
+        let outcome = self.idle().timeout(timeout).keepalive(false).wait_while(stop_on_any)?;
+        Ok(outcome == WaitOutcome::MailboxChanged)
+    }
+
+    fn enable_change_tracking(&mut self) -> Result<ChangeTracking> {
+        let caps = self.capabilities()?;
+        if !caps.has_str("ENABLE") {
+            return Ok(ChangeTracking::Unavailable);
+        }
+        if caps.has_str("QRESYNC") {
+            self.run_command_and_check_ok("ENABLE QRESYNC")?;
+            Ok(ChangeTracking::QResync)
+        } else if caps.has_str("CONDSTORE") {
+            self.run_command_and_check_ok("ENABLE CONDSTORE")?;
+            Ok(ChangeTracking::CondStore)
+        } else {
+            Ok(ChangeTracking::Unavailable)
+        }
+    }
+
+    fn fetch_changes(&mut self, since_modseq: u64, vanished: bool) -> Result<MailboxChanges> {
+        let modifier = if vanished {
+            format!("(CHANGEDSINCE {} VANISHED)", since_modseq)
+        } else {
+            format!("(CHANGEDSINCE {})", since_modseq)
+        };
+        let fetches = self.uid_fetch("1:*", format!("(UID FLAGS X-GM-LABELS) {}", modifier))?;
+        debug!("CHANGEDSINCE {} returned {} records", since_modseq, fetches.len());
+
+        let updated = fetches
+            .iter()
+            .filter_map(|fetch| {
+                fetch.uid.map(|uid| LabelUpdate {
+                    uid,
+                    labels: raw_labels(fetch),
+                })
+            })
+            .collect();
+        let vanished = vanished.then(|| {
+            self.take_all_unsolicited()
+                .filter_map(|response| match response {
+                    UnsolicitedResponse::Vanished { uids, .. } => Some(uids),
+                    _ => None,
+                })
+                .flatten()
+                .flatten()
+                .collect()
+        });
+
+        Ok(MailboxChanges { updated, vanished })
+    }
+
+    fn fetch_messages(&mut self, uids: &[u32]) -> Result<Vec<Message>> {
+        if uids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut sorted = uids.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let uid_set = compress_uid_set(&sorted);
+        debug!("UID FETCHing records for UIDs: {}", uid_set);
+
+        // Fetch UID, FLAGS, INTERNALDATE, X-GM-LABELS, size, MIME structure and full header in ONE batch request
+        // imap v3 properly supports Gmail extensions like X-GM-LABELS in combined fetch responses
+        // NOTE: X-GM-THRID causes server disconnection and is NOT supported
+        let fetches = self.uid_fetch(
+            &uid_set,
+            "(UID FLAGS INTERNALDATE X-GM-LABELS RFC822.SIZE BODYSTRUCTURE RFC822.HEADER)",
+        )?;
+        debug!("FETCH returned {} records", fetches.len());
+
+        let mut out = Vec::with_capacity(fetches.len());
+        for fetch in fetches.iter() {
+            let uid = fetch.uid.unwrap_or(0);
+            let seq = fetch.message;
+            debug!("Parsing FETCH record: seq={}, uid={}", seq, uid);
+
+            // extract full header bytes
+            let raw_header = fetch.header().unwrap_or(&[]).to_vec();
+
+            // convert internal date
+            let internal_date = fetch.internal_date().map(|dt| dt.with_timezone(&Utc));
+
+            let raw_labels = raw_labels(fetch);
+
+            // Thread ID will be computed from standard headers (Message-ID, In-Reply-To, References)
+            // after all messages are fetched. Pass None here - thread grouping happens in execute().
+            let thread_id: Option<String> = None;
+
+            // build Message
+            let mut msg = Message::new(uid, seq, raw_header, raw_labels, internal_date, thread_id);
+            msg.size = fetch.size.map(u64::from).unwrap_or_default();
+            if let Some(structure) = fetch.bodystructure() {
+                collect_parts(structure, &mut msg.parts);
+            }
+            debug!(
+                "Created message: uid={}, seq={}, subject={}",
+                msg.uid, msg.seq, msg.subject
+            );
+
+            // Drafts and header-stripped imports can name nobody; address
+            // predicates simply see no addresses
+            if msg.from.is_empty() && msg.recipients().next().is_none() {
+                debug!("UID {} has no address fields", uid);
+            }
+
+            out.push(msg);
+        }
+
+        Ok(out)
+    }
+
+    fn fetch_bodies(&mut self, uids: &[u32]) -> Result<HashMap<u32, Vec<u8>>> {
+        if uids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut sorted = uids.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let uid_set = compress_uid_set(&sorted);
+        debug!("UID FETCHing bodies for UIDs: {}", uid_set);
+
+        // BODY.PEEK leaves \Seen alone; the header carries the MIME structure
+        let fetches = self.uid_fetch(&uid_set, "(UID BODY.PEEK[HEADER] BODY.PEEK[TEXT])")?;
+        Ok(fetches
+            .iter()
+            .filter_map(|fetch| {
+                let mut raw = fetch.header().unwrap_or_default().to_vec();
+                raw.extend_from_slice(fetch.text().unwrap_or_default());
+                fetch.uid.map(|uid| (uid, raw))
+            })
+            .collect())
+    }
+
+    fn add_label(&mut self, uids: &[u32], label: &str) -> Result<()> {
+        set_label(self, uids, label)
+    }
+
+    fn remove_label(&mut self, uids: &[u32], label: &str) -> Result<()> {
+        remove_label(self, uids, label)
+    }
+
+    fn move_messages(&mut self, uids: &[u32], destination: &str) -> Result<()> {
+        uid_move_gmail(self, uids, destination)
+    }
+
+    fn delete_messages(&mut self, uids: &[u32]) -> Result<()> {
+        mark_deleted(self, uids)
+    }
+
+    fn list_mailboxes(&mut self) -> Result<Vec<MailboxInfo>> {
+        let names = self.list(None, Some("*"))?;
+        Ok(names
+            .iter()
+            .map(|name| MailboxInfo {
+                name: name.name().to_string(),
+                // imap does not re-export imap-proto's NameAttribute, so
+                // match on its Debug form (`All`, `Trash`, ...)
+                special_use: name
+                    .attributes()
+                    .iter()
+                    .find_map(|attribute| SpecialUse::parse(&format!("{:?}", attribute))),
+            })
+            .collect())
+    }
+
+    fn create_mailbox(&mut self, name: &str) -> Result<()> {
+        self.create(name)
+            .map_err(|e| eyre!("Failed to create label '{}': {:?}", name, e))
+    }
+
+    fn logout(&mut self) -> Result<()> {
+        Session::logout(self).map_err(|e| eyre!("IMAP logout failed: {}", e))
+    }
+}
+
+/// Labels of a FETCH record: Gmail labels via imap v3's gmail_labels()
+/// accessor, plus the IMAP FLAGS.
+fn raw_labels(fetch: &Fetch<'_>) -> Vec<String> {
+    let mut label_set: HashSet<String> = fetch
+        .gmail_labels()
+        .map(|iter| iter.map(String::from).collect())
+        .unwrap_or_default();
+    for flag in fetch.flags() {
+        label_set.insert(flag.to_string());
+    }
+    label_set.into_iter().collect()
+}
+
+/// Flatten a BODYSTRUCTURE into its leaf parts, in order. An attached
+/// message counts as one part; its insides are not examined.
+fn collect_parts(structure: &BodyStructure<'_>, out: &mut Vec<MimePart>) {
+    let (common, octets) = match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for body in bodies {
+                collect_parts(body, out);
+            }
+            return;
+        }
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, other.octets),
+    };
+    let disposition = common.disposition.as_ref();
+    let filename = disposition
+        .and_then(|d| param(&d.params, "filename"))
+        .or_else(|| param(&common.ty.params, "name"));
+    let attachment = match disposition {
+        Some(ContentDisposition { ty, .. }) => ty.eq_ignore_ascii_case("attachment"),
+        None => filename.is_some(),
+    };
+    out.push(MimePart {
+        mime_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        filename,
+        size: u64::from(octets),
+        attachment,
+    });
+}
+
+fn param(params: &BodyParams<'_>, name: &str) -> Option<String> {
+    params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_special_use_parse() {
+        assert_eq!(SpecialUse::parse("\\Trash"), Some(SpecialUse::Trash));
+        assert_eq!(SpecialUse::parse("All"), Some(SpecialUse::All));
+        assert_eq!(SpecialUse::parse("\\junk"), Some(SpecialUse::Junk));
+        assert_eq!(SpecialUse::parse("\\HasNoChildren"), None);
+    }
+
     #[test]
     fn test_real_clock_returns_current_time() {
         let clock = RealClock;
@@ -0,0 +1,314 @@
+// src/daemon.rs
+//
+// Long-running mode: keeps one session open and IDLEs on INBOX. New mail is
+// run through the message filters as it arrives, and the state filters run as
+// a periodic sweep over every configured mailbox. A dropped connection is
+// re-established with backoff.
+
+use eyre::Result;
+use log::{debug, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cfg::config::Config;
+use crate::client_ops::{Clock, IMAPClientOps, MailboxStatus};
+use crate::imap_filter::IMAPFilter;
+use crate::plan::Plan;
+use crate::utils::{classify_report, ImapErrorKind};
+
+/// Shortest IDLE we will issue; a zero read timeout is rejected by the socket.
+const MIN_IDLE: Duration = Duration::from_millis(10);
+
+/// Timing knobs for daemon mode.
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// How often the state-filter sweep runs.
+    pub sweep_interval: Duration,
+    /// Longest single IDLE before it is re-issued. RFC 2177 asks clients to
+    /// re-issue IDLE at least every 29 minutes.
+    pub idle_timeout: Duration,
+    /// Longest single IDLE before the shutdown flag is checked again; a
+    /// blocked IDLE cannot see the flag flip.
+    pub shutdown_poll: Duration,
+    /// Delay before the first reconnect attempt; doubled after each failure.
+    pub initial_backoff: Duration,
+    /// Upper bound on the reconnect delay.
+    pub max_backoff: Duration,
+    /// Plan actions without applying them.
+    pub dry_run: bool,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        DaemonOptions {
+            sweep_interval: Duration::from_secs(60 * 60),
+            idle_timeout: Duration::from_secs(25 * 60),
+            shutdown_poll: Duration::from_secs(30),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+/// Where the daemon is in its schedule; survives reconnects.
+struct Progress {
+    /// Highest INBOX UID already run through the message filters; `None`
+    /// until the first SELECT seeds it.
+    last_uid: Option<u32>,
+    /// INBOX UIDVALIDITY that `last_uid` belongs to.
+    uid_validity: Option<u32>,
+    next_sweep: Instant,
+}
+
+/// Run until `shutdown` is set.
+///
+/// `connect` opens and authenticates a new session. It is called at start-up
+/// and again whenever an error classified as `ConnectionLost` ends the
+/// session. A failed filtering pass or sweep is logged and retried on the
+/// next wake-up. Errors that no retry can fix stop the daemon and are
+/// returned: a move destination that does not exist, or an INBOX that cannot
+/// be selected or IDLEd on.
+///
+/// Only mail that arrives after start-up counts as new; what is already in
+/// INBOX is left to the state-filter sweep.
+pub fn run_daemon<S, K, F>(
+    mut connect: F,
+    config: Config,
+    clock: K,
+    options: &DaemonOptions,
+    shutdown: &AtomicBool,
+) -> Result<()>
+where
+    S: IMAPClientOps,
+    K: Clock,
+    F: FnMut() -> Result<S>,
+{
+    let client = match connect_with_backoff(&mut connect, options, shutdown)? {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+    let mut filter = IMAPFilter::with_clock(client, config, clock);
+    let mut progress = Progress {
+        last_uid: None,
+        uid_validity: None,
+        next_sweep: Instant::now(),
+    };
+
+    loop {
+        match serve(&mut filter, &mut progress, options, shutdown) {
+            Ok(()) => {
+                info!("Daemon shutting down; logging out");
+                return filter.client.logout();
+            }
+            Err(e) if classify_report(&e) == ImapErrorKind::ConnectionLost => {
+                warn!("⚠️  Connection lost: {:?}; reconnecting", e);
+                match connect_with_backoff(&mut connect, options, shutdown)? {
+                    Some(client) => filter.reconnected(client),
+                    None => return Ok(()),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Filter new mail and sweep on schedule until shutdown or an error.
+fn serve<S: IMAPClientOps, K: Clock>(
+    filter: &mut IMAPFilter<S, K>,
+    progress: &mut Progress,
+    options: &DaemonOptions,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    // Checked once per session so a bad destination stops the daemon
+    // instead of failing every pass
+    filter.check_labels()?;
+    let status = filter.client.select_mailbox("INBOX")?;
+    let mut last_uid = watermark(filter, progress, &status)?;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match filter_new_mail(filter, last_uid, options) {
+            Ok(Some(newest)) => {
+                last_uid = newest;
+                progress.last_uid = Some(newest);
+            }
+            Ok(None) => {}
+            Err(e) => survive(e, "Filtering new mail")?,
+        }
+
+        if Instant::now() >= progress.next_sweep {
+            info!("🧹 Running state-filter sweep");
+            match filter.sweep_state_filters(options.dry_run) {
+                Ok(plan) => log_plan(&plan),
+                Err(e) => survive(e, "State-filter sweep")?,
+            }
+            progress.next_sweep = Instant::now() + options.sweep_interval;
+            // The sweep may have left another mailbox selected
+            let status = filter.client.select_mailbox("INBOX")?;
+            last_uid = watermark(filter, progress, &status)?;
+        }
+
+        let until_sweep = progress.next_sweep.saturating_duration_since(Instant::now());
+        let wait = options
+            .idle_timeout
+            .min(options.shutdown_poll)
+            .min(until_sweep)
+            .max(MIN_IDLE);
+        debug!("IDLE on INBOX for up to {:?}", wait);
+        if filter.client.wait_for_changes(wait)? {
+            debug!("INBOX changed during IDLE");
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the message filters on INBOX mail above `last_uid`. Returns the
+/// newest UID filtered, if there was any new mail.
+fn filter_new_mail<S: IMAPClientOps, K: Clock>(
+    filter: &mut IMAPFilter<S, K>,
+    last_uid: u32,
+    options: &DaemonOptions,
+) -> Result<Option<u32>> {
+    let new_uids = filter.client.search_since(last_uid)?;
+    let Some(&newest) = new_uids.last() else {
+        return Ok(None);
+    };
+    info!("📬 {} new message(s) since UID {}", new_uids.len(), last_uid);
+    let plan = filter.filter_new_messages(&new_uids, options.dry_run)?;
+    log_plan(&plan);
+    Ok(Some(newest))
+}
+
+/// Log a failed pass so the daemon can carry on, unless the session itself
+/// is gone; that error is returned so the caller reconnects.
+fn survive(e: eyre::Report, pass: &str) -> Result<()> {
+    if classify_report(&e).is_session_error() {
+        return Err(e);
+    }
+    warn!("⚠️  {} failed: {:?}; retrying on the next pass", pass, e);
+    Ok(())
+}
+
+/// The UID above which INBOX mail is new. Seeded from the mailbox on the
+/// first SELECT, and again if UIDVALIDITY changed since, as the old UIDs
+/// then no longer name the same messages.
+fn watermark<S: IMAPClientOps, K: Clock>(
+    filter: &mut IMAPFilter<S, K>,
+    progress: &mut Progress,
+    status: &MailboxStatus,
+) -> Result<u32> {
+    let last_uid = match progress.last_uid {
+        Some(uid) if progress.uid_validity == status.uid_validity => uid,
+        seen => {
+            if seen.is_some() {
+                warn!(
+                    "⚠️  INBOX UIDVALIDITY changed from {:?} to {:?}; UIDs were renumbered",
+                    progress.uid_validity, status.uid_validity
+                );
+            }
+            let uid = newest_uid(filter, status)?;
+            info!("Watching INBOX for mail above UID {}", uid);
+            uid
+        }
+    };
+    progress.last_uid = Some(last_uid);
+    progress.uid_validity = status.uid_validity;
+    Ok(last_uid)
+}
+
+/// Highest UID in the selected mailbox: `UIDNEXT - 1`, or the last UID a
+/// search returns if the server did not report UIDNEXT.
+fn newest_uid<S: IMAPClientOps, K: Clock>(filter: &mut IMAPFilter<S, K>, status: &MailboxStatus) -> Result<u32> {
+    match status.uid_next {
+        Some(next) => Ok(next.saturating_sub(1)),
+        None => Ok(filter.client.search_all()?.last().copied().unwrap_or(0)),
+    }
+}
+
+/// Call `connect` until it succeeds, backing off between connection
+/// failures. Returns `None` if shutdown was requested while retrying.
+fn connect_with_backoff<S, F>(connect: &mut F, options: &DaemonOptions, shutdown: &AtomicBool) -> Result<Option<S>>
+where
+    F: FnMut() -> Result<S>,
+{
+    let mut backoff = options.initial_backoff;
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        match connect() {
+            Ok(client) => {
+                info!("✅ Daemon connected");
+                return Ok(Some(client));
+            }
+            Err(e) if classify_report(&e) == ImapErrorKind::ConnectionLost => {
+                warn!("⚠️  Connect failed: {:?}; retrying in {:?}", e, backoff);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(options.max_backoff);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn log_plan(plan: &Plan) {
+    if plan.is_empty() {
+        return;
+    }
+    if plan.dry_run {
+        info!("[dry-run] Planned actions:\n{}", plan);
+    } else if plan.failures().is_empty() {
+        info!("Applied {} action(s)", plan.len());
+    } else {
+        warn!("Some actions failed:\n{}", plan);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::eyre;
+
+    fn fast_options() -> DaemonOptions {
+        DaemonOptions {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            ..DaemonOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_connect_retries_connection_errors() {
+        let mut attempts = 0;
+        let mut connect = || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(eyre!("Failed to connect: Connection refused (os error 111)"))
+            } else {
+                Ok(attempts)
+            }
+        };
+        let shutdown = AtomicBool::new(false);
+        let client = connect_with_backoff(&mut connect, &fast_options(), &shutdown).unwrap();
+        assert_eq!(client, Some(3));
+    }
+
+    #[test]
+    fn test_connect_gives_up_on_other_errors() {
+        let mut connect = || -> Result<u32> { Err(eyre!("IMAP login failed: authentication rejected")) };
+        let shutdown = AtomicBool::new(false);
+        assert!(connect_with_backoff(&mut connect, &fast_options(), &shutdown).is_err());
+    }
+
+    #[test]
+    fn test_connect_stops_on_shutdown() {
+        let mut connect = || -> Result<u32> { Ok(1) };
+        let shutdown = AtomicBool::new(true);
+        assert_eq!(
+            connect_with_backoff(&mut connect, &fast_options(), &shutdown).unwrap(),
+            None
+        );
+    }
+}
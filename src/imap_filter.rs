@@ -1,214 +1,750 @@
 // src/imap_filter.rs
 
-use eyre::Result;
-use imap::{ImapConnection, Session};
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
 use log::{debug, error, info};
+use std::collections::{HashMap, HashSet};
 
+use crate::body::body_text;
 use crate::cfg::config::Config;
-use crate::cfg::message_filter::{FilterAction, MessageFilter};
-use crate::cfg::state_filter::{StateAction, StateFilter, Ttl};
+use crate::cfg::label::Label;
+use crate::cfg::message_filter::{MessageFilter, OnError};
+use crate::cfg::state_filter::{StateFilter, Ttl};
+use crate::client_ops::{ChangeTracking, Clock, IMAPClientOps, MailboxChanges, MailboxStatus, RealClock};
+use crate::label_registry::LabelRegistry;
 use crate::message::Message;
+use crate::plan::{ActionOutcome, Phase, Plan, PlannedAction, PlannedOp};
+use crate::sync_state::SyncState;
 use crate::thread::ThreadProcessor;
-use crate::utils::{set_label, uid_move_gmail};
+use crate::utils::{classify_report, BatchError, ImapErrorKind, MAX_FETCH};
 
-pub fn apply_message_action<C: ImapConnection>(
-    client: &mut Session<C>,
-    msg: &Message,
-    action: &FilterAction,
-) -> Result<()> {
-    let sender = msg.sender_display();
-    match action {
-        FilterAction::Star => {
-            info!("⭐ Starring UID {} from {} - {}", msg.uid, sender, msg.subject);
-            set_label(client, msg.uid, "\\Starred", &msg.subject)?;
-        }
-        FilterAction::Flag => {
-            info!("🚩 Flagging UID {} from {} - {}", msg.uid, sender, msg.subject);
-            set_label(client, msg.uid, "\\Important", &msg.subject)?;
+/// One mailbox's messages as fetched at the start of a run, sorted by UID.
+struct MailboxScan {
+    mailbox: String,
+    status: MailboxStatus,
+    messages: Vec<Message>,
+}
+
+/// Messages that share one step, operation and the labels a move out of
+/// All Mail releases (the labels the filter selected them by).
+struct ActionGroup {
+    /// Position of the action among those taken on the message.
+    step: usize,
+    op: PlannedOp,
+    release: Vec<Label>,
+    entries: Vec<BatchEntry>,
+}
+
+/// One message in an `ActionGroup`.
+struct BatchEntry {
+    uid: u32,
+    labels: Vec<Label>,
+    /// Index of the action in the plan, where its outcome is recorded.
+    action: usize,
+    /// The filter match the action belongs to; indexes `ActionBatch::chains`.
+    chain: usize,
+}
+
+/// Actions decided on during one phase. They are applied together at the
+/// end of the phase, one batched command per (operation, destination), so a
+/// large purge does not cost a round trip per message. Each message's
+/// actions are applied in the order they were decided: every first action
+/// before any second one.
+struct ActionBatch {
+    dry_run: bool,
+    groups: Vec<ActionGroup>,
+    /// What a failure does to the later actions of each filter match.
+    chains: Vec<OnError>,
+}
+
+impl ActionBatch {
+    fn new(dry_run: bool) -> Self {
+        ActionBatch {
+            dry_run,
+            groups: Vec::new(),
+            chains: Vec::new(),
         }
-        FilterAction::Move(label) => {
-            info!(
+    }
+
+    /// Start the actions of a new filter match; later `push`es belong to it.
+    fn start_chain(&mut self, on_error: OnError) {
+        self.chains.push(on_error);
+    }
+
+    /// Record `action` in the plan and queue it against `msg` as the
+    /// `step`th action taken on it, part of the current filter match.
+    fn push(&mut self, plan: &mut Plan, action: PlannedAction, msg: &Message, step: usize, release: &[Label]) {
+        let sender = msg.sender_display();
+        let op = action.op.clone();
+        match &op {
+            _ if self.dry_run => info!("[dry-run] Would apply {} to UID {}", op, msg.uid),
+            PlannedOp::Star => info!("⭐ Starring UID {} from {} - {}", msg.uid, sender, msg.subject),
+            PlannedOp::Flag => info!("🚩 Flagging UID {} from {} - {}", msg.uid, sender, msg.subject),
+            PlannedOp::Move(label) => info!(
                 "➡️ Moving UID {} from {} → {} - {}",
                 msg.uid, sender, label, msg.subject
-            );
-            uid_move_gmail(client, msg.uid, label, &msg.subject)?;
+            ),
+            PlannedOp::Delete => info!("🗑 Deleting UID {} from {} - {}", msg.uid, sender, msg.subject),
+        }
+
+        let entry = BatchEntry {
+            uid: msg.uid,
+            labels: msg.labels.clone(),
+            action: plan.len(),
+            chain: self.chains.len().saturating_sub(1),
+        };
+        plan.push(action);
+        match self
+            .groups
+            .iter_mut()
+            .find(|g| g.step == step && g.op == op && g.release == release)
+        {
+            Some(group) => group.entries.push(entry),
+            None => self.groups.push(ActionGroup {
+                step,
+                op,
+                release: release.to_vec(),
+                entries: vec![entry],
+            }),
         }
     }
-    Ok(())
+
+    /// Apply every group against the selected `mailbox`, creating missing
+    /// destinations first, and record each action's outcome in `plan`. A
+    /// dry run only notes which destinations would be created.
+    ///
+    /// A failed action is recorded and the batch carries on. Under
+    /// `on-error: abort` the failed message's later actions from the same
+    /// filter are skipped. A lost connection stops the batch with an error.
+    fn apply<S: IMAPClientOps>(
+        mut self,
+        client: &mut S,
+        labels: &mut LabelRegistry,
+        mailbox: &str,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        self.groups.sort_by_key(|g| g.step);
+        let mut aborted: HashSet<(usize, u32)> = HashSet::new();
+        for mut group in self.groups {
+            group.entries.retain(|e| {
+                let skip = aborted.contains(&(e.chain, e.uid));
+                if skip {
+                    plan.actions[e.action].outcome = ActionOutcome::Skipped;
+                }
+                !skip
+            });
+            if group.entries.is_empty() {
+                continue;
+            }
+
+            let failed = match apply_group(client, labels, &group, mailbox, self.dry_run) {
+                Ok(()) => Vec::new(),
+                Err(e) if classify_report(&e) == ImapErrorKind::ConnectionLost => return Err(e),
+                Err(e) => {
+                    error!("❌ {:#}", e);
+                    let uids = match e.downcast_ref::<BatchError>() {
+                        Some(batch) => batch.failed.clone(),
+                        None => group.entries.iter().map(|e| e.uid).collect(),
+                    };
+                    uids.into_iter().map(|uid| (uid, format!("{:#}", e))).collect()
+                }
+            };
+
+            for entry in &group.entries {
+                let outcome = match failed.iter().find(|(uid, _)| *uid == entry.uid) {
+                    Some((_, err)) => {
+                        if self.chains[entry.chain] == OnError::Abort {
+                            aborted.insert((entry.chain, entry.uid));
+                        }
+                        ActionOutcome::Failed(err.clone())
+                    }
+                    None if self.dry_run => ActionOutcome::Planned,
+                    None => ActionOutcome::Applied,
+                };
+                plan.actions[entry.action].outcome = outcome;
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn apply_state_action<C: ImapConnection>(
-    client: &mut Session<C>,
-    msg: &Message,
-    action: &StateAction,
+/// Apply one group; in a dry run, only resolve its destination.
+fn apply_group<S: IMAPClientOps>(
+    client: &mut S,
+    labels: &mut LabelRegistry,
+    group: &ActionGroup,
+    mailbox: &str,
+    dry_run: bool,
 ) -> Result<()> {
-    let sender = msg.sender_display();
-    match action {
-        StateAction::Delete => {
-            info!("🗑 Deleting UID {} from {} - {}", msg.uid, sender, msg.subject);
-            client.uid_store(msg.uid.to_string(), "+FLAGS (\\Deleted)")?;
+    let uids: Vec<u32> = group.entries.iter().map(|e| e.uid).collect();
+    let destination = match &group.op {
+        PlannedOp::Move(destination) => Some(labels.ensure(client, destination, dry_run)?),
+        _ => None,
+    };
+    if dry_run {
+        return Ok(());
+    }
+    debug!("Applying {} to {} message(s) in {}", group.op, uids.len(), mailbox);
+    match (&group.op, destination) {
+        (PlannedOp::Star, _) => client.add_label(&uids, "\\Starred"),
+        (PlannedOp::Flag, _) => client.add_label(&uids, "\\Important"),
+        (PlannedOp::Delete, _) => client.delete_messages(&uids),
+        (PlannedOp::Move(_), Some(destination)) if labels.is_all_mail(mailbox) => {
+            move_by_label(client, group, &destination)
         }
-        StateAction::Move(label) => {
-            info!(
-                "➡️ Moving UID {} from {} → {} - {}",
-                msg.uid, sender, label, msg.subject
-            );
-            uid_move_gmail(client, msg.uid, label, &msg.subject)?;
+        (PlannedOp::Move(_), Some(destination)) => client.move_messages(&uids, &destination),
+        (PlannedOp::Move(_), None) => unreachable!("move destinations are resolved above"),
+    }
+    .map_err(|e| e.wrap_err(format!("{} in {}", group.op, mailbox)))
+}
+
+/// The message filters that act on `msg`, in evaluation order: every match
+/// up to and including the first that stops. A filter that moves the message
+/// always stops, since later filters could no longer reach it. Only the first
+/// match of each named group counts.
+///
+/// `None` if a filter that would be reached cannot decide without the body
+/// and `body` was not given.
+fn matching_filters<'a>(
+    filters: &'a [MessageFilter],
+    mailbox: &str,
+    msg: &Message,
+    body: Option<&str>,
+    now: DateTime<Utc>,
+) -> Option<Vec<&'a MessageFilter>> {
+    let mut matched: Vec<&MessageFilter> = Vec::new();
+    for filter in filters {
+        if filter.actions.is_empty() || !filter.applies_to(mailbox) {
+            continue;
+        }
+        if let Some(group) = &filter.group {
+            if matched.iter().any(|m| m.group.as_ref() == Some(group)) {
+                continue;
+            }
+        }
+        if filter.evaluate(msg, body, now)? {
+            matched.push(filter);
+            if filter.stop || filter.moves() {
+                break;
+            }
+        }
+    }
+    Some(matched)
+}
+
+/// Move a group of messages out of All Mail into `destination`.
+///
+/// In Gmail's All Mail a MOVE would trash the messages, so there the move is
+/// done with labels instead: add `destination`, then drop `\Inbox` and any of
+/// the group's `release` labels from the messages that carry them.
+fn move_by_label<S: IMAPClientOps>(client: &mut S, group: &ActionGroup, destination: &str) -> Result<()> {
+    let uids: Vec<u32> = group.entries.iter().map(|e| e.uid).collect();
+    client.add_label(&uids, destination)?;
+
+    let mut released = vec![Label::Inbox];
+    released.extend(group.release.iter().filter(|l| **l != Label::Inbox).cloned());
+    for label in &released {
+        let carrying: Vec<u32> = group
+            .entries
+            .iter()
+            .filter(|e| e.labels.contains(label))
+            .map(|e| e.uid)
+            .collect();
+        if !carrying.is_empty() {
+            client.remove_label(&carrying, &label.to_string())?;
         }
     }
     Ok(())
 }
 
-pub struct IMAPFilter<C: ImapConnection> {
-    pub client: Session<C>,
+pub struct IMAPFilter<S: IMAPClientOps, K: Clock = RealClock> {
+    pub client: S,
+    pub clock: K,
+    /// Mailboxes each run scans, in order.
+    pub mailboxes: Vec<String>,
     pub message_filters: Vec<MessageFilter>,
     pub state_filters: Vec<StateFilter>,
+    /// When set, message filters only see UIDs not processed by earlier runs.
+    pub sync_state: Option<SyncState>,
+    /// The session's mailboxes/labels; reset with `reconnected`.
+    pub labels: LabelRegistry,
+    /// Most UIDs fetched per FETCH; large mailboxes are fetched in chunks.
+    pub fetch_chunk: usize,
 }
 
-impl<C: ImapConnection> IMAPFilter<C> {
-    pub fn new(client: Session<C>, config: Config) -> Self {
+impl<S: IMAPClientOps> IMAPFilter<S> {
+    pub fn new(client: S, config: Config) -> Self {
+        Self::with_clock(client, config, RealClock)
+    }
+}
+
+impl<S: IMAPClientOps, K: Clock> IMAPFilter<S, K> {
+    /// Create a filter that evaluates TTLs against the given clock.
+    pub fn with_clock(client: S, config: Config, clock: K) -> Self {
         debug!(
-            "Initializing IMAPFilter with {} message_filters and {} state_filters",
+            "Initializing IMAPFilter with {} message_filters and {} state_filters over {:?}",
             config.message_filters.len(),
             config.state_filters.len(),
+            config.mailboxes,
         );
 
+        // Message filters run by priority; the sort is stable, so equal
+        // priorities keep their config order
+        let mut message_filters = config.message_filters;
+        message_filters.sort_by_key(|f| f.priority);
+        for filter in &mut message_filters {
+            filter.set_internal_domains(&config.internal_domains);
+        }
+
         IMAPFilter {
             client,
-            message_filters: config.message_filters,
+            clock,
+            mailboxes: config.mailboxes,
+            message_filters,
             state_filters: config.state_filters,
+            sync_state: None,
+            labels: LabelRegistry::default(),
+            fetch_chunk: MAX_FETCH,
         }
     }
 
-    fn fetch_messages(&mut self) -> Result<Vec<Message>> {
-        debug!("Fetching all messages from INBOX");
+    /// Swap in a new session, dropping everything cached from the old one.
+    pub fn reconnected(&mut self, client: S) {
+        self.client = client;
+        self.labels = LabelRegistry::default();
+    }
+
+    /// List the session's labels once and check the configuration against
+    /// them: every special-use move destination (e.g. `\Trash`) must exist
+    /// on the server.
+    pub fn check_labels(&mut self) -> Result<()> {
+        self.labels.load(&mut self.client)?;
+        let destinations = self
+            .message_filters
+            .iter()
+            .flat_map(|f| f.actions.iter().map(move |a| (&f.name, PlannedOp::from(a))))
+            .chain(self.state_filters.iter().map(|f| (&f.name, PlannedOp::from(&f.action))));
+        for (filter, op) in destinations {
+            if let PlannedOp::Move(destination) = op {
+                self.labels
+                    .resolve(&destination)
+                    .map_err(|e| eyre!("Filter '{}': {}", filter, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run incrementally: message filters skip UIDs recorded in `state`, and
+    /// each non-dry run records what it processed and saves the state.
+    pub fn with_sync_state(mut self, state: SyncState) -> Self {
+        self.sync_state = Some(state);
+        self
+    }
+
+    /// Header names the message filters match on; only these and
+    /// `CORE_HEADERS` are kept on fetched messages.
+    fn filter_headers(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .message_filters
+            .iter()
+            .flat_map(|f| f.header_names().into_iter().cloned())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Fetch `uids` of the selected mailbox `fetch_chunk` at a time, trimming
+    /// each chunk's headers as it arrives. Returned sorted by UID.
+    fn fetch_compact(&mut self, uids: &[u32]) -> Result<Vec<Message>> {
+        let keep = self.filter_headers();
+        let mut out = Vec::with_capacity(uids.len());
+        self.client.fetch_chunked(uids, self.fetch_chunk, &mut |mut fetched| {
+            debug!("Fetched a chunk of {} messages", fetched.len());
+            for msg in &mut fetched {
+                msg.retain_headers(&keep);
+            }
+            out.append(&mut fetched);
+            Ok(())
+        })?;
+        out.sort_by_key(|m| m.uid);
+        Ok(out)
+    }
+
+    /// Decoded bodies of the `messages` whose filtering reaches a message
+    /// filter that cannot decide from headers alone, fetched `fetch_chunk` at
+    /// a time. A message an earlier filter already moves or stops, or whose
+    /// group is already taken, is never downloaded.
+    fn fetch_bodies(
+        &mut self,
+        mailbox: &str,
+        messages: &[&Message],
+        now: DateTime<Utc>,
+    ) -> Result<HashMap<u32, String>> {
+        let undecided: Vec<u32> = messages
+            .iter()
+            .filter(|msg| matching_filters(&self.message_filters, mailbox, msg, None, now).is_none())
+            .map(|msg| msg.uid)
+            .collect();
+        let mut bodies = HashMap::with_capacity(undecided.len());
+        if undecided.is_empty() {
+            return Ok(bodies);
+        }
+        debug!("Fetching {} message bodies from {}", undecided.len(), mailbox);
+        for chunk in undecided.chunks(self.fetch_chunk.max(1)) {
+            for (uid, raw) in self.client.fetch_bodies(chunk)? {
+                bodies.insert(uid, body_text(&raw));
+            }
+        }
+        Ok(bodies)
+    }
+
+    fn fetch_messages(&mut self, mailbox: &str, tracking: ChangeTracking) -> Result<(MailboxStatus, Vec<Message>)> {
+        debug!("Fetching all messages from {}", mailbox);
 
         // 1) Select mailbox
-        self.client.select("INBOX")?;
+        let status = self.client.select_mailbox(mailbox)?;
 
         // 2) Search all messages
-        let seqs = self.client.search("ALL")?;
-        debug!("SEARCH returned {} messages in INBOX", seqs.len());
-        if seqs.is_empty() {
-            return Ok(vec![]);
+        let uids = self.client.search_all()?;
+        debug!("SEARCH returned {} messages in {}", uids.len(), mailbox);
+
+        if tracking != ChangeTracking::Unavailable && status.highest_modseq.is_some() {
+            let out = self.fetch_tracked(mailbox, tracking, &status, &uids)?;
+            return Ok((status, out));
+        }
+        if uids.is_empty() {
+            return Ok((status, vec![]));
+        }
+
+        // 3) Fetch and parse the messages in bounded chunks
+        let out = self.fetch_compact(&uids)?;
+
+        debug!("Successfully fetched {} messages", out.len());
+        Ok((status, out))
+    }
+
+    /// Bring the cached snapshot up to date and build messages from it.
+    /// Only messages whose labels or flags changed since the snapshot's
+    /// HIGHESTMODSEQ are re-fetched, and only new messages have their headers
+    /// fetched. Without a usable snapshot, everything is fetched once.
+    /// The snapshot only keeps the headers the message filters match on; a
+    /// filter matching on a header it lacks has the snapshot taken again.
+    fn fetch_tracked(
+        &mut self,
+        mailbox: &str,
+        tracking: ChangeTracking,
+        status: &MailboxStatus,
+        uids: &[u32],
+    ) -> Result<Vec<Message>> {
+        let keep = self.filter_headers();
+        let Some(state) = self.sync_state.as_mut() else {
+            return Err(eyre!("change tracking without sync state"));
+        };
+        let cached = state.mailbox_mut(mailbox);
+        cached.sync(status);
+        if cached.highest_modseq.is_some() && !cached.snapshot_keeps(&keep) {
+            info!("Snapshot of {} lacks headers the message filters match on", mailbox);
+            cached.highest_modseq = None;
         }
 
-        // 3) Build sequence-set
-        let seq_set = seqs.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(",");
-        debug!("FETCHing records for sequences: {}", seq_set);
-
-        // 4) Fetch UID, FLAGS, INTERNALDATE, X-GM-LABELS, and full header in ONE batch request
-        // imap v3 properly supports Gmail extensions like X-GM-LABELS in combined fetch responses
-        // NOTE: X-GM-THRID causes server disconnection and is NOT supported
-        let fetches = self
-            .client
-            .fetch(&seq_set, "(UID FLAGS INTERNALDATE X-GM-LABELS RFC822.HEADER)")?;
-        debug!("FETCH returned {} records", fetches.len());
-
-        let mut out = Vec::with_capacity(fetches.len());
-        for fetch in fetches.iter() {
-            let uid = fetch.uid.unwrap_or(0);
-            let seq = fetch.message;
-            debug!("Parsing FETCH record: seq={}, uid={}", seq, uid);
-
-            // extract full header bytes
-            let raw_header = fetch.header().unwrap_or(&[]).to_vec();
-            // DEBUG: dump raw headers for diagnostics
-            let header_text = String::from_utf8_lossy(&raw_header).into_owned();
-
-            // convert internal date
-            let date_str = fetch.internal_date().map(|dt| dt.to_rfc3339()).unwrap_or_default();
-
-            // Labels: use imap v3's gmail_labels() accessor (fetched in batch above)
-            // Then add IMAP FLAGS to the label set
-            let mut label_set: std::collections::HashSet<String> = fetch
-                .gmail_labels()
-                .map(|iter| iter.map(String::from).collect())
-                .unwrap_or_default();
-            for flag in fetch.flags() {
-                label_set.insert(flag.to_string());
+        match cached.highest_modseq {
+            Some(since) => {
+                let changes = if status.highest_modseq == Some(since) {
+                    debug!("HIGHESTMODSEQ unchanged at {}; no label or flag changes", since);
+                    MailboxChanges::default()
+                } else {
+                    self.client.fetch_changes(since, tracking == ChangeTracking::QResync)?
+                };
+                let missing = cached.apply_changes(&changes, uids);
+                // Current now, even if nothing new is fetched below
+                cached.highest_modseq = status.highest_modseq;
+                info!(
+                    "Changes in {} since MODSEQ {}: {} updated, {} vanished, {} new",
+                    mailbox,
+                    since,
+                    changes.updated.len(),
+                    changes.vanished.as_ref().map_or(0, |v| v.len()),
+                    missing.len()
+                );
+                self.client.fetch_chunked(&missing, self.fetch_chunk, &mut |fetched| {
+                    cached.extend_snapshot(&fetched);
+                    Ok(())
+                })?;
+            }
+            None => {
+                info!("No metadata snapshot yet; fetching all {} messages", uids.len());
+                cached.replace_snapshot(&[], status.highest_modseq, &keep);
+                self.client.fetch_chunked(uids, self.fetch_chunk, &mut |fetched| {
+                    cached.extend_snapshot(&fetched);
+                    Ok(())
+                })?;
             }
-            let raw_labels: Vec<String> = label_set.into_iter().collect();
+        }
 
-            // Thread ID will be computed from standard headers (Message-ID, In-Reply-To, References)
-            // after all messages are fetched. Pass None here - thread grouping happens in execute().
-            let thread_id: Option<String> = None;
+        Ok(cached.snapshot_messages())
+    }
 
-            // build Message
-            let msg = Message::new(uid, seq, raw_header, raw_labels, date_str, thread_id);
-            debug!(
-                "Created message: uid={}, seq={}, subject={}",
-                msg.uid, msg.seq, msg.subject
-            );
+    /// Split fetched messages into those the message filters have not seen
+    /// yet and those they have. Without sync state, every message is new.
+    fn partition_new<'a>(
+        &mut self,
+        mailbox: &str,
+        status: &MailboxStatus,
+        messages: &'a [Message],
+    ) -> (Vec<&'a Message>, Vec<&'a Message>) {
+        let Some(state) = self.sync_state.as_mut() else {
+            return (messages.iter().collect(), Vec::new());
+        };
+
+        let cached = state.mailbox_mut(mailbox);
+        let reset = cached.sync(status);
+        if !reset && cached.uid_next.is_some() && cached.uid_next == status.uid_next {
+            debug!("UIDNEXT unchanged at {:?}; no new mail since last run", status.uid_next);
+        }
+        let (new, seen): (Vec<&Message>, Vec<&Message>) = messages.iter().partition(|m| cached.is_new(m.uid));
+        info!(
+            "Incremental run of {}: {} new, {} already processed by message filters",
+            mailbox,
+            new.len(),
+            seen.len()
+        );
+        (new, seen)
+    }
 
-            if msg.from.is_empty() && msg.to.is_empty() && msg.cc.is_empty() {
-                error!("UID {} address fields empty. Header was:\n{}", uid, header_text);
+    /// Fetch every configured mailbox before acting on any, so a message
+    /// moved from one scanned mailbox into another is not processed twice in
+    /// the same run. Returns the mailbox left selected.
+    fn fetch_all(&mut self, tracking: ChangeTracking) -> Result<(Vec<MailboxScan>, Option<String>)> {
+        let mut scans = Vec::new();
+        let mut selected = None;
+        for mailbox in self.mailboxes.clone() {
+            if !self.labels.contains(&mailbox) {
+                info!("Mailbox {} does not exist (yet); skipping it", mailbox);
+                continue;
             }
-            assert!(
-                !msg.from.is_empty() || !msg.to.is_empty() || !msg.cc.is_empty(),
-                "No address fields (To/Cc/From) for UID {}",
-                uid
-            );
+            info!("Fetching all messages from {}", mailbox);
+            let (status, messages) = self.fetch_messages(&mailbox, tracking)?;
+            info!("✅ Fetched {} messages from {}", messages.len(), mailbox);
+            for message in &messages {
+                debug!("message: {:#?}", message);
+            }
+            selected = Some(mailbox.clone());
+            scans.push(MailboxScan {
+                mailbox,
+                status,
+                messages,
+            });
+        }
+        Ok((scans, selected))
+    }
 
-            out.push(msg);
+    /// SELECT `mailbox` unless it is already the selected one; UIDs in
+    /// actions are only valid against their own mailbox.
+    fn reselect(&mut self, mailbox: &str, selected: &mut Option<String>) -> Result<()> {
+        if selected.as_deref() != Some(mailbox) {
+            debug!("Re-selecting {} to apply actions", mailbox);
+            self.client.select_mailbox(mailbox)?;
+            *selected = Some(mailbox.to_string());
         }
+        Ok(())
+    }
 
-        debug!("Successfully fetched {} messages", out.len());
-        Ok(out)
+    /// Run both phases against the mailbox, applying every action.
+    /// Returns the plan of what was applied.
+    pub fn execute(&mut self) -> Result<Plan> {
+        self.run(false)
     }
 
-    pub fn execute(&mut self) -> Result<()> {
-        debug!("Entering IMAPFilter.execute");
+    /// Run both phases without touching the mailbox (dry run).
+    /// Returns the plan of what `execute` would apply.
+    pub fn plan(&mut self) -> Result<Plan> {
+        self.run(true)
+    }
 
-        info!("Fetching all messages from INBOX");
-        let mut messages = self.fetch_messages()?;
-        info!("✅ Fetched {} messages", messages.len());
-        for message in &messages {
-            debug!("message: {:#?}", message);
+    fn run(&mut self, dry_run: bool) -> Result<Plan> {
+        debug!("Entering IMAPFilter.run (dry_run={})", dry_run);
+
+        // With sync state, ask for change tracking; ENABLE must precede SELECT
+        let tracking = if self.sync_state.is_some() {
+            self.client.enable_change_tracking()?
+        } else {
+            ChangeTracking::Unavailable
+        };
+
+        self.check_labels()?;
+        let (scans, mut selected) = self.fetch_all(tracking)?;
+        let mut plan = Plan::new(dry_run);
+        for scan in scans {
+            self.reselect(&scan.mailbox, &mut selected)?;
+            self.process_mailbox(scan, &mut plan)?;
         }
+        plan.created_labels = self.labels.take_created();
 
-        // Create thread processor (builds thread map using Gmail X-GM-THRID or standard headers)
-        let thread_processor = ThreadProcessor::new(&messages);
-        self.process_message_filters_with_threads(&mut messages, &thread_processor)?;
-        self.process_state_filters_with_threads(&mut messages, &thread_processor)?;
+        if let (Some(state), false) = (self.sync_state.as_ref(), dry_run) {
+            state.save()?;
+        }
 
-        debug!("Finished all filters; {} messages untouched", messages.len());
         info!("Logging out from IMAP");
         self.client.logout()?;
-        info!("✅ IMAP Filter execution completed");
+        if dry_run {
+            info!("✅ Dry run completed; {} actions planned", plan.len());
+        } else {
+            info!("✅ IMAP Filter execution completed");
+        }
+        Ok(plan)
+    }
+
+    /// Both phases against one fetched mailbox, which must be selected.
+    fn process_mailbox(&mut self, scan: MailboxScan, plan: &mut Plan) -> Result<()> {
+        let MailboxScan {
+            mailbox,
+            status,
+            messages,
+        } = scan;
+        let present: Vec<u32> = messages.iter().map(|m| m.uid).collect();
+
+        // Create thread processor (builds thread map using Gmail X-GM-THRID or standard headers).
+        // State filters see every message; message filters only the new ones,
+        // so their thread grouping is limited to the new messages too.
+        let thread_processor = ThreadProcessor::new(&messages);
+        let (mut new_messages, mut pending) = self.partition_new(&mailbox, &status, &messages);
+        let new_uids: Vec<u32> = new_messages.iter().map(|m| m.uid).collect();
+        let new_threads = ThreadProcessor::new(new_messages.iter().copied());
+
+        self.process_message_filters_with_threads(&mailbox, &mut new_messages, &messages, &new_threads, plan)?;
+        pending.append(&mut new_messages);
+        pending.sort_by_key(|m| m.uid);
+        self.process_state_filters_with_threads(&mailbox, &mut pending, &messages, &thread_processor, plan)?;
+
+        // Messages whose message-filter actions failed stay new, so the next
+        // run retries them
+        let failed: HashSet<u32> = plan
+            .failures()
+            .iter()
+            .filter(|a| a.phase == Phase::MessageFilter && a.mailbox == mailbox)
+            .map(|a| a.uid)
+            .collect();
+        let processed: Vec<u32> = new_uids.into_iter().filter(|uid| !failed.contains(uid)).collect();
+        if let (Some(state), false) = (self.sync_state.as_mut(), plan.dry_run) {
+            state
+                .mailbox_mut(&mailbox)
+                .record(&processed, &present, status.uid_next);
+        }
+
+        debug!(
+            "Finished all filters on {}; {} messages untouched",
+            mailbox,
+            pending.len()
+        );
         Ok(())
     }
 
-    fn process_message_filters_with_threads(
+    /// Run only the message-filter phase, against the given INBOX UIDs.
+    /// Used by daemon mode when new mail arrives. Threads are grouped among
+    /// the given messages only; older messages of the same conversation are
+    /// left alone. Does not log out.
+    pub fn filter_new_messages(&mut self, uids: &[u32], dry_run: bool) -> Result<Plan> {
+        let mut plan = Plan::new(dry_run);
+        if uids.is_empty() {
+            return Ok(plan);
+        }
+
+        self.check_labels()?;
+        self.client.select_mailbox("INBOX")?;
+        let messages = self.fetch_compact(uids)?;
+        info!("✅ Fetched {} new messages", messages.len());
+
+        let thread_processor = ThreadProcessor::new(&messages);
+        let mut pending: Vec<&Message> = messages.iter().collect();
+        self.process_message_filters_with_threads("INBOX", &mut pending, &messages, &thread_processor, &mut plan)?;
+        plan.created_labels = self.labels.take_created();
+        Ok(plan)
+    }
+
+    /// Run only the state-filter phase against every configured mailbox.
+    /// Used by daemon mode for its periodic sweep. Does not log out, and
+    /// leaves the last configured mailbox selected.
+    pub fn sweep_state_filters(&mut self, dry_run: bool) -> Result<Plan> {
+        self.check_labels()?;
+        let (scans, mut selected) = self.fetch_all(ChangeTracking::Unavailable)?;
+        let mut plan = Plan::new(dry_run);
+        for MailboxScan { mailbox, messages, .. } in scans {
+            self.reselect(&mailbox, &mut selected)?;
+            let thread_processor = ThreadProcessor::new(&messages);
+            let mut pending: Vec<&Message> = messages.iter().collect();
+            self.process_state_filters_with_threads(&mailbox, &mut pending, &messages, &thread_processor, &mut plan)?;
+        }
+        plan.created_labels = self.labels.take_created();
+        Ok(plan)
+    }
+
+    fn process_message_filters_with_threads<'a>(
         &mut self,
-        messages: &mut Vec<Message>,
+        mailbox: &str,
+        messages: &mut Vec<&'a Message>,
+        all: &'a [Message],
         thread_processor: &ThreadProcessor,
+        plan: &mut Plan,
     ) -> Result<()> {
-        info!("→ Phase 1: applying {} MessageFilters", self.message_filters.len());
+        info!(
+            "→ Phase 1: applying {} MessageFilters to {}",
+            self.message_filters.len(),
+            mailbox
+        );
 
+        // One reference time for the whole pass, so `age` is consistent
+        let now = self.clock.now();
+        let bodies = self.fetch_bodies(mailbox, messages, now)?;
+        let mut batch = ActionBatch::new(plan.dry_run);
         let mut i = 0;
         while i < messages.len() {
-            let msg = &messages[i];
-
-            let matched = self.message_filters.iter().find_map(|message_filter| {
-                if message_filter.matches(msg) {
-                    message_filter
-                        .actions
-                        .first()
-                        .map(|action| (message_filter.clone(), action.clone()))
-                } else {
-                    None
+            let msg = messages[i];
+
+            let body = bodies.get(&msg.uid).map(String::as_str);
+            // Still undecided only if the server returned no body; no match
+            let matched = matching_filters(&self.message_filters, mailbox, msg, body, now).unwrap_or_default();
+
+            if !matched.is_empty() {
+                // Process entire thread; every action of every matched filter
+                // in order. An action an earlier filter already took is not
+                // repeated.
+                let thread_id = thread_processor.get_thread_id(msg);
+                let processed = thread_processor.thread_members(msg, all);
+                let mut taken: Vec<PlannedOp> = Vec::new();
+                for matched_filter in matched {
+                    info!(
+                        "Filter '{}' matched UID {}; applying actions {:?}",
+                        matched_filter.name, msg.uid, matched_filter.actions
+                    );
+                    batch.start_chain(matched_filter.on_error);
+                    for action in &matched_filter.actions {
+                        let op = PlannedOp::from(action);
+                        if taken.contains(&op) {
+                            debug!("  → {} already applied by an earlier filter", op);
+                            continue;
+                        }
+                        for thread_msg in &processed {
+                            let planned = PlannedAction::new(
+                                mailbox,
+                                thread_msg,
+                                Phase::MessageFilter,
+                                &matched_filter.name,
+                                op.clone(),
+                                thread_id.clone(),
+                                msg.uid,
+                            );
+                            batch.push(
+                                plan,
+                                planned,
+                                thread_msg,
+                                taken.len(),
+                                &matched_filter.condition.labels.included,
+                            );
+                        }
+                        taken.push(op);
+                    }
                 }
-            });
-
-            if let Some((matched_filter, action)) = matched {
-                info!(
-                    "Filter '{}' matched UID {}; applying action {:?}",
-                    matched_filter.name, msg.uid, action
-                );
-
-                // Process entire thread
-                let processed = thread_processor.process_thread_message_filter(&mut self.client, msg, &action)?;
 
                 // Remove all processed messages from the list
                 messages.retain(|m| !processed.iter().any(|p| p.uid == m.uid));
@@ -217,21 +753,29 @@ impl<C: ImapConnection> IMAPFilter<C> {
             }
         }
 
-        Ok(())
+        batch.apply(&mut self.client, &mut self.labels, mailbox, plan)
     }
 
-    fn process_state_filters_with_threads(
+    fn process_state_filters_with_threads<'a>(
         &mut self,
-        messages: &mut Vec<Message>,
+        mailbox: &str,
+        messages: &mut Vec<&'a Message>,
+        all: &'a [Message],
         thread_processor: &ThreadProcessor,
+        plan: &mut Plan,
     ) -> Result<()> {
-        info!("→ Phase 2: applying {} StateFilters", self.state_filters.len());
+        info!(
+            "→ Phase 2: applying {} StateFilters to {}",
+            self.state_filters.len(),
+            mailbox
+        );
         let total_messages = messages.len();
         let mut processed_count = 0;
         let mut kept_count = 0;
         let mut expired_count = 0;
         let mut no_match_count = 0;
 
+        let mut batch = ActionBatch::new(plan.dry_run);
         let mut i = 0;
         while i < messages.len() {
             processed_count += 1;
@@ -242,15 +786,19 @@ impl<C: ImapConnection> IMAPFilter<C> {
                 );
             }
 
-            let msg = &messages[i];
+            let msg = messages[i];
             debug!(
                 "  Checking UID {} subject='{}' labels={:?}",
                 msg.uid,
-                &msg.subject[..msg.subject.len().min(50)],
+                msg.subject.chars().take(50).collect::<String>(),
                 msg.labels
             );
 
-            if let Some(state_filter) = self.state_filters.iter().find(|sf| sf.matches(msg)) {
+            if let Some(state_filter) = self.state_filters.iter().find(|sf| {
+                sf.applies_to(mailbox)
+                    && sf.matches(msg)
+                    && sf.matches_thread_size(|| thread_processor.thread_size(msg, all))
+            }) {
                 debug!("  → Matched filter '{}'", state_filter.name);
 
                 if let Ttl::Keep = state_filter.ttl {
@@ -263,15 +811,24 @@ impl<C: ImapConnection> IMAPFilter<C> {
                     continue;
                 }
 
-                debug!("  → Calling process_thread_state_filter for UID {}", msg.uid);
+                debug!("  → Evaluating thread TTL for UID {}", msg.uid);
 
                 // Process entire thread for TTL
-                let processed = thread_processor.process_thread_state_filter(
-                    &mut self.client,
-                    msg,
-                    state_filter,
-                    &state_filter.action,
-                )?;
+                let thread_id = thread_processor.get_thread_id(msg);
+                let processed = thread_processor.expired_thread_members(msg, all, state_filter, &self.clock);
+                batch.start_chain(OnError::Abort);
+                for thread_msg in &processed {
+                    let planned = PlannedAction::new(
+                        mailbox,
+                        thread_msg,
+                        Phase::StateFilter,
+                        &state_filter.name,
+                        PlannedOp::from(&state_filter.action),
+                        thread_id.clone(),
+                        msg.uid,
+                    );
+                    batch.push(plan, planned, thread_msg, 0, &state_filter.labels);
+                }
 
                 if !processed.is_empty() {
                     expired_count += processed.len();
@@ -304,6 +861,6 @@ impl<C: ImapConnection> IMAPFilter<C> {
             "  [Phase 2 complete] Total processed: {}, kept: {}, expired: {}, no_match: {}",
             processed_count, kept_count, expired_count, no_match_count
         );
-        Ok(())
+        batch.apply(&mut self.client, &mut self.labels, mailbox, plan)
     }
 }
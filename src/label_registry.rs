@@ -0,0 +1,152 @@
+// src/label_registry.rs
+//
+// Session-scoped cache of the account's mailboxes/labels. Listed once with
+// LIST on first use and kept current as labels are created, so actions do
+// not LIST before every move. Also resolves RFC 6154 special-use names
+// (`\Trash`, `\Junk`, `\All`, ...) to the mailbox that carries them.
+
+use eyre::{eyre, Result};
+use log::{debug, info};
+
+use crate::client_ops::{IMAPClientOps, MailboxInfo, SpecialUse};
+use crate::utils::is_all_mail;
+
+#[derive(Debug, Default)]
+pub struct LabelRegistry {
+    /// `None` until the first LIST of the session.
+    mailboxes: Option<Vec<MailboxInfo>>,
+    /// Labels created (or, in a dry run, that would be created) and not yet
+    /// reported.
+    created: Vec<String>,
+}
+
+impl LabelRegistry {
+    /// A registry over an already known list of mailboxes.
+    pub fn from_mailboxes(mailboxes: Vec<MailboxInfo>) -> Self {
+        LabelRegistry {
+            mailboxes: Some(mailboxes),
+            created: Vec::new(),
+        }
+    }
+
+    /// LIST the account's mailboxes unless this session already has.
+    pub fn load<S: IMAPClientOps>(&mut self, client: &mut S) -> Result<()> {
+        if self.mailboxes.is_none() {
+            let mailboxes = client.list_mailboxes()?;
+            debug!("LIST returned {} mailboxes", mailboxes.len());
+            self.mailboxes = Some(mailboxes);
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> &[MailboxInfo] {
+        self.mailboxes.as_deref().unwrap_or_default()
+    }
+
+    /// Whether `name` exists. INBOX is case-insensitive, as in RFC 3501.
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries()
+            .iter()
+            .any(|m| m.name == name || (m.name.eq_ignore_ascii_case("INBOX") && name.eq_ignore_ascii_case("INBOX")))
+    }
+
+    /// The mailbox carrying the `special_use` attribute, if any.
+    pub fn special(&self, special_use: SpecialUse) -> Option<&str> {
+        self.entries()
+            .iter()
+            .find(|m| m.special_use == Some(special_use))
+            .map(|m| m.name.as_str())
+    }
+
+    /// Whether `mailbox` is the account's All Mail view.
+    pub fn is_all_mail(&self, mailbox: &str) -> bool {
+        is_all_mail(mailbox) || self.special(SpecialUse::All) == Some(mailbox)
+    }
+
+    /// Resolve a destination as written in the config: a special-use name
+    /// such as `\Trash` becomes the mailbox that carries it; anything else
+    /// is returned unchanged.
+    pub fn resolve(&self, destination: &str) -> Result<String> {
+        if !destination.starts_with('\\') {
+            return Ok(destination.to_string());
+        }
+        match SpecialUse::parse(destination) {
+            Some(special_use) => self
+                .special(special_use)
+                .map(str::to_string)
+                .ok_or_else(|| eyre!("No mailbox on the server has special use {}", destination)),
+            // Gmail system labels such as \Starred and \Inbox
+            None => Ok(destination.to_string()),
+        }
+    }
+
+    /// Resolve `destination` and create it if it does not exist yet. In a
+    /// dry run the label is only recorded as one that would be created.
+    /// Returns the resolved name.
+    pub fn ensure<S: IMAPClientOps>(&mut self, client: &mut S, destination: &str, dry_run: bool) -> Result<String> {
+        self.load(client)?;
+        let name = self.resolve(destination)?;
+        if name.starts_with('\\') || self.contains(&name) {
+            return Ok(name);
+        }
+
+        if dry_run {
+            info!("[dry-run] Would create missing label '{}'", name);
+        } else {
+            info!("Creating missing label '{}'", name);
+            client.create_mailbox(&name)?;
+        }
+        self.mailboxes.get_or_insert_with(Vec::new).push(MailboxInfo {
+            name: name.clone(),
+            special_use: None,
+        });
+        self.created.push(name.clone());
+        Ok(name)
+    }
+
+    /// Labels created since the last call, oldest first.
+    pub fn take_created(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> LabelRegistry {
+        LabelRegistry::from_mailboxes(vec![
+            MailboxInfo {
+                name: "INBOX".to_string(),
+                special_use: None,
+            },
+            MailboxInfo {
+                name: "[Gmail]/Trash".to_string(),
+                special_use: Some(SpecialUse::Trash),
+            },
+            MailboxInfo {
+                name: "[Gmail]/Alle Nachrichten".to_string(),
+                special_use: Some(SpecialUse::All),
+            },
+        ])
+    }
+
+    #[test]
+    fn test_resolve_special_use() {
+        let labels = registry();
+        assert_eq!(labels.resolve("\\Trash").unwrap(), "[Gmail]/Trash");
+        assert_eq!(labels.resolve("Purgatory").unwrap(), "Purgatory");
+        assert_eq!(labels.resolve("\\Starred").unwrap(), "\\Starred");
+        assert!(labels.resolve("\\Junk").is_err());
+    }
+
+    #[test]
+    fn test_contains_and_all_mail() {
+        let labels = registry();
+        assert!(labels.contains("inbox"));
+        assert!(!labels.contains("Purgatory"));
+        assert!(labels.is_all_mail("[Gmail]/Alle Nachrichten"));
+        assert!(labels.is_all_mail("[Gmail]/All Mail"));
+        assert!(!labels.is_all_mail("INBOX"));
+    }
+}
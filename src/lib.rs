@@ -1,12 +1,21 @@
 // src/lib.rs
 //
 // Library entry point for imap-filter.
-// Re-exports modules needed by integration tests.
+// Exposes the filter engine so it can be driven by the binary or by integration tests.
 
+pub mod body;
 pub mod cfg;
 pub mod client_ops;
+pub mod daemon;
+pub mod imap_filter;
+pub mod label_registry;
 pub mod message;
+pub mod plan;
+pub mod report;
+pub mod sync_state;
+pub mod thread;
 pub mod utils;
 
-// Re-export Clock trait for easy access
-pub use client_ops::{Clock, RealClock};
+// Re-export the client/clock traits for easy access
+pub use client_ops::{Clock, IMAPClientOps, MailboxStatus, RealClock};
+pub use imap_filter::IMAPFilter;
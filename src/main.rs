@@ -3,24 +3,41 @@
 use clap::Parser;
 use env_logger::Builder;
 use eyre::{eyre, Result};
-use log::{debug, error, info};
+use imap::{Connection, Session};
+use log::{debug, error, info, warn};
+use secure_string::SecureString;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
 
-mod cfg;
 mod cli;
-mod client_ops;
-mod imap_filter;
-mod message;
 mod oauth2;
-mod thread;
-mod utils;
 
-use cfg::config::load_config;
-use cli::Cli;
-use imap_filter::IMAPFilter;
+use cli::{Cli, Command};
+use imap_filter::cfg::config::{load_config, Account, Config};
+use imap_filter::daemon::{run_daemon, DaemonOptions};
+use imap_filter::plan::Plan;
+use imap_filter::report::RunReport;
+use imap_filter::sync_state::SyncState;
+use imap_filter::{IMAPFilter, RealClock};
 use oauth2::{OAuth2Credentials, XOAuth2Authenticator};
 
+/// How each new session authenticates.
+enum Auth {
+    OAuth2(OAuth2Credentials),
+    Password(SecureString),
+}
+
+/// Where and as whom one account connects.
+struct Endpoint {
+    imap_domain: String,
+    imap_username: String,
+    auth: Auth,
+    debug: bool,
+}
+
 fn setup_logging() {
     let log_file = "imap-filter.log";
     let file = OpenOptions::new()
@@ -43,79 +60,272 @@ fn setup_logging() {
         .init();
 }
 
-fn main() -> Result<()> {
-    setup_logging();
-    info!("========== Starting IMAP Filter ==========");
-
-    let cli = Cli::parse();
-    //debug!("CLI args: {:?}", cli);
+/// Let CLI/env connection parameters override the config file. Only applied
+/// when there is a single account; with several they would be ambiguous.
+fn apply_cli_overrides(cli: &Cli, config: &mut Config) {
+    if let Some(v) = &cli.imap_domain {
+        config.imap_domain = Some(v.clone());
+    }
+    if let Some(v) = &cli.imap_username {
+        config.imap_username = Some(v.clone());
+    }
+    if let Some(v) = &cli.imap_password {
+        config.imap_password = Some(v.clone());
+    }
+    if let Some(v) = &cli.oauth2_client_id {
+        config.oauth2_client_id = Some(v.clone());
+    }
+    if let Some(v) = &cli.oauth2_client_secret {
+        config.oauth2_client_secret = Some(v.clone());
+    }
+    if let Some(v) = &cli.oauth2_refresh_token {
+        config.oauth2_refresh_token = Some(v.clone());
+    }
+}
 
-    // 1) Load YAML config
-    let config = load_config(&cli.config)?;
+fn has_cli_overrides(cli: &Cli) -> bool {
+    cli.imap_domain.is_some()
+        || cli.imap_username.is_some()
+        || cli.imap_password.is_some()
+        || cli.oauth2_client_id.is_some()
+        || cli.oauth2_client_secret.is_some()
+        || cli.oauth2_refresh_token.is_some()
+}
 
-    // 2) Resolve connection parameters, preferring CLI/env over config file
-    let imap_domain = cli.imap_domain.or(config.imap_domain.clone()).ok_or_else(|| {
+/// Resolve connection parameters and credentials for one account.
+fn resolve_endpoint(config: &Config, debug: bool) -> Result<Endpoint> {
+    let imap_domain = config.imap_domain.clone().ok_or_else(|| {
         error!("IMAP domain is required but missing.");
         eyre!("IMAP domain is required")
     })?;
 
-    let imap_username = cli.imap_username.or(config.imap_username.clone()).ok_or_else(|| {
+    let imap_username = config.imap_username.clone().ok_or_else(|| {
         error!("IMAP username is required but missing.");
         eyre!("IMAP username is required")
     })?;
 
-    // Resolve OAuth2 credentials (CLI/env takes precedence over config)
-    let oauth2_client_id = cli.oauth2_client_id.or(config.oauth2_client_id.clone());
-    let oauth2_client_secret = cli.oauth2_client_secret.or(config.oauth2_client_secret.clone());
-    let oauth2_refresh_token = cli.oauth2_refresh_token.or(config.oauth2_refresh_token.clone());
+    debug!("Using IMAP server: {}  user: {}", imap_domain, imap_username);
 
-    // Check if we have OAuth2 credentials
-    let use_oauth2 = oauth2_client_id.is_some() && oauth2_client_secret.is_some() && oauth2_refresh_token.is_some();
+    let auth = match (
+        &config.oauth2_client_id,
+        &config.oauth2_client_secret,
+        &config.oauth2_refresh_token,
+    ) {
+        (Some(client_id), Some(client_secret), Some(refresh_token)) => {
+            info!("Using OAuth2 authentication");
+            Auth::OAuth2(OAuth2Credentials {
+                client_id: client_id.unsecure().to_string(),
+                client_secret: client_secret.unsecure().to_string(),
+                refresh_token: refresh_token.unsecure().to_string(),
+            })
+        }
+        _ => {
+            info!("Using password authentication");
+            Auth::Password(config.imap_password.clone().ok_or_else(|| {
+                error!("IMAP password is required but missing (no OAuth2 credentials provided either).");
+                eyre!("IMAP password or OAuth2 credentials required")
+            })?)
+        }
+    };
 
-    debug!("Using IMAP server: {}  user: {}", imap_domain, imap_username);
+    Ok(Endpoint {
+        imap_domain,
+        imap_username,
+        auth,
+        debug,
+    })
+}
 
-    // 3) Connect & authenticate (v3 API uses ClientBuilder)
-    let client_conn = imap::ClientBuilder::new(&imap_domain, 993)
+/// Connect & authenticate (v3 API uses ClientBuilder); the daemon reconnects
+/// through the same function.
+fn connect(endpoint: &Endpoint) -> Result<Session<Connection>> {
+    let client_conn = imap::ClientBuilder::new(&endpoint.imap_domain, 993)
         .connect()
-        .map_err(|e| eyre!("Failed to connect to {}: {}", imap_domain, e))?;
-
-    let mut client = if use_oauth2 {
-        // OAuth2 authentication
-        info!("Using OAuth2 authentication");
-        let creds = OAuth2Credentials {
-            client_id: oauth2_client_id.unwrap().unsecure().to_string(),
-            client_secret: oauth2_client_secret.unwrap().unsecure().to_string(),
-            refresh_token: oauth2_refresh_token.unwrap().unsecure().to_string(),
-        };
+        .map_err(|e| eyre!("Failed to connect to {}: {}", endpoint.imap_domain, e))?;
+
+    let mut client = match &endpoint.auth {
+        Auth::OAuth2(creds) => {
+            let access_token = creds.refresh_access_token()?;
+            let authenticator = XOAuth2Authenticator::new(&endpoint.imap_username, &access_token);
+
+            client_conn
+                .authenticate("XOAUTH2", &authenticator)
+                .map_err(|(e, _)| eyre!("OAuth2 IMAP authentication failed: {}", e))?
+        }
+        Auth::Password(password) => client_conn
+            .login(&endpoint.imap_username, password.unsecure())
+            .map_err(|(e, _)| eyre!("IMAP login failed: {}", e))?,
+    };
+
+    info!("✅ Connected and logged in as {}", endpoint.imap_username);
+    client.debug = endpoint.debug;
+    debug!("Low‐level IMAP protocol debug enabled on client");
+    Ok(client)
+}
 
-        let access_token = creds.refresh_access_token()?;
-        let authenticator = XOAuth2Authenticator::new(&imap_username, &access_token);
+/// One-shot run of a single account.
+fn run_account(cli: &Cli, account: &Account) -> Result<Plan> {
+    info!("Processing account '{}'", account.name);
+    let endpoint = resolve_endpoint(&account.config, cli.debug)?;
 
-        client_conn
-            .authenticate("XOAUTH2", &authenticator)
-            .map_err(|(e, _)| eyre!("OAuth2 IMAP authentication failed: {}", e))?
+    let mut filter = IMAPFilter::new(connect(&endpoint)?, account.config.clone());
+    if let Some(path) = &cli.state_file {
+        let key = format!("{}/{}", endpoint.imap_domain, endpoint.imap_username);
+        filter = filter.with_sync_state(SyncState::load(path, &key)?);
+    }
+    if cli.dry_run {
+        filter.plan()
     } else {
-        // Password authentication
-        info!("Using password authentication");
-        let imap_password = cli.imap_password.or(config.imap_password.clone()).ok_or_else(|| {
-            error!("IMAP password is required but missing (no OAuth2 credentials provided either).");
-            eyre!("IMAP password or OAuth2 credentials required")
-        })?;
-
-        client_conn
-            .login(&imap_username, imap_password.unsecure())
-            .map_err(|(e, _)| eyre!("IMAP login failed: {}", e))?
+        filter.execute()
+    }
+}
+
+/// Daemon for a single account; runs until shutdown or a fatal error.
+fn run_account_daemon(cli: &Cli, account: &Account, shutdown: &AtomicBool) -> Result<()> {
+    let endpoint = resolve_endpoint(&account.config, cli.debug)?;
+    let options = DaemonOptions {
+        sweep_interval: cli.sweep_interval,
+        dry_run: cli.dry_run,
+        ..DaemonOptions::default()
     };
+    run_daemon(
+        || connect(&endpoint),
+        account.config.clone(),
+        RealClock,
+        &options,
+        shutdown,
+    )
+}
 
-    info!("✅ Connected and logged in");
+/// A flag set by the first SIGINT/SIGTERM, so the daemon logs out after its
+/// current IDLE; a second signal exits at once.
+fn shutdown_on_signal() -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        // The exit hook must be registered first: it checks the flag the
+        // second hook sets
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))
+            .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&shutdown)))
+            .map_err(|e| eyre!("Failed to install signal handler: {}", e))?;
+    }
+    Ok(shutdown)
+}
 
-    client.debug = cli.debug;
-    debug!("Low‐level IMAP protocol debug enabled on client");
+/// `check`: load and validate the config, then summarize each account.
+fn check_config(cli: &Cli) -> Result<()> {
+    let accounts = load_config(&cli.config)?.into_accounts()?;
+    for account in &accounts {
+        let config = &account.config;
+        println!(
+            "{}: {} message filter(s), {} state filter(s); mailboxes: {}",
+            account.name,
+            config.message_filters.len(),
+            config.state_filters.len(),
+            config.mailboxes.join(", ")
+        );
+    }
+    println!("✅ {} is valid", cli.config.display());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    setup_logging();
+    info!("========== Starting IMAP Filter ==========");
+
+    let cli = Cli::parse();
+    //debug!("CLI args: {:?}", cli);
+
+    if let Some(Command::Check) = cli.command {
+        return check_config(&cli);
+    }
+
+    // 1) Load YAML config and split it into accounts
+    let mut accounts = load_config(&cli.config)?.into_accounts()?;
+
+    // 2) CLI/env connection parameters take precedence for a single account
+    if accounts.len() == 1 {
+        apply_cli_overrides(&cli, &mut accounts[0].config);
+    } else if has_cli_overrides(&cli) {
+        warn!("Ignoring CLI/env connection parameters: config defines multiple accounts");
+    }
+
+    if cli.daemon {
+        info!("Starting daemon mode; state sweep every {:?}", cli.sweep_interval);
+        let shutdown = shutdown_on_signal()?;
+        if let [account] = accounts.as_slice() {
+            return run_account_daemon(&cli, account, &shutdown);
+        }
+
+        // One daemon per account; a failing account does not stop the others
+        let failed: Vec<&str> = thread::scope(|scope| {
+            let handles: Vec<_> = accounts
+                .iter()
+                .map(|account| (account, scope.spawn(|| run_account_daemon(&cli, account, &shutdown))))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|(account, handle)| match handle.join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => {
+                        error!("❌ Daemon for account '{}' stopped: {:?}", account.name, e);
+                        Some(account.name.as_str())
+                    }
+                    Err(_) => {
+                        error!("❌ Daemon for account '{}' panicked", account.name);
+                        Some(account.name.as_str())
+                    }
+                })
+                .collect()
+        });
+        return if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("Daemon failed for account(s): {}", failed.join(", ")))
+        };
+    }
+
+    // 3) Run the filter for each account
+    if let [account] = accounts.as_slice() {
+        let plan = run_account(&cli, account)?;
+        let failures = plan.failures().len();
+        if cli.dry_run || failures > 0 {
+            println!("{}", plan);
+        }
+        if let Some(path) = &cli.plan_json {
+            plan.write_json(path)?;
+            info!("Wrote plan with {} actions to {}", plan.len(), path.display());
+        }
+        if failures > 0 {
+            return Err(eyre!("{} action(s) failed", failures));
+        }
+        info!("✅ IMAP Filter execution completed");
+        return Ok(());
+    }
 
-    // 4) Run the filter — pass the entire `config` along with the logged‐in client
-    let mut filter = IMAPFilter::new(client, config);
-    filter.execute()?;
+    let report = RunReport::collect(&accounts, |account| run_account(&cli, account));
+    println!("{}", report);
+    if let Some(path) = &cli.plan_json {
+        report.write_json(path)?;
+        info!(
+            "Wrote report for {} accounts to {}",
+            report.accounts.len(),
+            path.display()
+        );
+    }
 
+    let failed = report.failed();
+    if !failed.is_empty() {
+        return Err(eyre!("Failed account(s): {}", failed.join(", ")));
+    }
+    let failures: usize = report
+        .accounts
+        .iter()
+        .filter_map(|a| a.plan.as_ref())
+        .map(|p| p.failures().len())
+        .sum();
+    if failures > 0 {
+        return Err(eyre!("{} action(s) failed", failures));
+    }
     info!("✅ IMAP Filter execution completed");
     Ok(())
 }
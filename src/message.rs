@@ -1,32 +1,197 @@
 // src/message.rs
 
-use mailparse::{addrparse, MailAddr};
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use log::debug;
+use mailparse::{addrparse_header, parse_headers, MailAddr, MailHeader};
+use serde::{Deserialize, Serialize};
 
 use crate::cfg::label::Label;
 
+/// Headers the engine itself reads: addresses, subject, date and threading.
+/// `retain_headers` always keeps these.
+pub const CORE_HEADERS: &[&str] = &[
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Bcc",
+    "Delivered-To",
+    "Return-Path",
+    "List-Post",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+];
+
+/// Whether `retain_headers(extra)` keeps a header called `name`.
+pub fn is_kept_header(name: &str, extra: &[String]) -> bool {
+    CORE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) || extra.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
 #[derive(Debug, Clone)]
 pub struct EmailAddress {
     pub name: String,
     pub email: String,
 }
 
+impl EmailAddress {
+    /// Whether the display name holds an email address other than `email`,
+    /// as in `"ceo@example.com" <someone@elsewhere.example>`.
+    pub fn name_spoofs_address(&self) -> bool {
+        self.name
+            .split(|c: char| c.is_whitespace() || "<>()[]\"',;:".contains(c))
+            .filter(|token| {
+                token
+                    .split_once('@')
+                    .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'))
+            })
+            .any(|addr| !addr.eq_ignore_ascii_case(&self.email))
+    }
+}
+
+/// One leaf of a message's MIME structure, as BODYSTRUCTURE reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MimePart {
+    /// Lowercase `type/subtype`.
+    pub mime_type: String,
+    pub filename: Option<String>,
+    /// Encoded size in octets (base64 parts are about a third larger than
+    /// the file).
+    pub size: u64,
+    /// Disposition `attachment`, or a named part with no disposition.
+    pub attachment: bool,
+}
+
+/// One header field. `value` is unfolded with RFC 2047 encoded words
+/// decoded; `raw` keeps the value as received when that differs, since
+/// address parsing needs the encoded form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderField {
+    pub name: String,
+    pub value: String,
+    raw: Option<String>,
+}
+
+impl HeaderField {
+    /// The value as received, folding and encoded words intact.
+    pub fn raw(&self) -> &str {
+        self.raw.as_deref().unwrap_or(&self.value)
+    }
+}
+
+/// A message's header fields in order. Names compare case-insensitively and
+/// a repeated field (`Received`, `List-*`) keeps every occurrence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<HeaderField>);
+
+impl Headers {
+    fn from_parsed(parsed: &[MailHeader<'_>]) -> Self {
+        Headers(
+            parsed
+                .iter()
+                .map(|h| {
+                    let value = h.get_value();
+                    let raw = String::from_utf8_lossy(h.get_value_raw());
+                    HeaderField {
+                        name: h.get_key(),
+                        raw: (raw != value).then(|| raw.into_owned()),
+                        value,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+            .map(|f| f.value.as_str())
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |f| f.name.eq_ignore_ascii_case(name))
+            .map(|f| f.value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Append a field; `value` is taken as already decoded.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.0.push(HeaderField {
+            name: name.to_string(),
+            value: value.to_string(),
+            raw: None,
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &HeaderField> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Which of a message's timestamps to read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateSource {
+    /// INTERNALDATE, set by the server on delivery.
+    #[default]
+    Internal,
+    /// The `Date:` header, set by the sender.
+    Header,
+}
+
 #[derive(Debug, Clone)]
 pub struct Message {
     pub uid: u32,
     pub seq: u32,
     pub to: Vec<EmailAddress>,
     pub cc: Vec<EmailAddress>,
+    pub bcc: Vec<EmailAddress>,
     pub from: Vec<EmailAddress>,
+    pub sender: Vec<EmailAddress>,
+    pub reply_to: Vec<EmailAddress>,
+    /// Where the server delivered the message; not folded into `to`.
+    pub delivered_to: Vec<EmailAddress>,
+    pub return_path: Vec<EmailAddress>,
+    /// The `mailto:` addresses of `List-Post`.
+    pub list_post: Vec<EmailAddress>,
     pub subject: String,
-    pub date: String,
+    /// INTERNALDATE; `None` if the server sent none.
+    pub internal_date: Option<DateTime<Utc>>,
+    /// The `Date:` header; `None` if absent or unparsable.
+    pub header_date: Option<DateTime<Utc>>,
     pub labels: Vec<Label>,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     // Thread-related fields for standard IMAP thread grouping
     pub message_id: Option<String>,
     pub in_reply_to: Option<String>,
     pub references: Vec<String>,
     pub thread_id: Option<String>, // Gmail X-GM-THRID
+    /// MIME structure; empty unless BODYSTRUCTURE was fetched.
+    pub parts: Vec<MimePart>,
+    /// Whole-message size in octets (RFC822.SIZE); 0 if not fetched.
+    pub size: u64,
+    /// IMAP flags: system flags in canonical form (`\Seen`, `\Answered`,
+    /// `\Flagged`, `\Draft`, `\Deleted`) and keywords as given.
+    pub flags: Vec<String>,
 }
 
 impl Message {
@@ -36,56 +201,115 @@ impl Message {
         seq: u32,
         raw_headers: Vec<u8>,
         raw_labels: Vec<String>,
-        internal_date: String,
+        internal_date: Option<DateTime<Utc>>,
         gmail_thread_id: Option<String>,
     ) -> Self {
-        // parse headers
-        let raw_str = String::from_utf8_lossy(&raw_headers);
-        let headers: HashMap<_, _> = raw_str
-            .lines()
-            .filter_map(|line| line.split_once(": "))
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
+        // parse headers: folded lines are joined and encoded words decoded
+        let parsed = parse_headers(&raw_headers)
+            .map(|(parsed, _)| parsed)
+            .unwrap_or_else(|e| {
+                debug!("UID {}: unparsable header block: {}", uid, e);
+                Vec::new()
+            });
+        let headers = Headers::from_parsed(&parsed);
 
-        // owned parsing of address fields
-        let mut to = parse_addrs(headers.get("To"));
-        let cc = parse_addrs(headers.get("Cc"));
-        let from = parse_addrs(headers.get("From"));
-
-        // if no "To:", but we do have "Delivered-To:", treat that as the recipient
-        if to.is_empty() {
-            to = parse_addrs(headers.get("Delivered-To"));
-        }
+        // owned parsing of address fields, across every occurrence
+        let addrs = |name: &str| -> Vec<EmailAddress> {
+            parsed
+                .iter()
+                .filter(|h| h.get_key_ref().eq_ignore_ascii_case(name))
+                .flat_map(parse_addrs)
+                .collect()
+        };
+        let list_post = parsed
+            .iter()
+            .filter(|h| h.get_key_ref().eq_ignore_ascii_case("List-Post"))
+            .flat_map(|h| parse_mailto(&h.get_value()))
+            .collect();
 
         // labels and subject
+        let mut flags: Vec<String> = Vec::new();
+        for flag in raw_labels.iter().filter_map(|s| parse_flag(s)) {
+            if !flags.contains(&flag) {
+                flags.push(flag);
+            }
+        }
         let labels = raw_labels.into_iter().map(|s| Label::new(&s)).collect();
-        let subject = headers.get("Subject").cloned().unwrap_or_default();
+        let subject = headers.get("Subject").unwrap_or_default().to_string();
+        let header_date = headers
+            .get("Date")
+            .and_then(|date| DateTime::parse_from_rfc2822(date.trim()).ok())
+            .map(|date| date.with_timezone(&Utc));
 
         // Parse thread-related headers (for non-Gmail IMAP servers - Phase 2)
-        let message_id = headers.get("Message-ID").cloned();
-        let in_reply_to = headers.get("In-Reply-To").cloned();
+        let message_id = headers.get("Message-ID").map(|id| id.trim().to_string());
+        let in_reply_to = headers.get("In-Reply-To").map(|id| id.trim().to_string());
         let references = headers
-            .get("References")
-            .map(|refs| refs.split_whitespace().map(String::from).collect())
-            .unwrap_or_default();
+            .get_all("References")
+            .flat_map(str::split_whitespace)
+            .map(String::from)
+            .collect();
 
         Message {
             uid,
             seq,
-            to,
-            cc,
-            from,
+            to: addrs("To"),
+            cc: addrs("Cc"),
+            bcc: addrs("Bcc"),
+            from: addrs("From"),
+            sender: addrs("Sender"),
+            reply_to: addrs("Reply-To"),
+            delivered_to: addrs("Delivered-To"),
+            return_path: addrs("Return-Path"),
+            list_post,
             subject,
-            date: internal_date,
+            internal_date,
+            header_date,
             labels,
             headers,
             message_id,
             in_reply_to,
             references,
             thread_id: gmail_thread_id,
+            parts: Vec::new(),
+            size: 0,
+            flags,
+        }
+    }
+
+    /// Drop every header except `CORE_HEADERS` and `extra` (names compare
+    /// case-insensitively). The parsed fields are unaffected; this only
+    /// trims `headers`, which otherwise holds the whole header block.
+    pub fn retain_headers(&mut self, extra: &[String]) {
+        self.headers.0.retain(|field| is_kept_header(&field.name, extra));
+        self.headers.0.shrink_to_fit();
+    }
+
+    /// Everyone the message was addressed or delivered to: To, Cc, Bcc and
+    /// Delivered-To.
+    pub fn recipients(&self) -> impl Iterator<Item = &EmailAddress> {
+        self.to
+            .iter()
+            .chain(&self.cc)
+            .chain(&self.bcc)
+            .chain(&self.delivered_to)
+    }
+
+    /// The timestamp `source` names, or the other one when that is missing.
+    /// `None` only when the message has neither.
+    pub fn date(&self, source: DateSource) -> Option<DateTime<Utc>> {
+        match source {
+            DateSource::Internal => self.internal_date.or(self.header_date),
+            DateSource::Header => self.header_date.or(self.internal_date),
         }
     }
 
+    /// Whether `flag` (a system flag such as `\Seen`, or a keyword) is set.
+    /// Compared case-insensitively, as IMAP does.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+
     /// Get the display name of the first sender, or their email if no name
     pub fn sender_display(&self) -> String {
         self.from
@@ -103,33 +327,69 @@ impl Message {
     }
 }
 
-/// Owned parsing of an address header into `EmailAddress`
-fn parse_addrs(field: Option<&String>) -> Vec<EmailAddress> {
-    if let Some(s) = field {
-        if let Ok(addrs) = addrparse(s) {
-            let mut result = Vec::new();
-            for addr in addrs.iter() {
-                match addr {
-                    MailAddr::Single(info) => {
-                        result.push(EmailAddress {
-                            name: info.display_name.clone().unwrap_or_default(),
-                            email: info.addr.clone(),
-                        });
-                    }
-                    MailAddr::Group(group) => {
-                        for info in &group.addrs {
-                            result.push(EmailAddress {
-                                name: info.display_name.clone().unwrap_or_default(),
-                                email: info.addr.clone(),
-                            });
-                        }
-                    }
+/// The IMAP flag a raw `FLAGS`/`X-GM-LABELS` entry stands for, if any.
+/// Gmail reports starring as the `\Starred` label and `\Flagged` flag
+/// alike. Keywords have no backslash; Gmail keeps them as labels, so user
+/// labels read as keywords there too.
+fn parse_flag(raw: &str) -> Option<String> {
+    let Some(system) = raw.strip_prefix('\\') else {
+        return Some(raw.to_string());
+    };
+    let flag = match system.to_ascii_lowercase().as_str() {
+        "seen" => "\\Seen",
+        "answered" => "\\Answered",
+        "flagged" | "starred" => "\\Flagged",
+        "draft" => "\\Draft",
+        "deleted" => "\\Deleted",
+        _ => return None,
+    };
+    Some(flag.to_string())
+}
+
+/// The addresses of the `<mailto:...>` entries in a `List-Post` value; `NO`
+/// and other URLs yield none.
+fn parse_mailto(value: &str) -> Vec<EmailAddress> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|entry| entry.split_once('>').map(|(url, _)| url.trim()))
+        .filter_map(|url| {
+            let (scheme, rest) = url.split_once(':')?;
+            let email = rest.split('?').next().unwrap_or_default();
+            (scheme.eq_ignore_ascii_case("mailto") && !email.is_empty()).then(|| EmailAddress {
+                name: String::new(),
+                email: email.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Owned parsing of an address header into `EmailAddress`. Encoded words
+/// are decoded per token, so a comma inside an encoded name is kept.
+fn parse_addrs(field: &MailHeader<'_>) -> Vec<EmailAddress> {
+    let Ok(addrs) = addrparse_header(field) else {
+        return Vec::new();
+    };
+    let mut result = Vec::new();
+    for addr in addrs.iter() {
+        match addr {
+            MailAddr::Single(info) => {
+                result.push(EmailAddress {
+                    name: info.display_name.clone().unwrap_or_default(),
+                    email: info.addr.clone(),
+                });
+            }
+            MailAddr::Group(group) => {
+                for info in &group.addrs {
+                    result.push(EmailAddress {
+                        name: info.display_name.clone().unwrap_or_default(),
+                        email: info.addr.clone(),
+                    });
                 }
             }
-            return result;
         }
     }
-    Vec::new()
+    result
 }
 
 #[cfg(test)]
@@ -157,7 +417,7 @@ mod tests {
             1,
             headers,
             labels,
-            "2024-01-15T10:00:00+00:00".to_string(),
+            "2024-01-15T10:00:00+00:00".parse().ok(),
             Some("thread123".to_string()),
         );
 
@@ -177,7 +437,7 @@ mod tests {
     #[test]
     fn test_message_thread_headers() {
         let headers = make_test_headers();
-        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".to_string(), None);
+        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".parse().ok(), None);
 
         assert_eq!(msg.message_id, Some("<123@example.com>".to_string()));
         assert_eq!(msg.in_reply_to, Some("<parent@example.com>".to_string()));
@@ -187,17 +447,33 @@ mod tests {
     }
 
     #[test]
-    fn test_message_uses_delivered_to_when_no_to() {
+    fn test_delivered_to_is_kept_apart_from_to() {
         let headers = b"From: sender@example.com\r\n\
                         Delivered-To: delivered@example.com\r\n\
                         Subject: No To Header\r\n\
                         \r\n"
             .to_vec();
 
-        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".to_string(), None);
+        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".parse().ok(), None);
 
-        assert_eq!(msg.to.len(), 1);
-        assert_eq!(msg.to[0].email, "delivered@example.com");
+        assert!(msg.to.is_empty());
+        assert_eq!(msg.delivered_to[0].email, "delivered@example.com");
+        let recipients: Vec<&str> = msg.recipients().map(|a| a.email.as_str()).collect();
+        assert_eq!(recipients, vec!["delivered@example.com"]);
+    }
+
+    #[test]
+    fn test_list_post_keeps_mailto_addresses_only() {
+        let headers = b"List-Post: <https://example.org/post>, <mailto:Dev@Example.org?subject=x>\r\n\
+                        Return-Path: <>\r\n\
+                        \r\n"
+            .to_vec();
+        let msg = Message::new(1, 1, headers, vec![], None, None);
+        let list_post: Vec<&str> = msg.list_post.iter().map(|a| a.email.as_str()).collect();
+        assert_eq!(list_post, vec!["Dev@Example.org"]);
+        assert!(parse_mailto("NO").is_empty());
+        // a null reverse-path names nobody
+        assert!(msg.return_path.iter().all(|a| !a.email.is_empty()));
     }
 
     #[test]
@@ -208,7 +484,7 @@ mod tests {
             1,
             b"From: test@example.com\r\n\r\n".to_vec(),
             labels,
-            "2024-01-15T10:00:00+00:00".to_string(),
+            "2024-01-15T10:00:00+00:00".parse().ok(),
             None,
         );
 
@@ -220,10 +496,80 @@ mod tests {
     #[test]
     fn test_message_headers_stored() {
         let headers = make_test_headers();
-        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".to_string(), None);
+        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".parse().ok(), None);
 
-        assert!(msg.headers.contains_key("From"));
-        assert!(msg.headers.contains_key("Subject"));
+        assert!(msg.headers.contains("From"));
+        assert!(msg.headers.contains("Subject"));
+    }
+
+    #[test]
+    fn test_folded_encoded_and_repeated_headers() {
+        let headers = b"From: =?UTF-8?Q?M=C3=BCller=2C_Hans?= <hans@example.com>\r\n\
+                        To: ann@example.com,\r\n\
+                        \tbob@example.com,\r\n \"Cat\" <cat@example.com>\r\n\
+                        Subject: =?UTF-8?B?UmU6IFLDqXN1bcOp?= of\r\n the week\r\n\
+                        Message-Id: <1@example.com>\r\n\
+                        References: <a@example.com>\r\n\
+                        References: <b@example.com>\r\n\
+                        Received: from a\r\n\
+                        Received: from b\r\n\
+                        \r\n"
+            .to_vec();
+        let msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".parse().ok(), None);
+
+        // a comma inside an encoded display name does not split the address
+        assert_eq!(msg.from.len(), 1);
+        assert_eq!(msg.from[0].name, "Müller, Hans");
+        let to: Vec<&str> = msg.to.iter().map(|a| a.email.as_str()).collect();
+        assert_eq!(to, vec!["ann@example.com", "bob@example.com", "cat@example.com"]);
+        assert_eq!(msg.subject, "Re: Résumé of the week");
+        assert_eq!(msg.message_id.as_deref(), Some("<1@example.com>"));
+        assert_eq!(msg.references, vec!["<a@example.com>", "<b@example.com>"]);
+        let received: Vec<&str> = msg.headers.get_all("received").collect();
+        assert_eq!(received, vec!["from a", "from b"]);
+        assert_eq!(msg.headers.get("MESSAGE-ID"), Some("<1@example.com>"));
+    }
+
+    #[test]
+    fn test_dates_are_typed_and_fall_back() {
+        let headers = b"From: a@example.com\r\nDate: Sat, 1 Jun 2024 10:00:00 +0200\r\n\r\n".to_vec();
+        let msg = Message::new(1, 1, headers, vec![], "2024-06-03T09:00:00-04:00".parse().ok(), None);
+        let at = |s: &str| s.parse::<DateTime<Utc>>().ok();
+        assert_eq!(msg.internal_date, at("2024-06-03T13:00:00Z"));
+        assert_eq!(msg.header_date, at("2024-06-01T08:00:00Z"));
+        assert_eq!(msg.date(DateSource::Header), msg.header_date);
+
+        let undated = Message::new(2, 2, b"Date: someday\r\n\r\n".to_vec(), vec![], None, None);
+        assert_eq!(undated.header_date, None);
+        assert_eq!(undated.date(DateSource::Header), None);
+        let received = Message::new(
+            3,
+            3,
+            b"Date: someday\r\n\r\n".to_vec(),
+            vec![],
+            at("2024-06-03T13:00:00Z"),
+            None,
+        );
+        assert_eq!(received.date(DateSource::Header), received.internal_date);
+    }
+
+    #[test]
+    fn test_retain_headers_keeps_core_and_requested() {
+        let headers = b"From: test@example.com\r\n\
+                        Subject: Hello\r\n\
+                        Received: from mx.example.com\r\n\
+                        X-Mailer: Mutt\r\n\
+                        List-Id: <dev.example.com>\r\n\
+                        \r\n"
+            .to_vec();
+        let mut msg = Message::new(1, 1, headers, vec![], "2024-01-15T10:00:00+00:00".parse().ok(), None);
+
+        msg.retain_headers(&["list-id".to_string()]);
+
+        let mut names: Vec<&str> = msg.headers.iter().map(|f| f.name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["From", "List-Id", "Subject"]);
+        assert_eq!(msg.from[0].email, "test@example.com");
     }
 
     #[test]
@@ -233,7 +579,7 @@ mod tests {
             1,
             b"From: John Doe <john@example.com>\r\n\r\n".to_vec(),
             vec![],
-            "2024-01-15T10:00:00+00:00".to_string(),
+            "2024-01-15T10:00:00+00:00".parse().ok(),
             None,
         );
         assert_eq!(msg.sender_display(), "John Doe");
@@ -246,7 +592,7 @@ mod tests {
             1,
             b"From: john@example.com\r\n\r\n".to_vec(),
             vec![],
-            "2024-01-15T10:00:00+00:00".to_string(),
+            "2024-01-15T10:00:00+00:00".parse().ok(),
             None,
         );
         assert_eq!(msg.sender_display(), "john@example.com");
@@ -0,0 +1,329 @@
+// src/plan.rs
+//
+// Structured record of the actions a filter run applies (or, in dry-run mode,
+// would apply). Serializable to JSON so a plan can be reviewed or diffed
+// before a real run.
+
+use eyre::{eyre, Result};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::cfg::message_filter::FilterAction;
+use crate::cfg::state_filter::StateAction;
+use crate::message::Message;
+
+/// Which engine phase produced an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    MessageFilter,
+    StateFilter,
+}
+
+/// A mailbox operation, independent of which kind of filter asked for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "target")]
+pub enum PlannedOp {
+    Star,
+    Flag,
+    Move(String),
+    Delete,
+}
+
+impl From<&FilterAction> for PlannedOp {
+    fn from(action: &FilterAction) -> Self {
+        match action {
+            FilterAction::Star => PlannedOp::Star,
+            FilterAction::Flag => PlannedOp::Flag,
+            FilterAction::Move(label) => PlannedOp::Move(label.clone()),
+        }
+    }
+}
+
+impl From<&StateAction> for PlannedOp {
+    fn from(action: &StateAction) -> Self {
+        match action {
+            StateAction::Move(label) => PlannedOp::Move(label.clone()),
+            StateAction::Delete => PlannedOp::Delete,
+        }
+    }
+}
+
+impl fmt::Display for PlannedOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedOp::Star => write!(f, "Star"),
+            PlannedOp::Flag => write!(f, "Flag"),
+            PlannedOp::Move(label) => write!(f, "Move → {}", label),
+            PlannedOp::Delete => write!(f, "Delete"),
+        }
+    }
+}
+
+/// What became of an action once the engine tried to apply it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "kebab-case")]
+pub enum ActionOutcome {
+    /// Decided on but not (yet) applied; every action of a dry run.
+    Planned,
+    Applied,
+    Failed(String),
+    /// Not attempted because an earlier action of the same filter failed
+    /// for this message.
+    Skipped,
+}
+
+/// One action against one message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedAction {
+    /// Mailbox the message was fetched from; `uid` is only meaningful there.
+    pub mailbox: String,
+    pub uid: u32,
+    pub phase: Phase,
+    pub filter: String,
+    pub op: PlannedOp,
+    /// Thread the message belongs to, if it was grouped with others.
+    pub thread_id: Option<String>,
+    /// UID of the message whose match pulled this one in. Equal to `uid`
+    /// unless the message was acted on as part of a matched thread.
+    pub matched_uid: u32,
+    pub from: String,
+    pub subject: String,
+    pub outcome: ActionOutcome,
+}
+
+impl PlannedAction {
+    pub fn new(
+        mailbox: &str,
+        msg: &Message,
+        phase: Phase,
+        filter: &str,
+        op: PlannedOp,
+        thread_id: Option<String>,
+        matched_uid: u32,
+    ) -> Self {
+        PlannedAction {
+            mailbox: mailbox.to_string(),
+            uid: msg.uid,
+            phase,
+            filter: filter.to_string(),
+            op,
+            thread_id,
+            matched_uid,
+            from: msg.sender_display(),
+            subject: msg.subject.clone(),
+            outcome: ActionOutcome::Planned,
+        }
+    }
+}
+
+/// Every action of a run, in the order the engine decided on them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Plan {
+    pub dry_run: bool,
+    pub actions: Vec<PlannedAction>,
+    /// Labels the run created (or, in a dry run, would create).
+    pub created_labels: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Self {
+        Plan {
+            dry_run,
+            actions: Vec::new(),
+            created_labels: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, action: PlannedAction) {
+        self.actions.push(action);
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Actions that failed or were skipped because an earlier one failed.
+    pub fn failures(&self) -> Vec<&PlannedAction> {
+        self.actions
+            .iter()
+            .filter(|a| matches!(a.outcome, ActionOutcome::Failed(_) | ActionOutcome::Skipped))
+            .collect()
+    }
+
+    /// Pretty-printed JSON representation of the plan.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| eyre!("Failed to serialize plan: {}", e))
+    }
+
+    /// Write the plan as JSON to `path`.
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let json = self.to_json()?;
+        fs::write(path, json).map_err(|e| eyre!("Failed to write plan to {}: {}", path.display(), e))
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for a in &self.actions {
+            let via = match (&a.thread_id, a.matched_uid != a.uid) {
+                (Some(tid), true) => format!(" (thread {} via UID {})", tid, a.matched_uid),
+                _ => String::new(),
+            };
+            let location = if a.mailbox == "INBOX" {
+                String::new()
+            } else {
+                format!(" [in {}]", a.mailbox)
+            };
+            let outcome = match &a.outcome {
+                ActionOutcome::Failed(err) => format!(" FAILED: {}", err),
+                ActionOutcome::Skipped => " (skipped)".to_string(),
+                ActionOutcome::Planned | ActionOutcome::Applied => String::new(),
+            };
+            writeln!(
+                f,
+                "UID {:>8}  {:<20}  {:<24}  {} - {}{}{}{}",
+                a.uid,
+                a.filter,
+                a.op.to_string(),
+                a.from,
+                a.subject,
+                via,
+                location,
+                outcome
+            )?;
+        }
+        if !self.created_labels.is_empty() {
+            writeln!(
+                f,
+                "{} label(s): {}",
+                if self.dry_run { "Would create" } else { "Created" },
+                self.created_labels.join(", ")
+            )?;
+        }
+        write!(
+            f,
+            "{} action(s){}",
+            self.actions.len(),
+            if self.dry_run { " (dry run)" } else { "" }
+        )?;
+        match self.failures().len() {
+            0 => Ok(()),
+            n => write!(f, ", {} failed", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_message(uid: u32) -> Message {
+        Message::new(
+            uid,
+            uid,
+            b"From: Test User <test@example.com>\r\nSubject: Hello\r\n\r\n".to_vec(),
+            vec![],
+            "2024-01-15T10:00:00+00:00".parse().ok(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_planned_op_from_actions() {
+        assert_eq!(PlannedOp::from(&FilterAction::Star), PlannedOp::Star);
+        assert_eq!(
+            PlannedOp::from(&FilterAction::Move("Lists".to_string())),
+            PlannedOp::Move("Lists".to_string())
+        );
+        assert_eq!(PlannedOp::from(&StateAction::Delete), PlannedOp::Delete);
+    }
+
+    #[test]
+    fn test_plan_to_json() {
+        let mut plan = Plan::new(true);
+        plan.push(PlannedAction::new(
+            "Purgatory",
+            &make_message(7),
+            Phase::StateFilter,
+            "Cull",
+            PlannedOp::Move("Purgatory".to_string()),
+            Some("std-thread-0".to_string()),
+            3,
+        ));
+
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json().unwrap()).unwrap();
+        assert_eq!(json["dry_run"], true);
+        let action = &json["actions"][0];
+        assert_eq!(action["mailbox"], "Purgatory");
+        assert_eq!(action["uid"], 7);
+        assert_eq!(action["phase"], "state-filter");
+        assert_eq!(action["filter"], "Cull");
+        assert_eq!(action["op"]["type"], "Move");
+        assert_eq!(action["op"]["target"], "Purgatory");
+        assert_eq!(action["thread_id"], "std-thread-0");
+        assert_eq!(action["matched_uid"], 3);
+        assert_eq!(action["from"], "Test User");
+        assert_eq!(action["outcome"]["status"], "planned");
+    }
+
+    #[test]
+    fn test_plan_display_mentions_thread() {
+        let mut plan = Plan::new(true);
+        plan.push(PlannedAction::new(
+            "INBOX",
+            &make_message(7),
+            Phase::MessageFilter,
+            "star-me",
+            PlannedOp::Star,
+            Some("t1".to_string()),
+            3,
+        ));
+
+        plan.created_labels.push("Purgatory".to_string());
+
+        let text = plan.to_string();
+        assert!(text.contains("Would create label(s): Purgatory"));
+        assert!(text.contains("UID        7"));
+        assert!(text.contains("thread t1 via UID 3"));
+        assert!(text.ends_with("1 action(s) (dry run)"));
+    }
+
+    #[test]
+    fn test_plan_reports_failed_actions() {
+        let mut plan = Plan::new(false);
+        for (uid, outcome) in [
+            (1, ActionOutcome::Applied),
+            (2, ActionOutcome::Failed("STORE failed".to_string())),
+            (2, ActionOutcome::Skipped),
+        ] {
+            let mut action = PlannedAction::new(
+                "INBOX",
+                &make_message(uid),
+                Phase::MessageFilter,
+                "lists",
+                PlannedOp::Star,
+                None,
+                uid,
+            );
+            action.outcome = outcome;
+            plan.push(action);
+        }
+
+        assert_eq!(plan.failures().len(), 2);
+        let text = plan.to_string();
+        assert!(text.contains("FAILED: STORE failed"));
+        assert!(text.contains("(skipped)"));
+        assert!(text.ends_with("3 action(s), 2 failed"));
+
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json().unwrap()).unwrap();
+        assert_eq!(json["actions"][1]["outcome"]["status"], "failed");
+        assert_eq!(json["actions"][1]["outcome"]["error"], "STORE failed");
+    }
+}
@@ -0,0 +1,164 @@
+// src/report.rs
+//
+// Per-account outcome of a multi-account run. Each account is processed in
+// isolation: an error (or panic) in one is recorded against it and the
+// remaining accounts still run.
+
+use eyre::{eyre, Result};
+use log::error;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::cfg::config::Account;
+use crate::plan::Plan;
+
+/// Outcome of one account: the plan it ran, or why it failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountReport {
+    pub account: String,
+    pub plan: Option<Plan>,
+    pub error: Option<String>,
+}
+
+/// Outcome of every account of a run, in config order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunReport {
+    pub accounts: Vec<AccountReport>,
+}
+
+impl RunReport {
+    /// Run `f` for each account and collect the outcomes. Errors and panics
+    /// are recorded against the account instead of stopping the run.
+    pub fn collect<F>(accounts: &[Account], mut f: F) -> Self
+    where
+        F: FnMut(&Account) -> Result<Plan>,
+    {
+        let mut report = RunReport::default();
+        for account in accounts {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| f(account)))
+                .unwrap_or_else(|payload| Err(eyre!("panicked: {}", panic_message(payload.as_ref()))));
+            report.push(&account.name, outcome);
+        }
+        report
+    }
+
+    /// Record the outcome of one account.
+    pub fn push(&mut self, account: &str, outcome: Result<Plan>) {
+        let entry = match outcome {
+            Ok(plan) => AccountReport {
+                account: account.to_string(),
+                plan: Some(plan),
+                error: None,
+            },
+            Err(e) => {
+                error!("❌ Account '{}' failed: {:?}", account, e);
+                AccountReport {
+                    account: account.to_string(),
+                    plan: None,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+        self.accounts.push(entry);
+    }
+
+    /// Names of the accounts that failed.
+    pub fn failed(&self) -> Vec<&str> {
+        self.accounts
+            .iter()
+            .filter(|a| a.error.is_some())
+            .map(|a| a.account.as_str())
+            .collect()
+    }
+
+    /// Pretty-printed JSON representation of the report.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| eyre!("Failed to serialize report: {}", e))
+    }
+
+    /// Write the report as JSON to `path`.
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let json = self.to_json()?;
+        fs::write(path, json).map_err(|e| eyre!("Failed to write report to {}: {}", path.display(), e))
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for a in &self.accounts {
+            writeln!(f, "== {} ==", a.account)?;
+            match (&a.plan, &a.error) {
+                (_, Some(err)) => writeln!(f, "FAILED: {}", err)?,
+                (Some(plan), None) => writeln!(f, "{}", plan)?,
+                (None, None) => {}
+            }
+        }
+        write!(f, "{} account(s), {} failed", self.accounts.len(), self.failed().len())
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts(config_yaml: &str) -> Vec<Account> {
+        let cfg: crate::cfg::config::Config = serde_yaml::from_str(config_yaml).unwrap();
+        cfg.into_accounts().unwrap()
+    }
+
+    const THREE_ACCOUNTS: &str = r#"
+accounts:
+  - good:
+      imap-username: good@example.com
+  - bad:
+      imap-username: bad@example.com
+  - worse:
+      imap-username: worse@example.com
+"#;
+
+    #[test]
+    fn test_collect_isolates_failures() {
+        let report = RunReport::collect(&accounts(THREE_ACCOUNTS), |account| match account.name.as_str() {
+            "good" => Ok(Plan::new(false)),
+            "bad" => Err(eyre!("IMAP login failed")),
+            _ => panic!("boom"),
+        });
+
+        assert_eq!(report.accounts.len(), 3);
+        assert!(report.accounts[0].plan.is_some());
+        assert_eq!(report.failed(), vec!["bad", "worse"]);
+        assert!(report.accounts[1].error.as_ref().unwrap().contains("IMAP login failed"));
+        assert!(report.accounts[2].error.as_ref().unwrap().contains("boom"));
+
+        let text = report.to_string();
+        assert!(text.contains("== good =="));
+        assert!(text.contains("FAILED: IMAP login failed"));
+        assert!(text.ends_with("3 account(s), 2 failed"));
+    }
+
+    #[test]
+    fn test_report_to_json() {
+        let mut report = RunReport::default();
+        report.push("a", Ok(Plan::new(true)));
+        report.push("b", Err(eyre!("nope")));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["accounts"][0]["account"], "a");
+        assert_eq!(json["accounts"][0]["plan"]["dry_run"], true);
+        assert_eq!(json["accounts"][1]["error"], "nope");
+        assert!(json["accounts"][1]["plan"].is_null());
+    }
+}
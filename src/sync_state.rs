@@ -0,0 +1,442 @@
+// src/sync_state.rs
+//
+// Persisted per-account, per-mailbox sync state for incremental runs.
+// Remembers UIDVALIDITY, UIDNEXT and which UIDs the message filters have
+// already seen, so a run only applies message filters to new mail. With
+// CONDSTORE/QRESYNC it also keeps a metadata snapshot of the mailbox, so a
+// run only fetches messages whose labels or flags changed.
+
+use eyre::{eyre, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cfg::label::Label;
+use crate::client_ops::{MailboxChanges, MailboxStatus};
+use crate::message::{is_kept_header, Message, MimePart};
+
+/// Cached metadata for one message: everything `Message::new` needs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedMessage {
+    pub seq: u32,
+    /// `(name, value as received)`, in order and repeats included. Older
+    /// state files hold a name → value map, which still loads.
+    #[serde(deserialize_with = "deserialize_cached_headers")]
+    pub headers: Vec<(String, String)>,
+    /// Raw labels and flags, as `X-GM-LABELS`/`FLAGS` report them.
+    pub labels: Vec<String>,
+    /// INTERNALDATE as RFC 3339; empty if the server sent none.
+    pub date: String,
+    pub thread_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<MimePart>,
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl CachedMessage {
+    /// Cache `msg`, keeping only `CORE_HEADERS` and the `extra` headers.
+    pub fn from_message(msg: &Message, extra: &[String]) -> Self {
+        CachedMessage {
+            seq: msg.seq,
+            headers: msg
+                .headers
+                .iter()
+                .filter(|f| is_kept_header(&f.name, extra))
+                .map(|f| (f.name.clone(), f.raw().to_string()))
+                .collect(),
+            // A label that mirrors a flag is stored as the flag, which keeps
+            // the backslash `Label` drops from `\Seen` and the like
+            labels: msg
+                .labels
+                .iter()
+                .map(|l| {
+                    msg.flags
+                        .iter()
+                        .find(|f| Label::new(f) == *l)
+                        .cloned()
+                        .unwrap_or_else(|| l.to_string())
+                })
+                .collect(),
+            date: msg.internal_date.map(|date| date.to_rfc3339()).unwrap_or_default(),
+            thread_id: msg.thread_id.clone(),
+            parts: msg.parts.clone(),
+            size: msg.size,
+        }
+    }
+
+    pub fn to_message(&self, uid: u32) -> Message {
+        let raw_headers: String = self.headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+        let mut msg = Message::new(
+            uid,
+            self.seq,
+            raw_headers.into_bytes(),
+            self.labels.clone(),
+            self.date.parse().ok(),
+            self.thread_id.clone(),
+        );
+        msg.parts = self.parts.clone();
+        msg.size = self.size;
+        msg
+    }
+}
+
+fn deserialize_cached_headers<'de, D>(deserializer: D) -> std::result::Result<Vec<(String, String)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Fields(Vec<(String, String)>),
+        Legacy(BTreeMap<String, String>),
+    }
+
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Fields(fields) => fields,
+        Stored::Legacy(map) => map.into_iter().collect(),
+    })
+}
+
+/// What we know about one mailbox from previous runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxState {
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    /// UIDs already run through the message filters.
+    pub processed: BTreeSet<u32>,
+    /// HIGHESTMODSEQ the snapshot is current to; `None` if the server does
+    /// not support CONDSTORE or no snapshot has been taken.
+    #[serde(default)]
+    pub highest_modseq: Option<u64>,
+    /// Metadata snapshot of the mailbox, by UID.
+    #[serde(default)]
+    pub snapshot: BTreeMap<u32, CachedMessage>,
+    /// Headers beyond `CORE_HEADERS` the snapshot keeps. `None` for
+    /// snapshots written before headers were trimmed, which hold whole
+    /// header blocks.
+    #[serde(default)]
+    pub snapshot_headers: Option<Vec<String>>,
+}
+
+impl MailboxState {
+    /// Reconcile with a fresh SELECT. If UIDVALIDITY changed, every stored
+    /// UID is meaningless and the state is reset. Returns `true` on reset.
+    pub fn sync(&mut self, status: &MailboxStatus) -> bool {
+        if self.uid_validity == status.uid_validity {
+            return false;
+        }
+        if self.uid_validity.is_some() {
+            warn!(
+                "UIDVALIDITY changed ({:?} → {:?}); discarding {} processed UIDs",
+                self.uid_validity,
+                status.uid_validity,
+                self.processed.len()
+            );
+        }
+        *self = MailboxState {
+            uid_validity: status.uid_validity,
+            ..MailboxState::default()
+        };
+        true
+    }
+
+    /// Whether the message filters have not yet seen this UID.
+    pub fn is_new(&self, uid: u32) -> bool {
+        !self.processed.contains(&uid)
+    }
+
+    /// Replace the snapshot with freshly fetched messages, keeping `headers`
+    /// beyond `CORE_HEADERS` from now on.
+    pub fn replace_snapshot(&mut self, messages: &[Message], highest_modseq: Option<u64>, headers: &[String]) {
+        self.snapshot = messages
+            .iter()
+            .map(|m| (m.uid, CachedMessage::from_message(m, headers)))
+            .collect();
+        self.snapshot_headers = Some(headers.to_vec());
+        self.highest_modseq = highest_modseq;
+    }
+
+    /// Whether the snapshot keeps every one of `headers` (names compare
+    /// case-insensitively). Older, untrimmed snapshots never do, so they
+    /// are taken again.
+    pub fn snapshot_keeps(&self, headers: &[String]) -> bool {
+        self.snapshot_headers
+            .as_ref()
+            .is_some_and(|kept| headers.iter().all(|h| kept.iter().any(|k| k.eq_ignore_ascii_case(h))))
+    }
+
+    /// Bring the snapshot up to date: apply label changes, drop UIDs that
+    /// vanished or are no longer `present`. Returns the present UIDs missing
+    /// from the snapshot, which need a full fetch.
+    pub fn apply_changes(&mut self, changes: &MailboxChanges, present: &[u32]) -> Vec<u32> {
+        for update in &changes.updated {
+            if let Some(cached) = self.snapshot.get_mut(&update.uid) {
+                cached.labels = update.labels.clone();
+            }
+        }
+        if let Some(vanished) = &changes.vanished {
+            for uid in vanished {
+                self.snapshot.remove(uid);
+            }
+        }
+        let present_set: BTreeSet<u32> = present.iter().copied().collect();
+        self.snapshot.retain(|uid, _| present_set.contains(uid));
+        present
+            .iter()
+            .copied()
+            .filter(|uid| !self.snapshot.contains_key(uid))
+            .collect()
+    }
+
+    /// Add freshly fetched messages to the snapshot. `highest_modseq` is
+    /// left alone: the caller advances it once changes are applied.
+    pub fn extend_snapshot(&mut self, messages: &[Message]) {
+        let headers = self.snapshot_headers.clone().unwrap_or_default();
+        for msg in messages {
+            self.snapshot
+                .insert(msg.uid, CachedMessage::from_message(msg, &headers));
+        }
+    }
+
+    /// Messages rebuilt from the snapshot, in UID order.
+    pub fn snapshot_messages(&self) -> Vec<Message> {
+        self.snapshot
+            .iter()
+            .map(|(uid, cached)| cached.to_message(*uid))
+            .collect()
+    }
+
+    /// Record a completed run: `processed` UIDs were seen by the message
+    /// filters, `present` is every UID still in the mailbox. UIDs that have
+    /// left the mailbox are forgotten.
+    pub fn record(&mut self, processed: &[u32], present: &[u32], uid_next: Option<u32>) {
+        let present: BTreeSet<u32> = present.iter().copied().collect();
+        self.processed.retain(|uid| present.contains(uid));
+        self.processed.extend(processed.iter().copied());
+        self.uid_next = uid_next;
+    }
+}
+
+/// On-disk layout: account → mailbox → state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    accounts: BTreeMap<String, BTreeMap<String, MailboxState>>,
+}
+
+/// Sync state for one account, backed by a JSON file that may hold other
+/// accounts too.
+#[derive(Debug)]
+pub struct SyncState {
+    path: PathBuf,
+    account: String,
+    file: StateFile,
+}
+
+impl SyncState {
+    /// Load the state file at `path`; a missing file means a first run.
+    pub fn load(path: &Path, account: &str) -> Result<Self> {
+        let file = if path.exists() {
+            let content =
+                fs::read_to_string(path).map_err(|e| eyre!("Failed to read state file {}: {}", path.display(), e))?;
+            serde_json::from_str(&content).map_err(|e| eyre!("Failed to parse state file {}: {}", path.display(), e))?
+        } else {
+            info!("No state file at {}; starting fresh", path.display());
+            StateFile::default()
+        };
+
+        Ok(SyncState {
+            path: path.to_path_buf(),
+            account: account.to_string(),
+            file,
+        })
+    }
+
+    /// State for `mailbox`, if any has been recorded.
+    pub fn mailbox(&self, mailbox: &str) -> Option<&MailboxState> {
+        self.file.accounts.get(&self.account).and_then(|m| m.get(mailbox))
+    }
+
+    /// State for `mailbox`, created empty on first use.
+    pub fn mailbox_mut(&mut self, mailbox: &str) -> &mut MailboxState {
+        self.file
+            .accounts
+            .entry(self.account.clone())
+            .or_default()
+            .entry(mailbox.to_string())
+            .or_default()
+    }
+
+    /// Write the state back to its file. Writes a sibling temp file first so
+    /// an interrupted save never leaves a truncated state file behind.
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.file).map_err(|e| eyre!("Failed to serialize state: {}", e))?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json).map_err(|e| eyre!("Failed to write state file {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| eyre!("Failed to replace state file {}: {}", self.path.display(), e))?;
+        debug!("Saved sync state to {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_ops::LabelUpdate;
+
+    fn status(uid_validity: u32, uid_next: u32) -> MailboxStatus {
+        MailboxStatus {
+            exists: 0,
+            uid_validity: Some(uid_validity),
+            uid_next: Some(uid_next),
+            highest_modseq: None,
+        }
+    }
+
+    fn make_message(uid: u32, labels: &[&str]) -> Message {
+        Message::new(
+            uid,
+            uid,
+            b"From: Test User <test@example.com>\r\nSubject: Re: Hello\r\nMessage-ID: <1@example.com>\r\n\r\n".to_vec(),
+            labels.iter().map(|l| l.to_string()).collect(),
+            "2024-01-15T10:00:00+00:00".parse().ok(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_cached_message_round_trip() {
+        let mut msg = make_message(4, &["INBOX", "\\Starred", "\\Seen", "$Forwarded"]);
+        msg.parts.push(MimePart {
+            mime_type: "application/pdf".to_string(),
+            filename: Some("invoice.pdf".to_string()),
+            size: 2048,
+            attachment: true,
+        });
+        msg.size = 3100;
+        let rebuilt = CachedMessage::from_message(&msg, &[]).to_message(4);
+        assert_eq!(rebuilt.uid, 4);
+        assert_eq!(rebuilt.subject, "Re: Hello");
+        assert_eq!(rebuilt.from[0].email, "test@example.com");
+        assert_eq!(rebuilt.message_id, msg.message_id);
+        assert_eq!(rebuilt.labels, msg.labels);
+        assert_eq!(rebuilt.internal_date, msg.internal_date);
+        assert_eq!(rebuilt.parts, msg.parts);
+        assert_eq!(rebuilt.size, 3100);
+        assert_eq!(rebuilt.flags, msg.flags);
+        assert!(rebuilt.has_flag("\\seen") && rebuilt.has_flag("\\Flagged") && rebuilt.has_flag("$forwarded"));
+    }
+
+    #[test]
+    fn test_cached_headers_keep_raw_values_and_accept_legacy_maps() {
+        let msg = Message::new(
+            1,
+            1,
+            b"From: =?UTF-8?Q?M=C3=BCller=2C_Hans?= <hans@example.com>\r\nSubject: =?UTF-8?B?UsOpc3Vtw6k=?=\r\n\
+              Received: from a\r\nReceived: from b\r\nX-Mailer: Mutt\r\n\r\n"
+                .to_vec(),
+            vec![],
+            "2024-01-15T10:00:00+00:00".parse().ok(),
+            None,
+        );
+        let json = serde_json::to_string(&CachedMessage::from_message(&msg, &["received".to_string()])).unwrap();
+        let rebuilt = serde_json::from_str::<CachedMessage>(&json).unwrap().to_message(1);
+        assert_eq!(rebuilt.from[0].name, "Müller, Hans");
+        assert_eq!(rebuilt.subject, "Résumé");
+        assert_eq!(rebuilt.headers.get_all("Received").count(), 2);
+        assert!(!rebuilt.headers.contains("X-Mailer"));
+
+        // state files written before headers were kept in order
+        let legacy: CachedMessage = serde_json::from_str(
+            r#"{"seq": 2, "headers": {"From": "a@example.com", "Subject": "Hi"}, "labels": [], "date": "2024-01-15T10:00:00+00:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.to_message(2).subject, "Hi");
+    }
+
+    #[test]
+    fn test_apply_changes_updates_labels_and_reports_missing() {
+        let mut state = MailboxState::default();
+        state.replace_snapshot(
+            &[make_message(1, &["INBOX"]), make_message(2, &["INBOX"])],
+            Some(10),
+            &[],
+        );
+
+        let changes = MailboxChanges {
+            updated: vec![LabelUpdate {
+                uid: 1,
+                labels: vec!["INBOX".to_string(), "\\Seen".to_string()],
+            }],
+            vanished: Some(vec![2]),
+        };
+        let missing = state.apply_changes(&changes, &[1, 3]);
+        assert_eq!(missing, vec![3]);
+        assert!(!state.snapshot.contains_key(&2));
+        assert!(state.snapshot[&1].labels.contains(&"\\Seen".to_string()));
+
+        state.extend_snapshot(&[make_message(3, &["INBOX"])]);
+        assert_eq!(state.highest_modseq, Some(10));
+        let uids: Vec<u32> = state.snapshot_messages().iter().map(|m| m.uid).collect();
+        assert_eq!(uids, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_only_the_headers_it_was_taken_with() {
+        let mut state = MailboxState::default();
+        assert!(!state.snapshot_keeps(&[]));
+
+        state.replace_snapshot(&[], Some(10), &["List-ID".to_string()]);
+        assert!(state.snapshot_keeps(&[]));
+        assert!(state.snapshot_keeps(&["list-id".to_string()]));
+        assert!(!state.snapshot_keeps(&["X-Spam-Flag".to_string()]));
+    }
+
+    #[test]
+    fn test_sync_resets_on_uid_validity_change() {
+        let mut state = MailboxState::default();
+        assert!(state.sync(&status(1, 5)));
+        state.record(&[1, 2, 3], &[1, 2, 3], Some(4));
+        assert!(!state.sync(&status(1, 5)));
+        assert!(!state.is_new(2));
+
+        assert!(state.sync(&status(2, 5)));
+        assert_eq!(state.uid_validity, Some(2));
+        assert!(state.processed.is_empty());
+        assert!(state.is_new(2));
+    }
+
+    #[test]
+    fn test_record_forgets_uids_that_left() {
+        let mut state = MailboxState::default();
+        state.record(&[1, 2, 3], &[1, 2, 3], Some(4));
+        state.record(&[4], &[2, 4], Some(5));
+        assert_eq!(state.processed.iter().copied().collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(state.uid_next, Some(5));
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let mut state = SyncState::load(&path, "imap.example.com/me").unwrap();
+        assert!(state.mailbox("INBOX").is_none());
+        state.mailbox_mut("INBOX").sync(&status(7, 10));
+        state.mailbox_mut("INBOX").record(&[8, 9], &[8, 9], Some(10));
+        state.save().unwrap();
+
+        let reloaded = SyncState::load(&path, "imap.example.com/me").unwrap();
+        let inbox = reloaded.mailbox("INBOX").unwrap();
+        assert_eq!(inbox.uid_validity, Some(7));
+        assert_eq!(inbox.uid_next, Some(10));
+        assert!(!inbox.is_new(8));
+
+        // Other accounts in the same file are independent
+        let other = SyncState::load(&path, "imap.example.com/someone-else").unwrap();
+        assert!(other.mailbox("INBOX").is_none());
+    }
+}
@@ -1,152 +1,151 @@
-use eyre::Result;
-use imap::{ImapConnection, Session};
 use log::debug;
 use std::collections::{HashMap, HashSet};
 
-use crate::cfg::message_filter::FilterAction;
-use crate::cfg::state_filter::{StateAction, StateFilter};
-use crate::client_ops::{Clock, RealClock};
+use crate::cfg::state_filter::StateFilter;
+use crate::client_ops::Clock;
 use crate::message::Message;
 
-/// Builds a thread map from messages using available thread identification methods.
+/// The fields thread grouping needs from a message, and nothing else.
+/// Grouping works from these so whole headers need not be retained.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadKey {
+    pub uid: u32,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub thread_id: Option<String>,
+}
+
+impl ThreadKey {
+    pub fn of(msg: &Message) -> Self {
+        ThreadKey {
+            uid: msg.uid,
+            message_id: msg.message_id.clone(),
+            in_reply_to: msg.in_reply_to.clone(),
+            references: msg.references.clone(),
+            thread_id: msg.thread_id.clone(),
+        }
+    }
+}
+
+/// Groups messages into threads by UID, working from their `ThreadKey`s only.
 ///
 /// Priority order:
 /// 1. Gmail X-GM-THRID (if available)
 /// 2. Standard headers: Message-ID, In-Reply-To, References
 ///
-/// For standard headers, we build a union-find structure to group related messages.
-pub fn build_thread_map(messages: &[Message]) -> HashMap<String, Vec<Message>> {
-    let mut thread_map: HashMap<String, Vec<Message>> = HashMap::new();
+/// For standard headers, related Message-IDs are grouped into connected components.
+pub fn build_thread_index<I>(keys: I) -> HashMap<String, Vec<u32>>
+where
+    I: IntoIterator<Item = ThreadKey>,
+{
+    let mut thread_map: HashMap<String, Vec<u32>> = HashMap::new();
+    let mut total = 0;
 
     // First pass: collect all messages with Gmail thread IDs
-    let mut messages_without_gmail_thread: Vec<&Message> = Vec::new();
+    let mut keys_without_gmail_thread: Vec<ThreadKey> = Vec::new();
 
-    for msg in messages {
-        if let Some(thread_id) = &msg.thread_id {
+    for key in keys {
+        total += 1;
+        match &key.thread_id {
             // Gmail thread ID available - use it directly
-            thread_map.entry(thread_id.clone()).or_default().push(msg.clone());
-        } else {
-            messages_without_gmail_thread.push(msg);
+            Some(thread_id) => thread_map.entry(thread_id.clone()).or_default().push(key.uid),
+            None => keys_without_gmail_thread.push(key),
         }
     }
 
     // If all messages have Gmail thread IDs, we're done
-    if messages_without_gmail_thread.is_empty() {
+    if keys_without_gmail_thread.is_empty() {
         return thread_map;
     }
 
     // Second pass: build thread groups using standard headers
     // Build adjacency: which Message-IDs are related
-    let mut related: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut related: HashMap<&str, HashSet<&str>> = HashMap::new();
 
-    for msg in &messages_without_gmail_thread {
-        let msg_id = msg.message_id.clone().unwrap_or_default();
-        if msg_id.is_empty() {
-            continue;
-        }
-
-        // In-Reply-To links this message to its parent
-        if let Some(ref parent_id) = msg.in_reply_to {
-            related.entry(msg_id.clone()).or_default().insert(parent_id.clone());
-            related.entry(parent_id.clone()).or_default().insert(msg_id.clone());
-        }
+    for key in &keys_without_gmail_thread {
+        let msg_id = match &key.message_id {
+            Some(id) if !id.is_empty() => id.as_str(),
+            _ => continue,
+        };
 
-        // References links this message to all ancestors
-        for ref_id in &msg.references {
-            related.entry(msg_id.clone()).or_default().insert(ref_id.clone());
-            related.entry(ref_id.clone()).or_default().insert(msg_id.clone());
+        // In-Reply-To links this message to its parent, References to all ancestors
+        for parent_id in key.in_reply_to.iter().chain(key.references.iter()) {
+            related.entry(msg_id).or_default().insert(parent_id);
+            related.entry(parent_id).or_default().insert(msg_id);
         }
     }
 
-    // Find connected components (thread groups) using BFS
-    let mut visited: HashSet<String> = HashSet::new();
-    let mut component_id = 0;
+    // Find connected components (thread groups) using DFS; each Message-ID
+    // maps to the index of its component
+    let mut component_of: HashMap<&str, usize> = HashMap::new();
+    let mut component_count = 0;
 
-    for msg in &messages_without_gmail_thread {
-        let msg_id = match &msg.message_id {
-            Some(id) if !id.is_empty() => id.clone(),
+    for key in &keys_without_gmail_thread {
+        let msg_id = match &key.message_id {
+            Some(id) if !id.is_empty() => id.as_str(),
             _ => continue,
         };
 
-        if visited.contains(&msg_id) {
+        if component_of.contains_key(msg_id) {
             continue;
         }
 
-        // BFS to find all connected message IDs
-        let mut component: HashSet<String> = HashSet::new();
-        let mut queue = vec![msg_id.clone()];
-
-        while let Some(current) = queue.pop() {
-            if visited.contains(&current) {
+        let mut stack = vec![msg_id];
+        while let Some(current) = stack.pop() {
+            if component_of.contains_key(current) {
                 continue;
             }
-            visited.insert(current.clone());
-            component.insert(current.clone());
-
-            if let Some(neighbors) = related.get(&current) {
-                for neighbor in neighbors {
-                    if !visited.contains(neighbor) {
-                        queue.push(neighbor.clone());
-                    }
-                }
-            }
-        }
-
-        // Create a thread ID for this component
-        let thread_id = format!("std-thread-{}", component_id);
-        component_id += 1;
+            component_of.insert(current, component_count);
 
-        // Add all messages in this component to the thread map
-        for msg in &messages_without_gmail_thread {
-            if let Some(ref mid) = msg.message_id {
-                if component.contains(mid) {
-                    thread_map.entry(thread_id.clone()).or_default().push((*msg).clone());
-                }
+            if let Some(neighbors) = related.get(current) {
+                stack.extend(neighbors.iter().filter(|n| !component_of.contains_key(*n)));
             }
         }
+        component_count += 1;
     }
 
-    // Handle messages with no Message-ID (each is its own "thread")
-    for msg in &messages_without_gmail_thread {
-        if msg.message_id.is_none() || msg.message_id.as_ref().map(|s| s.is_empty()).unwrap_or(true) {
-            let solo_thread_id = format!("solo-uid-{}", msg.uid);
-            thread_map.entry(solo_thread_id).or_default().push((*msg).clone());
-        }
+    // Add every message to its component's thread; messages with no
+    // Message-ID are each their own "thread"
+    for key in &keys_without_gmail_thread {
+        let thread_id = match key.message_id.as_deref().and_then(|id| component_of.get(id)) {
+            Some(component) => format!("std-thread-{}", component),
+            None => format!("solo-uid-{}", key.uid),
+        };
+        thread_map.entry(thread_id).or_default().push(key.uid);
     }
 
-    debug!(
-        "Built thread map: {} threads from {} messages",
-        thread_map.len(),
-        messages.len()
-    );
+    debug!("Built thread map: {} threads from {} messages", thread_map.len(), total);
 
     thread_map
 }
 
+/// Thread membership of a set of messages, held as UIDs only. Members are
+/// looked up in the caller's messages, which must be sorted by UID.
 pub struct ThreadProcessor {
-    thread_map: HashMap<String, Vec<Message>>,
+    thread_map: HashMap<String, Vec<u32>>,
     // Reverse lookup: UID -> thread_id for O(1) lookup
     uid_to_thread: HashMap<u32, String>,
 }
 
 impl ThreadProcessor {
-    pub fn new(messages: &[Message]) -> Self {
+    pub fn new<'a, I>(messages: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
         use log::info;
         use std::time::Instant;
 
-        info!(
-            "[ThreadProcessor] Building thread map from {} messages...",
-            messages.len()
-        );
+        info!("[ThreadProcessor] Building thread map...");
         let start = Instant::now();
 
-        let thread_map = build_thread_map(messages);
+        let thread_map = build_thread_index(messages.into_iter().map(ThreadKey::of));
 
         // Build reverse lookup for O(1) thread ID lookup by UID
         let mut uid_to_thread: HashMap<u32, String> = HashMap::new();
-        for (thread_id, msgs) in &thread_map {
-            for msg in msgs {
-                uid_to_thread.insert(msg.uid, thread_id.clone());
+        for (thread_id, uids) in &thread_map {
+            for uid in uids {
+                uid_to_thread.insert(*uid, thread_id.clone());
             }
         }
 
@@ -177,120 +176,101 @@ impl ThreadProcessor {
         self.uid_to_thread.get(&msg.uid).cloned()
     }
 
-    /// Processes a message filter action across an entire thread
-    pub fn process_thread_message_filter<C: ImapConnection>(
-        &self,
-        client: &mut Session<C>,
-        msg: &Message,
-        action: &FilterAction,
-    ) -> Result<Vec<Message>> {
-        let mut processed = Vec::new();
+    /// The members of `msg`'s thread found in `messages` (sorted by UID).
+    fn members<'a>(&self, msg: &Message, messages: &'a [Message]) -> Option<(String, Vec<&'a Message>)> {
+        let thread_id = self.get_thread_id(msg)?;
+        let uids = self.thread_map.get(&thread_id)?;
+        let members = uids
+            .iter()
+            .filter_map(|uid| messages.binary_search_by_key(uid, |m| m.uid).ok())
+            .map(|i| &messages[i])
+            .collect();
+        Some((thread_id, members))
+    }
 
-        // Find the thread this message belongs to
-        if let Some(thread_id) = self.get_thread_id(msg) {
-            if let Some(thread_msgs) = self.thread_map.get(&thread_id) {
-                debug!("Processing thread {} with {} messages", thread_id, thread_msgs.len());
-                for thread_msg in thread_msgs {
-                    // Apply the same action to each message in thread
-                    crate::imap_filter::apply_message_action(client, thread_msg, action)?;
-                    processed.push(thread_msg.clone());
-                }
+    /// Returns every message in `msg`'s thread, or just `msg` if it is not part of one.
+    /// Message filter actions apply to the whole thread.
+    pub fn thread_members<'a>(&self, msg: &'a Message, messages: &'a [Message]) -> Vec<&'a Message> {
+        match self.members(msg, messages) {
+            Some((thread_id, members)) if !members.is_empty() => {
+                debug!("Thread {} has {} messages", thread_id, members.len());
+                members
             }
-        } else {
-            // Not part of a thread, just process the single message
-            crate::imap_filter::apply_message_action(client, msg, action)?;
-            processed.push(msg.clone());
+            _ => vec![msg],
         }
-
-        Ok(processed)
     }
 
-    /// Processes a state filter action across an entire thread.
-    /// TTL is evaluated based on the NEWEST message in the thread.
-    /// The thread only expires when the newest message has exceeded TTL.
-    pub fn process_thread_state_filter<Conn: ImapConnection>(
-        &self,
-        client: &mut Session<Conn>,
-        msg: &Message,
-        filter: &StateFilter,
-        action: &StateAction,
-    ) -> Result<Vec<Message>> {
-        self.process_thread_state_filter_with_clock(client, msg, filter, action, &RealClock)
+    /// Summed RFC822.SIZE of `msg`'s thread, or of `msg` alone if it has none.
+    pub fn thread_size(&self, msg: &Message, messages: &[Message]) -> u64 {
+        match self.members(msg, messages) {
+            Some((_, members)) if !members.is_empty() => members.iter().map(|m| m.size).sum(),
+            _ => msg.size,
+        }
     }
 
-    /// Processes a state filter action across an entire thread with a custom clock.
+    /// Returns the messages a state filter should act on for `msg`'s thread.
     /// TTL is evaluated based on the NEWEST message in the thread.
-    /// The thread only expires when the newest message has exceeded TTL.
-    pub fn process_thread_state_filter_with_clock<Conn: ImapConnection, Clk: Clock>(
+    /// The thread only expires when the newest message has exceeded TTL;
+    /// until then this returns an empty list.
+    pub fn expired_thread_members<'a, Clk: Clock>(
         &self,
-        client: &mut Session<Conn>,
-        msg: &Message,
+        msg: &'a Message,
+        messages: &'a [Message],
         filter: &StateFilter,
-        action: &StateAction,
         clock: &Clk,
-    ) -> Result<Vec<Message>> {
-        let mut processed = Vec::new();
-
+    ) -> Vec<&'a Message> {
         debug!("    [thread] Looking up thread for UID {}", msg.uid);
 
         // Find the thread this message belongs to
-        if let Some(thread_id) = self.get_thread_id(msg) {
+        if let Some((thread_id, thread_msgs)) = self.members(msg, messages) {
             debug!("    [thread] Found thread_id: {}", thread_id);
-
-            if let Some(thread_msgs) = self.thread_map.get(&thread_id) {
-                debug!("    [thread] Thread has {} messages", thread_msgs.len());
-
-                // Find newest message in thread (by date)
-                let newest_msg = thread_msgs.iter().max_by_key(|m| m.date.clone()).unwrap_or(msg);
+            debug!("    [thread] Thread has {} messages", thread_msgs.len());
+
+            // Find newest message in thread, by the filter's date source;
+            // undated messages count as oldest
+            let newest_msg = thread_msgs
+                .iter()
+                .copied()
+                .max_by_key(|m| m.date(filter.date))
+                .unwrap_or(msg);
+            debug!(
+                "    [thread] Newest msg in thread: UID {} dated {:?}",
+                newest_msg.uid,
+                newest_msg.date(filter.date)
+            );
+
+            // Evaluate TTL based on the newest message only
+            // If the newest message has expired, the whole thread expires
+            debug!("    [thread] Evaluating TTL for filter '{}'", filter.name);
+            let ttl_result = filter.evaluate_ttl(newest_msg, clock);
+            debug!("    [thread] TTL result: {:?}", ttl_result);
+
+            if ttl_result.is_some() {
                 debug!(
-                    "    [thread] Newest msg in thread: UID {} dated {}",
-                    newest_msg.uid, newest_msg.date
+                    "    [thread] Thread {} EXPIRED (newest msg UID {} from {} dated {:?})",
+                    thread_id,
+                    newest_msg.uid,
+                    newest_msg.sender_display(),
+                    newest_msg.date(filter.date)
                 );
-
-                // Evaluate TTL based on the newest message only
-                // If the newest message has expired, the whole thread expires
-                debug!("    [thread] Evaluating TTL for filter '{}'", filter.name);
-                let ttl_result = filter.evaluate_ttl(newest_msg, clock);
-                debug!("    [thread] TTL result: {:?}", ttl_result);
-
-                let thread_expired = ttl_result.map(|opt| opt.is_some()).unwrap_or(false);
-
-                if thread_expired {
-                    debug!(
-                        "    [thread] Thread {} EXPIRED (newest msg UID {} from {} dated {})",
-                        thread_id,
-                        newest_msg.uid,
-                        newest_msg.sender_display(),
-                        newest_msg.date
-                    );
-                    for thread_msg in thread_msgs {
-                        debug!("    [thread] Applying action to UID {}", thread_msg.uid);
-                        crate::imap_filter::apply_state_action(client, thread_msg, action)?;
-                        processed.push(thread_msg.clone());
-                    }
-                } else {
-                    debug!("    [thread] Thread NOT expired yet");
-                }
-            } else {
-                debug!("    [thread] No messages found in thread_map for thread_id");
-            }
-        } else {
-            debug!("    [thread] No thread found, evaluating single message");
-            // Not part of a thread, evaluate normally
-            let ttl_result = filter.evaluate_ttl(msg, clock);
-            debug!("    [thread] Single msg TTL result: {:?}", ttl_result);
-
-            if let Ok(Some(_)) = ttl_result {
-                debug!("    [thread] Single msg EXPIRED, applying action");
-                crate::imap_filter::apply_state_action(client, msg, action)?;
-                processed.push(msg.clone());
-            } else {
-                debug!("    [thread] Single msg NOT expired");
+                return thread_msgs;
             }
+            debug!("    [thread] Thread NOT expired yet");
+            return Vec::new();
         }
 
-        debug!("    [thread] Returning {} processed messages", processed.len());
-        Ok(processed)
+        debug!("    [thread] No thread found, evaluating single message");
+        // Not part of a thread, evaluate normally
+        let ttl_result = filter.evaluate_ttl(msg, clock);
+        debug!("    [thread] Single msg TTL result: {:?}", ttl_result);
+
+        if ttl_result.is_some() {
+            debug!("    [thread] Single msg EXPIRED");
+            vec![msg]
+        } else {
+            debug!("    [thread] Single msg NOT expired");
+            Vec::new()
+        }
     }
 }
 
@@ -298,6 +278,9 @@ impl ThreadProcessor {
 mod tests {
     use super::*;
     use crate::cfg::label::Label;
+    use crate::client_ops::RealClock;
+    use crate::message::Headers;
+    use chrono::{Duration, Utc};
 
     fn make_message(
         uid: u32,
@@ -311,27 +294,41 @@ mod tests {
             seq: uid,
             to: vec![],
             cc: vec![],
+            bcc: vec![],
             from: vec![],
+            sender: vec![],
+            reply_to: vec![],
+            delivered_to: vec![],
+            return_path: vec![],
+            list_post: vec![],
             subject: format!("Test message {}", uid),
-            date: "2024-01-15T10:00:00+00:00".to_string(),
+            internal_date: "2024-01-15T10:00:00+00:00".parse().ok(),
+            header_date: None,
             labels: vec![Label::Inbox],
-            headers: std::collections::HashMap::new(),
+            headers: Headers::default(),
             message_id: message_id.map(String::from),
             in_reply_to: in_reply_to.map(String::from),
             references: references.into_iter().map(String::from).collect(),
             thread_id: thread_id.map(String::from),
+            parts: vec![],
+            size: 0,
+            flags: vec![],
         }
     }
 
+    fn thread_index(messages: &[Message]) -> HashMap<String, Vec<u32>> {
+        build_thread_index(messages.iter().map(ThreadKey::of))
+    }
+
     #[test]
-    fn test_build_thread_map_gmail_thread_ids() {
+    fn test_build_thread_index_gmail_thread_ids() {
         let messages = vec![
             make_message(1, Some("gmail-thread-1"), None, None, vec![]),
             make_message(2, Some("gmail-thread-1"), None, None, vec![]),
             make_message(3, Some("gmail-thread-2"), None, None, vec![]),
         ];
 
-        let thread_map = build_thread_map(&messages);
+        let thread_map = thread_index(&messages);
 
         assert_eq!(thread_map.len(), 2);
         assert_eq!(thread_map.get("gmail-thread-1").unwrap().len(), 2);
@@ -339,7 +336,7 @@ mod tests {
     }
 
     #[test]
-    fn test_build_thread_map_standard_headers() {
+    fn test_build_thread_index_standard_headers() {
         // Simulate a thread:
         // msg1: root message
         // msg2: reply to msg1
@@ -356,7 +353,7 @@ mod tests {
             ),
         ];
 
-        let thread_map = build_thread_map(&messages);
+        let thread_map = thread_index(&messages);
 
         // All three messages should be in the same thread
         assert_eq!(thread_map.len(), 1);
@@ -365,7 +362,7 @@ mod tests {
     }
 
     #[test]
-    fn test_build_thread_map_separate_threads() {
+    fn test_build_thread_index_separate_threads() {
         // Two separate conversations
         let messages = vec![
             make_message(1, None, Some("<thread1-msg1@test.com>"), None, vec![]),
@@ -386,28 +383,28 @@ mod tests {
             ),
         ];
 
-        let thread_map = build_thread_map(&messages);
+        let thread_map = thread_index(&messages);
 
         // Two separate threads
         assert_eq!(thread_map.len(), 2);
     }
 
     #[test]
-    fn test_build_thread_map_no_message_id() {
+    fn test_build_thread_index_no_message_id() {
         // Messages without Message-ID become solo threads
         let messages = vec![
             make_message(1, None, None, None, vec![]),
             make_message(2, None, None, None, vec![]),
         ];
 
-        let thread_map = build_thread_map(&messages);
+        let thread_map = thread_index(&messages);
 
         // Each message is its own "thread"
         assert_eq!(thread_map.len(), 2);
     }
 
     #[test]
-    fn test_build_thread_map_mixed_gmail_and_standard() {
+    fn test_build_thread_index_mixed_gmail_and_standard() {
         let messages = vec![
             // Gmail thread
             make_message(1, Some("gmail-thread-1"), None, None, vec![]),
@@ -423,7 +420,7 @@ mod tests {
             ),
         ];
 
-        let thread_map = build_thread_map(&messages);
+        let thread_map = thread_index(&messages);
 
         // Should have 2 threads: 1 Gmail + 1 standard
         assert_eq!(thread_map.len(), 2);
@@ -450,4 +447,92 @@ mod tests {
         assert!(thread_id.is_some());
         assert!(thread_id.unwrap().starts_with("std-thread-"));
     }
+
+    #[test]
+    fn test_thread_index_from_compact_keys() {
+        let keys = vec![
+            ThreadKey {
+                uid: 7,
+                message_id: Some("<root@test.com>".to_string()),
+                in_reply_to: None,
+                references: vec![],
+                thread_id: None,
+            },
+            ThreadKey {
+                uid: 9,
+                message_id: Some("<reply@test.com>".to_string()),
+                in_reply_to: None,
+                references: vec!["<root@test.com>".to_string()],
+                thread_id: None,
+            },
+            ThreadKey {
+                uid: 12,
+                message_id: None,
+                in_reply_to: None,
+                references: vec![],
+                thread_id: None,
+            },
+        ];
+
+        let index = build_thread_index(keys);
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("std-thread-0"), Some(&vec![7, 9]));
+        assert_eq!(index.get("solo-uid-12"), Some(&vec![12]));
+    }
+
+    #[test]
+    fn test_thread_members_resolve_against_given_messages() {
+        let messages = vec![
+            make_message(1, None, Some("<a@test.com>"), None, vec![]),
+            make_message(2, None, Some("<b@test.com>"), Some("<a@test.com>"), vec![]),
+            make_message(3, None, Some("<c@test.com>"), None, vec![]),
+        ];
+        // Group only the first two; members are looked up in all three
+        let processor = ThreadProcessor::new(&messages[..2]);
+
+        let members: Vec<u32> = processor
+            .thread_members(&messages[1], &messages)
+            .iter()
+            .map(|m| m.uid)
+            .collect();
+        assert_eq!(members, vec![1, 2]);
+        let members: Vec<u32> = processor
+            .thread_members(&messages[2], &messages)
+            .iter()
+            .map(|m| m.uid)
+            .collect();
+        assert_eq!(members, vec![3]);
+    }
+
+    #[test]
+    fn test_thread_expiry_follows_the_filters_date_source() {
+        let now = Utc::now();
+        let mut messages = vec![
+            make_message(1, None, Some("<a@test.com>"), None, vec![]),
+            make_message(2, None, Some("<b@test.com>"), Some("<a@test.com>"), vec![]),
+        ];
+        messages[0].internal_date = Some(now - Duration::days(10));
+        // delivered recently, but written long ago
+        messages[1].internal_date = Some(now - Duration::days(3));
+        messages[1].header_date = Some(now - Duration::days(20));
+        let processor = ThreadProcessor::new(&messages);
+        let expired = |yaml: &str, messages: &[Message]| -> Vec<u32> {
+            let filter: StateFilter = serde_yaml::from_str(yaml).unwrap();
+            processor
+                .expired_thread_members(&messages[0], messages, &filter, &RealClock)
+                .iter()
+                .map(|m| m.uid)
+                .collect()
+        };
+
+        assert!(expired("{ ttl: 7d }", &messages).is_empty());
+        // by header, the newest is the first message, ten days old
+        assert_eq!(expired("{ ttl: 7d, date: header }", &messages), vec![1, 2]);
+
+        // an undated message counts as the oldest
+        messages[1].internal_date = None;
+        messages[1].header_date = None;
+        assert_eq!(expired("{ ttl: 7d }", &messages), vec![1, 2]);
+    }
 }
@@ -3,7 +3,7 @@
 use chrono::Duration;
 use eyre::{eyre, Result};
 use imap::Session;
-use log::{debug, info, warn};
+use log::{debug, warn};
 use regex::Regex;
 use std::collections::HashSet;
 use std::io::{Read, Write};
@@ -40,9 +40,34 @@ impl std::fmt::Display for ImapErrorKind {
     }
 }
 
+impl ImapErrorKind {
+    /// Whether the error is about the session rather than particular
+    /// messages, so retrying the same UIDs one at a time cannot help.
+    pub fn is_session_error(&self) -> bool {
+        matches!(self, ImapErrorKind::ConnectionLost)
+    }
+}
+
 /// Classify an IMAP error based on its message/type
 pub fn classify_imap_error(error: &imap::Error) -> ImapErrorKind {
-    let error_str = format!("{:?}", error);
+    classify_error_text(&format!("{:?}", error))
+}
+
+/// Classify an error that has already been wrapped in an `eyre::Report`.
+/// Uses the underlying `imap::Error` or `BatchError` when one is attached,
+/// and falls back to the report's text (e.g. errors re-raised by
+/// `with_retry` or by connecting).
+pub fn classify_report(error: &eyre::Report) -> ImapErrorKind {
+    if let Some(e) = error.downcast_ref::<BatchError>() {
+        return e.kind.clone();
+    }
+    match error.downcast_ref::<imap::Error>() {
+        Some(e) => classify_imap_error(e),
+        None => classify_error_text(&format!("{:?}", error)),
+    }
+}
+
+fn classify_error_text(error_str: &str) -> ImapErrorKind {
     let error_lower = error_str.to_lowercase();
 
     // Check for rate limiting indicators
@@ -72,6 +97,7 @@ pub fn classify_imap_error(error: &imap::Error) -> ImapErrorKind {
         || error_lower.contains("reset by peer")
         || error_lower.contains("timed out")
         || error_lower.contains("eof")
+        || error_lower.contains("not authenticated")
     {
         return ImapErrorKind::ConnectionLost;
     }
@@ -101,8 +127,15 @@ const MAX_RETRIES: u32 = 3;
 const INITIAL_BACKOFF_MS: u64 = 1000;
 const BACKOFF_MULTIPLIER: u64 = 2;
 
+/// Most UIDs sent in one STORE/MOVE command, keeping command lines short
+pub const MAX_BATCH: usize = 500;
+
+/// Most UIDs fetched in one FETCH. Bounds both the command line and the raw
+/// responses held in memory at once.
+pub const MAX_FETCH: usize = 1000;
+
 /// Execute an IMAP operation with retry logic
-fn with_retry<F, T>(operation_name: &str, uid: u32, mut operation: F) -> Result<T>
+fn with_retry<F, T>(operation_name: &str, uid: &str, mut operation: F) -> Result<T>
 where
     F: FnMut() -> std::result::Result<T, imap::Error>,
 {
@@ -148,6 +181,119 @@ where
     }
 }
 
+/// A batched operation that failed for some of its UIDs; the others were
+/// processed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchError {
+    pub operation: String,
+    pub failed: Vec<u32>,
+    pub total: usize,
+    /// Classification of the last failure.
+    pub kind: ImapErrorKind,
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} failed for UIDs {} ({} of {})",
+            self.operation,
+            compress_uid_set(&self.failed),
+            self.failed.len(),
+            self.total
+        )
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// Run `operation` over `uids` in chunks of at most `MAX_BATCH`, passing
+/// each chunk as a compressed UID set. Each chunk is retried on its own; if
+/// a chunk still fails, its UIDs are retried one at a time so one bad
+/// message does not sink the rest. A session error (a lost connection) stops
+/// at once, counting every UID not yet processed as failed. Fails with a
+/// `BatchError` listing every UID that could not be processed.
+fn with_batch_retry<F>(operation_name: &str, uids: &[u32], mut operation: F) -> Result<()>
+where
+    F: FnMut(&str) -> std::result::Result<(), imap::Error>,
+{
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+
+    let mut failed = Vec::new();
+    let mut kind = ImapErrorKind::Unknown;
+    // Run one UID set with retries, remembering how its last failure classified
+    let mut attempt = |set: &str, kind: &mut ImapErrorKind| {
+        with_retry(operation_name, set, || {
+            operation(set).inspect_err(|e| *kind = classify_imap_error(e))
+        })
+    };
+
+    'chunks: for (n, chunk) in uids.chunks(MAX_BATCH).enumerate() {
+        let set = compress_uid_set(chunk);
+        debug!("{} on UID set {}", operation_name, set);
+        if attempt(&set, &mut kind).is_ok() {
+            continue;
+        }
+        if kind.is_session_error() {
+            failed.extend_from_slice(&uids[n * MAX_BATCH..]);
+            break;
+        }
+        if chunk.len() == 1 {
+            failed.push(chunk[0]);
+            continue;
+        }
+        warn!(
+            "⚠️  {} failed for {} UIDs; falling back to one UID at a time",
+            operation_name,
+            chunk.len()
+        );
+        for (i, &uid) in chunk.iter().enumerate() {
+            let single = uid.to_string();
+            if let Err(e) = attempt(&single, &mut kind) {
+                warn!("{:?}", e);
+                if kind.is_session_error() {
+                    failed.extend_from_slice(&uids[n * MAX_BATCH + i..]);
+                    break 'chunks;
+                }
+                failed.push(uid);
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(BatchError {
+            operation: operation_name.to_string(),
+            failed,
+            total: uids.len(),
+            kind,
+        }
+        .into())
+    }
+}
+
+/// Render UIDs as an IMAP sequence set, collapsing runs into ranges:
+/// `[1, 2, 3, 5, 9, 10]` becomes `1:3,5,9:10`. The input must be sorted.
+pub fn compress_uid_set(uids: &[u32]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut iter = uids.iter().copied().peekable();
+    while let Some(start) = iter.next() {
+        let mut end = start;
+        while end.checked_add(1).is_some_and(|next| iter.peek() == Some(&next)) {
+            end = iter.next().unwrap_or(end);
+        }
+        if start == end {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}:{}", start, end));
+        }
+    }
+    parts.join(",")
+}
+
 /// Parse a string like "7d" into a chrono::Duration of days.
 /// Returns an error if the format is unsupported.
 pub fn parse_days(s: &str) -> Result<Duration> {
@@ -160,21 +306,46 @@ pub fn parse_days(s: &str) -> Result<Duration> {
     }
 }
 
-/// Ensures the given label exists on the server, creating it if necessary.
-pub fn ensure_label_exists<T>(client: &mut Session<T>, label: &str) -> Result<()>
-where
-    T: Read + Write,
-{
-    let list = client.list(None, Some("*"))?;
-    let exists = list.iter().any(|mb| mb.name() == label);
-    if !exists {
-        info!("Creating missing label '{}'", label);
-        client
-            .create(label)
-            .map_err(|e| eyre!("Failed to create label '{}': {:?}", label, e))?;
-        info!("Label '{}' created", label);
-    }
-    Ok(())
+/// Parse an interval like "90s", "15m", "1h" or "1d" into a std Duration.
+/// Returns an error if the format is unsupported.
+pub fn parse_interval(s: &str) -> Result<StdDuration> {
+    let s = s.trim();
+    let (num, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => return Err(eyre!("Unsupported interval format '{}'; expected '<n>s|m|h|d'", s)),
+    };
+    let n: u64 = num.parse().map_err(|e| eyre!("Invalid interval '{}': {}", s, e))?;
+    if n == 0 {
+        return Err(eyre!("Interval '{}' must be greater than zero", s));
+    }
+    Ok(StdDuration::from_secs(n * unit_secs))
+}
+
+/// Parse a size such as `512`, `200KB`, `5MB` or `1GB` into bytes. Units are
+/// binary (1KB = 1024 bytes) and case-insensitive; a bare number is bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(digits);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(eyre!("Unsupported size '{}'; expected '<n>[B|KB|MB|GB]'", s)),
+    };
+    let n: u64 = num.parse().map_err(|e| eyre!("Invalid size '{}': {}", s, e))?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| eyre!("Size '{}' is too large", s))
+}
+
+/// Whether `mailbox` is Gmail's All Mail view. Every message lives there, so
+/// expunging from it (as MOVE does) trashes the message instead of filing it.
+pub fn is_all_mail(mailbox: &str) -> bool {
+    matches!(mailbox, "[Gmail]/All Mail" | "[Google Mail]/All Mail")
 }
 
 /// Returns the set of Gmail labels on this message (by UID).
@@ -213,51 +384,63 @@ fn extract_gmail_extension(raw: &str, field: &str) -> Option<String> {
         .map(|m| m.as_str().to_string())
 }
 
-/// Add a label to the message, creating the label if needed.
+/// Add a label to every message in `uids`. The label must already exist.
 /// Includes retry logic for transient errors and rate limiting.
-pub fn set_label<T>(client: &mut Session<T>, uid: u32, label: &str, subject: &str) -> Result<()>
+pub fn set_label<T>(client: &mut Session<T>, uids: &[u32], label: &str) -> Result<()>
 where
     T: Read + Write,
 {
-    let current = get_labels(client, uid)?;
-    if current.contains(label) {
-        debug!("UID {} already has label '{}' (subject={})", uid, label, subject);
-        return Ok(());
-    }
-    ensure_label_exists(client, label)?;
-    // SILENT to suppress the untagged FETCH
-    let cmd = format!(
-        "+X-GM-LABELS.SILENT (\"{}\")",
-        label.replace('\\', "\\\\").replace('"', "\\\"")
-    );
+    // SILENT to suppress the untagged FETCH; adding a label a message
+    // already has is a no-op, so there is no need to check first
+    let cmd = format!("+X-GM-LABELS.SILENT (\"{}\")", quote_label(label));
     debug!("before client.uid_store: cmd={}", cmd);
 
-    let cmd_owned = cmd.clone();
-    with_retry(&format!("SET_LABEL {}", label), uid, || {
-        client.uid_store(uid.to_string(), &cmd_owned)
+    with_batch_retry(&format!("SET_LABEL {}", label), uids, |set| {
+        client.uid_store(set, &cmd).map(|_| ())
     })
-    .map(|_| ())
-    .map_err(|e| eyre!("{} | subject: {}", e, subject))
 }
 
-/// "Move" a message by moving it server-side from INBOX → `label`.
-/// Uses the UID MOVE extension (Gmail supports it), so you never have
-/// to manually remove "INBOX" yourself.
+/// Remove a label from every message in `uids`.
 /// Includes retry logic for transient errors and rate limiting.
-pub fn uid_move_gmail<T>(client: &mut Session<T>, uid: u32, label: &str, subject: &str) -> Result<()>
+pub fn remove_label<T>(client: &mut Session<T>, uids: &[u32], label: &str) -> Result<()>
 where
     T: Read + Write,
 {
-    // make sure the destination mailbox/label exists
-    ensure_label_exists(client, label)?;
+    let cmd = format!("-X-GM-LABELS.SILENT (\"{}\")", quote_label(label));
+    debug!("before client.uid_store: cmd={}", cmd);
 
-    // this sends: `a1 UID MOVE 12345 "Purgatory"` with retry logic
-    let label_owned = label.to_string();
-    with_retry(&format!("MOVE → {}", label), uid, || {
-        client.uid_mv(uid.to_string(), &label_owned)
+    with_batch_retry(&format!("REMOVE_LABEL {}", label), uids, |set| {
+        client.uid_store(set, &cmd).map(|_| ())
     })
-    .map(|_| ())
-    .map_err(|e| eyre!("{} | subject: {}", e, subject))
+}
+
+/// Mark every message in `uids` as deleted by adding the `\Deleted` flag.
+/// Includes retry logic for transient errors and rate limiting.
+pub fn mark_deleted<T>(client: &mut Session<T>, uids: &[u32]) -> Result<()>
+where
+    T: Read + Write,
+{
+    with_batch_retry("DELETE", uids, |set| {
+        client.uid_store(set, "+FLAGS.SILENT (\\Deleted)").map(|_| ())
+    })
+}
+
+/// Escape a label for use inside a quoted IMAP string.
+fn quote_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// "Move" messages by moving them server-side from the selected mailbox →
+/// `label`. Uses the UID MOVE extension (Gmail supports it), so you never
+/// have to manually remove "INBOX" yourself.
+/// The destination must already exist.
+/// Includes retry logic for transient errors and rate limiting.
+pub fn uid_move_gmail<T>(client: &mut Session<T>, uids: &[u32], label: &str) -> Result<()>
+where
+    T: Read + Write,
+{
+    // this sends: `a1 UID MOVE 12345:12350,12377 "Purgatory"` with retry logic
+    with_batch_retry(&format!("MOVE → {}", label), uids, |set| client.uid_mv(set, label))
 }
 
 #[cfg(test)]
@@ -282,6 +465,135 @@ mod tests {
         assert!(parse_days("abc").is_err()); // not a number
     }
 
+    #[test]
+    fn test_compress_uid_set() {
+        assert_eq!(compress_uid_set(&[1, 2, 3, 4, 5, 9, 12, 13, 14]), "1:5,9,12:14");
+        assert_eq!(compress_uid_set(&[7]), "7");
+        assert_eq!(compress_uid_set(&[]), "");
+    }
+
+    #[test]
+    fn test_compress_uid_set_at_u32_max() {
+        assert_eq!(compress_uid_set(&[u32::MAX]), "4294967295");
+        assert_eq!(compress_uid_set(&[u32::MAX - 1, u32::MAX]), "4294967294:4294967295");
+        assert_eq!(compress_uid_set(&[1, u32::MAX]), "1,4294967295");
+    }
+
+    fn not_found() -> imap::Error {
+        imap::Error::Io(std::io::Error::other("no such message"))
+    }
+
+    #[test]
+    fn test_batch_retry_chunks_uid_sets() {
+        let uids: Vec<u32> = (1..=MAX_BATCH as u32 + 2).rev().collect();
+        let mut sets = Vec::new();
+        with_batch_retry("TEST", &uids, |set| {
+            sets.push(set.to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            sets,
+            vec![
+                format!("1:{}", MAX_BATCH),
+                format!("{}:{}", MAX_BATCH + 1, MAX_BATCH + 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_retry_falls_back_to_single_uids() {
+        let mut sets = Vec::new();
+        let err = with_batch_retry("TEST", &[1, 2, 3, 4], |set| {
+            sets.push(set.to_string());
+            if set == "1:4" || set == "3" {
+                Err(not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+        assert_eq!(sets, vec!["1:4", "1", "2", "3", "4"]);
+        assert!(err.to_string().contains("failed for UIDs 3 (1 of 4)"));
+        let batch = err.downcast_ref::<BatchError>().unwrap();
+        assert_eq!(batch.failed, vec![3]);
+        assert_eq!(classify_report(&err), ImapErrorKind::MessageNotFound);
+    }
+
+    #[test]
+    fn test_batch_retry_stops_on_connection_loss() {
+        let uids: Vec<u32> = (1..=MAX_BATCH as u32 + 2).collect();
+        let mut sets = Vec::new();
+        let err = with_batch_retry("TEST", &uids, |set| {
+            sets.push(set.to_string());
+            Err(imap::Error::ConnectionLost)
+        })
+        .unwrap_err();
+        // no per-UID fallback and no later chunk
+        assert_eq!(sets, vec![format!("1:{}", MAX_BATCH)]);
+        let batch = err.downcast_ref::<BatchError>().unwrap();
+        assert_eq!(batch.failed, uids);
+        assert_eq!(classify_report(&err), ImapErrorKind::ConnectionLost);
+
+        // lost halfway through the per-UID fallback
+        let mut sets = Vec::new();
+        let err = with_batch_retry("TEST", &[1, 2, 3, 4], |set| {
+            sets.push(set.to_string());
+            match set {
+                "1:4" => Err(not_found()),
+                "2" => Err(imap::Error::Io(std::io::Error::other("not authenticated"))),
+                _ => Ok(()),
+            }
+        })
+        .unwrap_err();
+        assert_eq!(sets, vec!["1:4", "1", "2"]);
+        assert_eq!(err.downcast_ref::<BatchError>().unwrap().failed, vec![2, 3, 4]);
+        assert_eq!(classify_report(&err), ImapErrorKind::ConnectionLost);
+    }
+
+    #[test]
+    fn test_is_all_mail() {
+        assert!(is_all_mail("[Gmail]/All Mail"));
+        assert!(is_all_mail("[Google Mail]/All Mail"));
+        assert!(!is_all_mail("INBOX"));
+        assert!(!is_all_mail("All Mail"));
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90s").unwrap(), StdDuration::from_secs(90));
+        assert_eq!(parse_interval("15m").unwrap(), StdDuration::from_secs(15 * 60));
+        assert_eq!(parse_interval(" 1h ").unwrap(), StdDuration::from_secs(60 * 60));
+        assert_eq!(parse_interval("1d").unwrap(), StdDuration::from_secs(24 * 60 * 60));
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("10").is_err());
+        assert!(parse_interval("h").is_err());
+        assert!(parse_interval("").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("200KB").unwrap(), 200 * 1024);
+        assert_eq!(parse_size(" 5 mb ").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("5TB").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("1.5MB").is_err());
+    }
+
+    #[test]
+    fn test_classify_report_uses_imap_error() {
+        let report = eyre::Report::new(imap::Error::ConnectionLost);
+        assert_eq!(classify_report(&report), ImapErrorKind::ConnectionLost);
+
+        let wrapped = eyre!("MOVE failed for UID 7 after 1 attempts: [CONNECTION_LOST] Io(...)");
+        assert_eq!(classify_report(&wrapped), ImapErrorKind::ConnectionLost);
+
+        let other = eyre!("No address fields");
+        assert_eq!(classify_report(&other), ImapErrorKind::Unknown);
+    }
+
     #[test]
     fn test_extract_gmail_extension() {
         let raw = "Fetch { uid: Some(12345), X-GM-THRID 1852322999435237597, X-GM-MSGID 1852322999435237598 }";
@@ -0,0 +1,561 @@
+// tests/harness/imap_server.rs
+//
+// IDLE-capable stand-in IMAP server for testing.
+// Speaks just enough IMAP over a local TCP socket for the production
+// `imap::Session` client, backed by a shared VirtualMailbox. Used to drive
+// daemon mode end-to-end, including IDLE push and dropped connections, and
+// CONDSTORE/QRESYNC change tracking.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
+use imap::Session;
+
+use crate::harness::virtual_mailbox::{MailboxMessage, VirtualMailbox};
+
+/// How often an idling connection checks the mailbox for new mail.
+const IDLE_POLL: Duration = Duration::from_millis(20);
+
+const CAPABILITIES: &str = "IMAP4rev1 IDLE MOVE ENABLE CONDSTORE QRESYNC X-GM-EXT-1";
+
+/// Counters and logs shared between the server handle and its connections.
+#[derive(Default)]
+struct ServerState {
+    connections: Mutex<Vec<TcpStream>>,
+    accepted: AtomicUsize,
+    exists_pushed: AtomicUsize,
+    commands: Mutex<Vec<String>>,
+    /// UIDs that STORE and MOVE refuse, failing the whole command.
+    rejected: Mutex<Vec<u32>>,
+    /// How many of the next UID FETCH commands fail with NO.
+    failing_fetches: AtomicUsize,
+}
+
+/// A local IMAP server over a VirtualMailbox.
+/// Runs until the test process exits.
+pub struct StandInServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+}
+
+impl StandInServer {
+    /// Bind to an ephemeral local port and start accepting connections.
+    pub fn start(mailbox: Arc<RwLock<VirtualMailbox>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind stand-in server");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState::default());
+
+        let accept_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                accept_state.accepted.fetch_add(1, Ordering::SeqCst);
+                if let Ok(clone) = stream.try_clone() {
+                    accept_state.connections.lock().unwrap().push(clone);
+                }
+                let mailbox = Arc::clone(&mailbox);
+                let state = Arc::clone(&accept_state);
+                thread::spawn(move || {
+                    let _ = Connection::new(stream, mailbox, state).and_then(|mut c| c.serve());
+                });
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    /// Open and log in a new session, the way `main` does against a real server.
+    pub fn connect(&self) -> Result<Session<TcpStream>> {
+        let stream = TcpStream::connect(self.addr).map_err(|e| eyre!("Failed to connect to {}: {}", self.addr, e))?;
+        let mut client = imap::Client::new(stream);
+        client.read_greeting()?;
+        client
+            .login("user", "password")
+            .map_err(|(e, _)| eyre!("IMAP login failed: {}", e))
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connection_count(&self) -> usize {
+        self.state.accepted.load(Ordering::SeqCst)
+    }
+
+    /// Number of `EXISTS` notifications pushed to idling clients.
+    pub fn exists_pushed(&self) -> usize {
+        self.state.exists_pushed.load(Ordering::SeqCst)
+    }
+
+    /// Every command received so far, without its tag.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.commands.lock().unwrap().clone()
+    }
+
+    /// Make every STORE or MOVE whose UID set includes `uid` fail with NO,
+    /// as if the message had been expunged by another client.
+    pub fn reject_uid(&self, uid: u32) {
+        self.state.rejected.lock().unwrap().push(uid);
+    }
+
+    /// Make the next `count` UID FETCH commands fail with NO, as a server
+    /// with a struggling backend would.
+    pub fn fail_fetches(&self, count: usize) {
+        self.state.failing_fetches.store(count, Ordering::SeqCst);
+    }
+
+    /// Abruptly close every open connection, as a network drop would.
+    pub fn drop_connections(&self) {
+        for stream in self.state.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Poll `condition` until it holds or `timeout` elapses.
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    condition()
+}
+
+/// One client connection.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    mailbox: Arc<RwLock<VirtualMailbox>>,
+    state: Arc<ServerState>,
+    selected: String,
+    /// Newest UID the client has been told about (via SELECT or EXISTS).
+    announced: u32,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, mailbox: Arc<RwLock<VirtualMailbox>>, state: Arc<ServerState>) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            mailbox,
+            state,
+            selected: "INBOX".to_string(),
+            announced: 0,
+        })
+    }
+
+    fn serve(&mut self) -> io::Result<()> {
+        self.send(&format!("* OK [CAPABILITY {}] stand-in ready", CAPABILITIES))?;
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            let (tag, command) = line.split_once(' ').unwrap_or((line, ""));
+            self.state.commands.lock().unwrap().push(command.to_string());
+
+            let (verb, args) = command.split_once(' ').unwrap_or((command, ""));
+            match verb.to_uppercase().as_str() {
+                "CAPABILITY" => {
+                    self.send(&format!("* CAPABILITY {}", CAPABILITIES))?;
+                    self.ok(tag, "CAPABILITY completed")?;
+                }
+                "ENABLE" => {
+                    self.send(&format!("* ENABLED {}", args))?;
+                    self.ok(tag, "ENABLE completed")?;
+                }
+                "LOGIN" => self.ok(tag, "LOGIN completed")?,
+                "SELECT" => self.select(tag, args)?,
+                "LIST" => self.list(tag)?,
+                "CREATE" => {
+                    self.mailbox.write().unwrap().create_label(&unquote(args));
+                    self.ok(tag, "CREATE completed")?;
+                }
+                "FETCH" => self.fetch(tag, args, false)?,
+                "UID" => {
+                    let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+                    match sub.to_uppercase().as_str() {
+                        "SEARCH" => self.search(tag, rest)?,
+                        "FETCH" if self.take_fetch_failure() => {
+                            self.send(&format!("{} NO [SERVERBUG] Fetch failed", tag))?
+                        }
+                        "FETCH" => self.fetch(tag, rest, true)?,
+                        "STORE" => self.store(tag, rest)?,
+                        "MOVE" => self.move_message(tag, rest)?,
+                        _ => self.send(&format!("{} BAD unsupported UID command", tag))?,
+                    }
+                }
+                "IDLE" => self.idle(tag)?,
+                "LOGOUT" => {
+                    self.send("* BYE logging out")?;
+                    return self.ok(tag, "LOGOUT completed");
+                }
+                _ => self.send(&format!("{} BAD unsupported command", tag))?,
+            }
+        }
+    }
+
+    /// Use up one of the failures armed by `fail_fetches`, if any are left.
+    fn take_fetch_failure(&self) -> bool {
+        self.state
+            .failing_fetches
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()
+    }
+
+    fn ok(&mut self, tag: &str, text: &str) -> io::Result<()> {
+        self.send(&format!("{} OK {}", tag, text))
+    }
+
+    /// Whether any of `uids` has been marked rejected by the test.
+    fn rejects(&self, uids: &[u32]) -> bool {
+        let rejected = self.state.rejected.lock().unwrap();
+        uids.iter().any(|uid| rejected.contains(uid))
+    }
+
+    /// Messages in the selected folder, in sequence-number order.
+    fn folder_messages(&self) -> Vec<MailboxMessage> {
+        let mailbox = self.mailbox.read().unwrap();
+        let mut messages: Vec<MailboxMessage> = mailbox.folder_messages(&self.selected).into_iter().cloned().collect();
+        messages.sort_by_key(|m| m.uid);
+        messages
+    }
+
+    fn select(&mut self, tag: &str, args: &str) -> io::Result<()> {
+        self.selected = unquote(args);
+        let messages = self.folder_messages();
+        let count = messages.len();
+        self.announced = messages.last().map(|m| m.uid).unwrap_or(0);
+        let (uid_validity, uid_next, highest_modseq) = {
+            let mailbox = self.mailbox.read().unwrap();
+            (mailbox.uid_validity(), mailbox.uid_next(), mailbox.highest_modseq())
+        };
+        self.send("* FLAGS (\\Seen \\Deleted \\Flagged)")?;
+        self.send(&format!("* {} EXISTS", count))?;
+        self.send(&format!("* OK [UIDVALIDITY {}] UIDs valid", uid_validity))?;
+        self.send(&format!("* OK [UIDNEXT {}] Predicted next UID", uid_next))?;
+        self.send(&format!("* OK [HIGHESTMODSEQ {}] Highest", highest_modseq))?;
+        self.ok(tag, "[READ-WRITE] SELECT completed")
+    }
+
+    fn list(&mut self, tag: &str) -> io::Result<()> {
+        let listed = self.mailbox.read().unwrap().list_mailboxes();
+        for (name, special_use) in listed {
+            let attributes = match special_use {
+                Some(attribute) => format!("\\HasNoChildren {}", attribute),
+                None => "\\HasNoChildren".to_string(),
+            };
+            self.send(&format!("* LIST ({}) \"/\" \"{}\"", attributes, name))?;
+        }
+        self.ok(tag, "LIST completed")
+    }
+
+    fn search(&mut self, tag: &str, args: &str) -> io::Result<()> {
+        let uids: Vec<u32> = self.folder_messages().iter().map(|m| m.uid).collect();
+        let matched: Vec<u32> = match args.to_uppercase().strip_prefix("UID ") {
+            Some(range) => {
+                let from: u32 = range.split(':').next().and_then(|n| n.parse().ok()).unwrap_or(1);
+                let found: Vec<u32> = uids.iter().copied().filter(|u| *u >= from).collect();
+                // RFC 3501: `n:*` always includes the highest UID, even below n
+                match (found.is_empty(), uids.last()) {
+                    (true, Some(last)) => vec![*last],
+                    _ => found,
+                }
+            }
+            None => uids,
+        };
+        let listed: Vec<String> = matched.iter().map(|u| u.to_string()).collect();
+        self.send(format!("* SEARCH {}", listed.join(" ")).trim_end())?;
+        self.ok(tag, "SEARCH completed")
+    }
+
+    fn fetch(&mut self, tag: &str, args: &str, by_uid: bool) -> io::Result<()> {
+        if let Some((_, modifier)) = args.to_uppercase().split_once("(CHANGEDSINCE ") {
+            let since = modifier
+                .split(|c: char| !c.is_ascii_digit())
+                .next()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(0);
+            return self.fetch_changed(tag, since, modifier.contains("VANISHED"));
+        }
+
+        let wanted = parse_set(args.split(' ').next().unwrap_or(""));
+        if args.to_uppercase().contains("[TEXT]") {
+            return self.fetch_bodies(tag, &wanted, args.to_uppercase().contains("BODY[TEXT]"), by_uid);
+        }
+
+        // Every other FETCH is answered with the full item list the client asks for
+        for (index, message) in self.folder_messages().iter().enumerate() {
+            let seq = index as u32 + 1;
+            let id = if by_uid { message.uid } else { seq };
+            if !wanted.contains(&id) {
+                continue;
+            }
+            let header = message.raw_header();
+            self.writer.write_all(
+                format!(
+                    "* {} FETCH (UID {} FLAGS ({}) INTERNALDATE \"{}\" X-GM-LABELS ({}) RFC822.SIZE {} BODYSTRUCTURE {} RFC822.HEADER {{{}}}\r\n",
+                    seq,
+                    message.uid,
+                    message.flags.iter().cloned().collect::<Vec<_>>().join(" "),
+                    internal_date(&message.date),
+                    gmail_labels(message),
+                    message.size(),
+                    message.body_structure(),
+                    header.len()
+                )
+                .as_bytes(),
+            )?;
+            self.writer.write_all(&header)?;
+            self.send(")")?;
+        }
+        self.ok(tag, "FETCH completed")
+    }
+
+    /// `FETCH set (UID BODY[.PEEK][HEADER] BODY[.PEEK][TEXT])`. Without
+    /// PEEK, reading the text marks the message `\Seen`, as RFC 3501 says.
+    fn fetch_bodies(&mut self, tag: &str, wanted: &[u32], marks_seen: bool, by_uid: bool) -> io::Result<()> {
+        for (index, message) in self.folder_messages().iter().enumerate() {
+            let seq = index as u32 + 1;
+            if !wanted.contains(&if by_uid { message.uid } else { seq }) {
+                continue;
+            }
+            if marks_seen {
+                self.mailbox.write().unwrap().add_flag(message.uid, "\\Seen");
+            }
+            let header = message.raw_header();
+            self.writer.write_all(
+                format!(
+                    "* {} FETCH (UID {} BODY[HEADER] {{{}}}\r\n",
+                    seq,
+                    message.uid,
+                    header.len()
+                )
+                .as_bytes(),
+            )?;
+            self.writer.write_all(&header)?;
+            self.writer
+                .write_all(format!(" BODY[TEXT] {{{}}}\r\n", message.body.len()).as_bytes())?;
+            self.writer.write_all(message.body.as_bytes())?;
+            self.send(")")?;
+        }
+        self.ok(tag, "FETCH completed")
+    }
+
+    /// `UID FETCH 1:* (...) (CHANGEDSINCE n [VANISHED])`: flags and labels of
+    /// messages changed after `since`, plus, with `vanished`, the UIDs that
+    /// left the selected folder since then.
+    fn fetch_changed(&mut self, tag: &str, since: u64, vanished: bool) -> io::Result<()> {
+        let changed: Vec<MailboxMessage> = self
+            .mailbox
+            .read()
+            .unwrap()
+            .changed_since(since)
+            .into_iter()
+            .cloned()
+            .collect();
+        let (present, gone): (Vec<&MailboxMessage>, Vec<&MailboxMessage>) =
+            changed.iter().partition(|m| m.in_folder(&self.selected));
+
+        if vanished && !gone.is_empty() {
+            let uids: Vec<String> = gone.iter().map(|m| m.uid.to_string()).collect();
+            self.send(&format!("* VANISHED (EARLIER) {}", uids.join(",")))?;
+        }
+        let folder = self.folder_messages();
+        for message in present {
+            let seq = folder.iter().position(|m| m.uid == message.uid).unwrap_or(0) + 1;
+            self.send(&format!(
+                "* {} FETCH (UID {} MODSEQ ({}) FLAGS ({}) X-GM-LABELS ({}))",
+                seq,
+                message.uid,
+                message.modseq,
+                message.flags.iter().cloned().collect::<Vec<_>>().join(" "),
+                gmail_labels(message)
+            ))?;
+        }
+        self.ok(tag, "FETCH completed")
+    }
+
+    fn store(&mut self, tag: &str, args: &str) -> io::Result<()> {
+        let mut parts = args.splitn(3, ' ');
+        let uids = parse_set(parts.next().unwrap_or(""));
+        let item = parts.next().unwrap_or("").to_uppercase();
+        let values = parse_list(parts.next().unwrap_or(""));
+        if self.rejects(&uids) {
+            return self.send(&format!("{} NO [NONEXISTENT] No such message", tag));
+        }
+
+        let mut mailbox = self.mailbox.write().unwrap();
+        for uid in uids {
+            for value in &values {
+                match (item.as_str(), value.as_str()) {
+                    ("+FLAGS", "\\Deleted") | ("+FLAGS.SILENT", "\\Deleted") => {
+                        mailbox.delete_message(uid);
+                    }
+                    (item, _) if item.starts_with("+X-GM-LABELS") => {
+                        mailbox.add_label(uid, value);
+                    }
+                    (item, _) if item.starts_with("-X-GM-LABELS") => {
+                        mailbox.remove_label(uid, value);
+                    }
+                    (item, _) if item.starts_with("+FLAGS") => {
+                        mailbox.add_flag(uid, value);
+                    }
+                    (item, _) if item.starts_with("-FLAGS") => {
+                        mailbox.remove_flag(uid, value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        drop(mailbox);
+        self.ok(tag, "STORE completed")
+    }
+
+    fn move_message(&mut self, tag: &str, args: &str) -> io::Result<()> {
+        let (set, destination) = args.split_once(' ').unwrap_or((args, ""));
+        let destination = unquote(destination);
+        let uids = parse_set(set);
+        if self.rejects(&uids) {
+            return self.send(&format!("{} NO [NONEXISTENT] No such message", tag));
+        }
+        let mut mailbox = self.mailbox.write().unwrap();
+        for uid in uids {
+            mailbox.move_message(uid, &self.selected, &destination);
+        }
+        drop(mailbox);
+        self.ok(tag, "MOVE completed")
+    }
+
+    /// Hold the connection in IDLE, pushing `EXISTS` whenever the selected
+    /// folder holds mail the client has not been told about, until the
+    /// client sends `DONE`.
+    fn idle(&mut self, tag: &str) -> io::Result<()> {
+        self.send("+ idling")?;
+
+        self.reader.get_ref().set_read_timeout(Some(IDLE_POLL))?;
+        let mut line = String::new();
+        let result = loop {
+            match self.reader.read_line(&mut line) {
+                Ok(0) => break Err(io::Error::new(ErrorKind::UnexpectedEof, "client went away")),
+                Ok(_) if line.ends_with('\n') => {
+                    if line.trim_end().eq_ignore_ascii_case("DONE") {
+                        break Ok(());
+                    }
+                    line.clear();
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let messages = self.folder_messages();
+                    let latest = messages.last().map(|m| m.uid).unwrap_or(0);
+                    if latest > self.announced {
+                        self.announced = latest;
+                        self.state.exists_pushed.fetch_add(1, Ordering::SeqCst);
+                        self.send(&format!("* {} EXISTS", messages.len()))?;
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.reader.get_ref().set_read_timeout(None)?;
+        result?;
+        self.ok(tag, "IDLE terminated")
+    }
+}
+
+/// Parse a sequence set like `1,3,5:7` into its members.
+fn parse_set(set: &str) -> Vec<u32> {
+    set.split(',')
+        .flat_map(|part| match part.split_once(':') {
+            Some((lo, hi)) => {
+                let lo: u32 = lo.parse().unwrap_or(1);
+                let hi: u32 = hi.parse().unwrap_or(u32::MAX);
+                (lo..=hi.min(lo.saturating_add(10_000))).collect::<Vec<_>>()
+            }
+            None => part.parse().into_iter().collect(),
+        })
+        .collect()
+}
+
+/// Parse a parenthesized list of atoms and quoted strings.
+fn parse_list(list: &str) -> Vec<String> {
+    let inner = list.trim().trim_start_matches('(').trim_end_matches(')');
+    let mut values = Vec::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                }
+                values.push(value);
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                values.push(value);
+            }
+        }
+    }
+    values
+}
+
+/// Strip IMAP quoting from a mailbox name.
+fn unquote(name: &str) -> String {
+    parse_list(name).into_iter().next().unwrap_or_default()
+}
+
+/// Render a message date (RFC 3339 or RFC 2822) as an IMAP INTERNALDATE.
+fn internal_date(date: &str) -> String {
+    let parsed = DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_rfc2822(date))
+        .map(|d| d.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now());
+    parsed.format("%d-%b-%Y %H:%M:%S %z").to_string()
+}
+
+/// Render a message's labels as Gmail does: system labels as atoms,
+/// everything else quoted.
+fn gmail_labels(message: &MailboxMessage) -> String {
+    let mut labels: Vec<String> = message
+        .labels
+        .iter()
+        .map(|l| {
+            if l.starts_with('\\') {
+                l.clone()
+            } else {
+                format!("\"{}\"", l)
+            }
+        })
+        .collect();
+    labels.sort();
+    labels.join(" ")
+}
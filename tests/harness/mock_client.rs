@@ -3,7 +3,14 @@
 // Mock IMAP client for testing.
 // Records all actions for verification and operates against a VirtualMailbox.
 
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use eyre::{eyre, Result};
+use imap_filter::client_ops::{ChangeTracking, LabelUpdate, MailboxChanges, MailboxInfo, SpecialUse};
+use imap_filter::message::{Message, MimePart};
+use imap_filter::{IMAPClientOps, MailboxStatus};
 
 use crate::harness::virtual_clock::VirtualClock;
 use crate::harness::virtual_mailbox::{MailboxMessage, VirtualMailbox};
@@ -58,11 +65,17 @@ impl RecordedAction {
 
 /// Mock IMAP client for testing.
 /// Operates against a VirtualMailbox and records all actions for verification.
+/// Clones share the same mailbox and action log.
+#[derive(Clone)]
 pub struct MockIMAPClient {
     mailbox: Arc<RwLock<VirtualMailbox>>,
     actions: Arc<RwLock<Vec<RecordedAction>>>,
     current_folder: String,
     clock: VirtualClock,
+    change_tracking: ChangeTracking,
+    header_fetches: Arc<RwLock<Vec<u32>>>,
+    change_fetches: Arc<RwLock<Vec<u64>>>,
+    body_fetches: Arc<RwLock<Vec<u32>>>,
 }
 
 impl MockIMAPClient {
@@ -73,9 +86,20 @@ impl MockIMAPClient {
             actions: Arc::new(RwLock::new(Vec::new())),
             current_folder: "INBOX".to_string(),
             clock,
+            change_tracking: ChangeTracking::Unavailable,
+            header_fetches: Arc::new(RwLock::new(Vec::new())),
+            change_fetches: Arc::new(RwLock::new(Vec::new())),
+            body_fetches: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
+    /// Advertise CONDSTORE or QRESYNC, so `enable_change_tracking` succeeds
+    /// and SELECT reports HIGHESTMODSEQ.
+    pub fn with_change_tracking(mut self, tracking: ChangeTracking) -> Self {
+        self.change_tracking = tracking;
+        self
+    }
+
     /// Get the current virtual time.
     pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
         self.clock.now()
@@ -101,7 +125,7 @@ impl MockIMAPClient {
     pub fn search_all(&self) -> Result<Vec<u32>, String> {
         let mailbox = self.mailbox.read().unwrap();
         let uids: Vec<u32> = mailbox
-            .get_messages_with_label(&self.current_folder)
+            .folder_messages(&self.current_folder)
             .iter()
             .map(|m| m.uid)
             .collect();
@@ -112,7 +136,7 @@ impl MockIMAPClient {
     pub fn fetch_messages(&self) -> Result<Vec<MailboxMessage>, String> {
         let mailbox = self.mailbox.read().unwrap();
         let messages: Vec<MailboxMessage> = mailbox
-            .get_messages_with_label(&self.current_folder)
+            .folder_messages(&self.current_folder)
             .into_iter()
             .cloned()
             .collect();
@@ -257,6 +281,23 @@ impl MockIMAPClient {
         self.actions.read().unwrap().contains(action)
     }
 
+    /// UIDs whose headers were fetched through `IMAPClientOps::fetch_messages`,
+    /// in request order.
+    pub fn header_fetches(&self) -> Vec<u32> {
+        self.header_fetches.read().unwrap().clone()
+    }
+
+    /// MODSEQs passed to `IMAPClientOps::fetch_changes`, in request order.
+    pub fn change_fetches(&self) -> Vec<u64> {
+        self.change_fetches.read().unwrap().clone()
+    }
+
+    /// UIDs whose bodies were fetched through `IMAPClientOps::fetch_bodies`,
+    /// in request order.
+    pub fn body_fetches(&self) -> Vec<u32> {
+        self.body_fetches.read().unwrap().clone()
+    }
+
     /// Get all Star actions.
     pub fn get_star_actions(&self) -> Vec<RecordedAction> {
         self.actions
@@ -302,6 +343,164 @@ impl MockIMAPClient {
     }
 }
 
+/// Lets the production `IMAPFilter` engine drive the mock directly.
+impl IMAPClientOps for MockIMAPClient {
+    fn select_mailbox(&mut self, name: &str) -> Result<MailboxStatus> {
+        MockIMAPClient::select(self, name).map_err(|e| eyre!(e))?;
+        let mailbox = self.mailbox.read().unwrap();
+        Ok(MailboxStatus {
+            exists: mailbox.folder_messages(name).len() as u32,
+            uid_validity: Some(mailbox.uid_validity()),
+            uid_next: Some(mailbox.uid_next()),
+            highest_modseq: match self.change_tracking {
+                ChangeTracking::Unavailable => None,
+                _ => Some(mailbox.highest_modseq()),
+            },
+        })
+    }
+
+    fn search_all(&mut self) -> Result<Vec<u32>> {
+        let mut uids = MockIMAPClient::search_all(self).map_err(|e| eyre!(e))?;
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    fn search_since(&mut self, uid: u32) -> Result<Vec<u32>> {
+        let uids = IMAPClientOps::search_all(self)?;
+        Ok(uids.into_iter().filter(|u| *u > uid).collect())
+    }
+
+    fn wait_for_changes(&mut self, timeout: Duration) -> Result<bool> {
+        // The mock has no server push: the wait simply passes in virtual time
+        // and reports no change. The IDLE path is covered by `StandInServer`.
+        self.clock
+            .advance(chrono::Duration::from_std(timeout).map_err(|e| eyre!(e))?);
+        Ok(false)
+    }
+
+    fn enable_change_tracking(&mut self) -> Result<ChangeTracking> {
+        Ok(self.change_tracking)
+    }
+
+    fn fetch_changes(&mut self, since_modseq: u64, vanished: bool) -> Result<MailboxChanges> {
+        self.change_fetches.write().unwrap().push(since_modseq);
+        let mailbox = self.mailbox.read().unwrap();
+        let (present, gone): (Vec<&MailboxMessage>, Vec<&MailboxMessage>) = mailbox
+            .changed_since(since_modseq)
+            .into_iter()
+            .partition(|m| m.in_folder(&self.current_folder));
+        Ok(MailboxChanges {
+            updated: present
+                .iter()
+                .map(|m| LabelUpdate {
+                    uid: m.uid,
+                    labels: m.labels.iter().chain(m.flags.iter()).cloned().collect(),
+                })
+                .collect(),
+            vanished: vanished.then(|| gone.iter().map(|m| m.uid).collect()),
+        })
+    }
+
+    fn fetch_messages(&mut self, uids: &[u32]) -> Result<Vec<Message>> {
+        self.header_fetches.write().unwrap().extend_from_slice(uids);
+        let mailbox = self.mailbox.read().unwrap();
+        let messages = uids
+            .iter()
+            .filter_map(|uid| mailbox.get_message(*uid))
+            .filter(|m| !m.deleted)
+            .map(|m| {
+                let labels = m.labels.iter().chain(m.flags.iter()).cloned().collect();
+                let mut msg = Message::new(
+                    m.uid,
+                    m.seq,
+                    m.raw_header(),
+                    labels,
+                    m.date.parse().ok(),
+                    m.thread_id.clone(),
+                );
+                msg.size = m.size();
+                // The structure a server would report in BODYSTRUCTURE
+                msg.parts.push(MimePart {
+                    mime_type: m.text_type(),
+                    filename: None,
+                    size: m.body.len() as u64,
+                    attachment: false,
+                });
+                msg.parts.extend(m.parts.iter().map(|p| MimePart {
+                    mime_type: p.mime_type.clone(),
+                    filename: p.filename.clone(),
+                    size: p.size,
+                    attachment: p.filename.is_some(),
+                }));
+                msg
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    fn fetch_bodies(&mut self, uids: &[u32]) -> Result<HashMap<u32, Vec<u8>>> {
+        self.body_fetches.write().unwrap().extend_from_slice(uids);
+        let mailbox = self.mailbox.read().unwrap();
+        Ok(uids
+            .iter()
+            .filter_map(|uid| mailbox.get_message(*uid))
+            .map(|m| {
+                let mut raw = m.raw_header();
+                raw.extend_from_slice(m.body.as_bytes());
+                (m.uid, raw)
+            })
+            .collect())
+    }
+
+    fn add_label(&mut self, uids: &[u32], label: &str) -> Result<()> {
+        for &uid in uids {
+            self.uid_store_add_flags(uid, label).map_err(|e| eyre!(e))?;
+        }
+        Ok(())
+    }
+
+    fn remove_label(&mut self, uids: &[u32], label: &str) -> Result<()> {
+        for &uid in uids {
+            self.uid_store_remove_flags(uid, label).map_err(|e| eyre!(e))?;
+        }
+        Ok(())
+    }
+
+    fn move_messages(&mut self, uids: &[u32], destination: &str) -> Result<()> {
+        for &uid in uids {
+            self.uid_move(uid, destination).map_err(|e| eyre!(e))?;
+        }
+        Ok(())
+    }
+
+    fn delete_messages(&mut self, uids: &[u32]) -> Result<()> {
+        for &uid in uids {
+            self.uid_store_add_flags(uid, "\\Deleted").map_err(|e| eyre!(e))?;
+        }
+        Ok(())
+    }
+
+    fn list_mailboxes(&mut self) -> Result<Vec<MailboxInfo>> {
+        let mailbox = self.mailbox.read().unwrap();
+        Ok(mailbox
+            .list_mailboxes()
+            .into_iter()
+            .map(|(name, special_use)| MailboxInfo {
+                name,
+                special_use: special_use.and_then(SpecialUse::parse),
+            })
+            .collect())
+    }
+
+    fn create_mailbox(&mut self, name: &str) -> Result<()> {
+        MockIMAPClient::ensure_label(self, name).map_err(|e| eyre!(e))
+    }
+
+    fn logout(&mut self) -> Result<()> {
+        MockIMAPClient::logout(self).map_err(|e| eyre!(e))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -582,6 +781,22 @@ mod tests {
         assert_eq!(client.get_delete_actions().len(), 1);
     }
 
+    #[test]
+    fn test_trait_fetch_builds_messages() {
+        let (mut client, mailbox) = setup_test_client();
+        let uid = add_test_message(&mailbox, "Trait Fetch");
+
+        let uids = IMAPClientOps::search_all(&mut client).unwrap();
+        assert_eq!(uids, vec![uid]);
+
+        let messages = IMAPClientOps::fetch_messages(&mut client, &uids).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].uid, uid);
+        assert_eq!(messages[0].subject, "Trait Fetch");
+        assert_eq!(messages[0].from[0].email, "sender@example.com");
+        assert_eq!(messages[0].to[0].email, "recipient@example.com");
+    }
+
     #[test]
     fn test_client_with_virtual_clock() {
         let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
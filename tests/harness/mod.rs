@@ -4,13 +4,15 @@
 // Provides in-memory IMAP simulation and time control for testing.
 
 pub mod fixtures;
+pub mod imap_server;
 pub mod mock_client;
 pub mod test_harness;
 pub mod virtual_clock;
 pub mod virtual_mailbox;
 
 pub use fixtures::{EmailFixture, FixtureError, FixtureLoader};
+pub use imap_server::{wait_until, StandInServer};
 pub use mock_client::{MockIMAPClient, RecordedAction};
 pub use test_harness::TestHarness;
 pub use virtual_clock::{Clock, RealClock, VirtualClock};
-pub use virtual_mailbox::{MailboxMessage, MoveRecord, VirtualMailbox};
+pub use virtual_mailbox::{MailboxMessage, MoveRecord, VirtualMailbox, ALL_MAIL, SPAM, TRASH};
@@ -3,8 +3,15 @@
 // High-level test harness combining all components.
 // Provides a convenient API for writing integration tests.
 
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
+use imap_filter::cfg::config::{load_config, Config};
+use imap_filter::client_ops::ChangeTracking;
+use imap_filter::plan::Plan;
+use imap_filter::sync_state::SyncState;
+use imap_filter::IMAPFilter;
+
 use crate::harness::fixtures::{EmailFixture, FixtureLoader};
 use crate::harness::mock_client::{MockIMAPClient, RecordedAction};
 use crate::harness::virtual_clock::VirtualClock;
@@ -50,6 +57,12 @@ impl TestHarness {
         }
     }
 
+    /// Have the mock client advertise CONDSTORE or QRESYNC.
+    pub fn with_change_tracking(mut self, tracking: ChangeTracking) -> Self {
+        self.client = self.client.with_change_tracking(tracking);
+        self
+    }
+
     // ===== Message Management =====
 
     /// Add a message directly to the mailbox.
@@ -110,6 +123,39 @@ impl TestHarness {
         self.clock.now()
     }
 
+    // ===== Running the Engine =====
+
+    /// Load a config from `tests/fixtures/configs`.
+    pub fn load_config(&self, name: &str) -> eyre::Result<Config> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join("configs")
+            .join(name);
+        load_config(&path)
+    }
+
+    /// Run the production `IMAPFilter` pipeline against the virtual mailbox,
+    /// evaluating TTLs against the virtual clock.
+    pub fn run_filters(&self, config: Config) -> eyre::Result<Plan> {
+        let mut filter = IMAPFilter::with_clock(self.client.clone(), config, self.clock.clone());
+        filter.execute()
+    }
+
+    /// Run the production pipeline incrementally, with sync state loaded from
+    /// (and saved back to) `state_path`.
+    pub fn run_filters_incremental(&self, config: Config, state_path: &Path) -> eyre::Result<Plan> {
+        let state = SyncState::load(state_path, "test-account")?;
+        let mut filter = IMAPFilter::with_clock(self.client.clone(), config, self.clock.clone()).with_sync_state(state);
+        filter.execute()
+    }
+
+    /// Run the production pipeline in dry-run mode; the mailbox is left untouched.
+    pub fn plan_filters(&self, config: Config) -> eyre::Result<Plan> {
+        let mut filter = IMAPFilter::with_clock(self.client.clone(), config, self.clock.clone());
+        filter.plan()
+    }
+
     // ===== Action Inspection =====
 
     /// Get all recorded actions.
@@ -5,6 +5,24 @@
 
 use std::collections::{HashMap, HashSet};
 
+/// Gmail's All Mail view: every message, whatever its labels.
+pub const ALL_MAIL: &str = "[Gmail]/All Mail";
+
+/// Gmail's Trash and Spam folders, listed with RFC 6154 special-use
+/// attributes alongside All Mail.
+pub const TRASH: &str = "[Gmail]/Trash";
+pub const SPAM: &str = "[Gmail]/Spam";
+const SPECIAL_USE: [(&str, &str); 3] = [(ALL_MAIL, "\\All"), (TRASH, "\\Trash"), (SPAM, "\\Junk")];
+
+/// A MIME part after the message text, described only as far as
+/// BODYSTRUCTURE reports it.
+#[derive(Debug, Clone)]
+pub struct VirtualPart {
+    pub mime_type: String,
+    pub filename: Option<String>,
+    pub size: u64,
+}
+
 /// Represents the state of a message in the virtual mailbox.
 #[derive(Debug, Clone)]
 pub struct MailboxMessage {
@@ -18,11 +36,19 @@ pub struct MailboxMessage {
     pub labels: HashSet<String>,
     pub flags: HashSet<String>,
     pub headers: HashMap<String, String>,
+    /// MIME headers and text following the header block, as an IMAP server
+    /// would return them for `BODY[TEXT]` (empty for header-only messages).
+    pub mime_headers: String,
+    pub body: String,
+    /// Parts after the text; with any, the message is multipart/mixed.
+    pub parts: Vec<VirtualPart>,
     pub message_id: Option<String>,
     pub in_reply_to: Option<String>,
     pub references: Vec<String>,
     pub thread_id: Option<String>,
     pub deleted: bool,
+    /// MODSEQ of the message's last change (CONDSTORE).
+    pub modseq: u64,
 }
 
 impl MailboxMessage {
@@ -39,14 +65,23 @@ impl MailboxMessage {
             labels: HashSet::new(),
             flags: HashSet::new(),
             headers: HashMap::new(),
+            mime_headers: String::new(),
+            body: String::new(),
+            parts: Vec::new(),
             message_id: None,
             in_reply_to: None,
             references: Vec::new(),
             thread_id: None,
             deleted: false,
+            modseq: 0,
         }
     }
 
+    /// Whether the message shows up when `folder` is selected.
+    pub fn in_folder(&self, folder: &str) -> bool {
+        !self.deleted && (folder == ALL_MAIL || self.labels.contains(folder))
+    }
+
     /// Builder method to add labels.
     pub fn with_labels(mut self, labels: &[&str]) -> Self {
         for label in labels {
@@ -55,6 +90,14 @@ impl MailboxMessage {
         self
     }
 
+    /// Builder method to set IMAP flags, e.g. `\Seen`.
+    pub fn with_flags(mut self, flags: &[&str]) -> Self {
+        for flag in flags {
+            self.flags.insert(flag.to_string());
+        }
+        self
+    }
+
     /// Builder method to set message ID (for threading).
     pub fn with_message_id(mut self, message_id: &str) -> Self {
         self.message_id = Some(message_id.to_string());
@@ -90,6 +133,121 @@ impl MailboxMessage {
         self.headers.insert(name.to_string(), value.to_string());
         self
     }
+
+    /// Builder method to set a plain-text body.
+    pub fn with_body(self, text: &str) -> Self {
+        self.with_mime_body("Content-Type: text/plain; charset=utf-8", text)
+    }
+
+    /// Builder method to set a body with its MIME headers (`Content-Type`
+    /// and, if encoded, `Content-Transfer-Encoding`, one per line).
+    pub fn with_mime_body(mut self, mime_headers: &str, body: &str) -> Self {
+        self.mime_headers = mime_headers.lines().map(|l| format!("{}\r\n", l)).collect();
+        self.body = body.to_string();
+        self
+    }
+
+    /// Builder method to attach a file (disposition `attachment`).
+    pub fn with_attachment(mut self, filename: &str, mime_type: &str, size: u64) -> Self {
+        self.parts.push(VirtualPart {
+            mime_type: mime_type.to_string(),
+            filename: Some(filename.to_string()),
+            size,
+        });
+        self
+    }
+
+    /// Builder method to add an unnamed inline part, e.g. `text/calendar`.
+    pub fn with_inline_part(mut self, mime_type: &str, size: u64) -> Self {
+        self.parts.push(VirtualPart {
+            mime_type: mime_type.to_string(),
+            filename: None,
+            size,
+        });
+        self
+    }
+
+    /// `type/subtype` of the message text, from its MIME headers.
+    pub fn text_type(&self) -> String {
+        self.mime_headers
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Type: "))
+            .and_then(|value| value.split(';').next())
+            .unwrap_or("text/plain")
+            .to_lowercase()
+    }
+
+    /// RFC822.SIZE: the header block, the text and every further part.
+    pub fn size(&self) -> u64 {
+        (self.raw_header().len() + self.body.len()) as u64 + self.parts.iter().map(|p| p.size).sum::<u64>()
+    }
+
+    /// The message's MIME structure in IMAP BODYSTRUCTURE syntax.
+    pub fn body_structure(&self) -> String {
+        let part = |mime_type: &str, filename: Option<&str>, size: u64| {
+            let (ty, subtype) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+            let params = filename.map_or("NIL".to_string(), |f| format!("(\"NAME\" \"{}\")", f));
+            // text/* parts carry a line count after the size
+            let lines = if ty.eq_ignore_ascii_case("text") { " 1" } else { "" };
+            let disposition = filename.map_or("NIL".to_string(), |f| {
+                format!("(\"ATTACHMENT\" (\"FILENAME\" \"{}\"))", f)
+            });
+            format!(
+                "(\"{}\" \"{}\" {} NIL NIL \"7BIT\" {}{} NIL {} NIL NIL)",
+                ty.to_uppercase(),
+                subtype.to_uppercase(),
+                params,
+                size,
+                lines,
+                disposition
+            )
+        };
+        let text = part(&self.text_type(), None, self.body.len() as u64);
+        if self.parts.is_empty() {
+            return text;
+        }
+        let rest: String = self
+            .parts
+            .iter()
+            .map(|p| part(&p.mime_type, p.filename.as_deref(), p.size))
+            .collect();
+        format!("({}{} \"MIXED\")", text, rest)
+    }
+
+    /// Render the message as a raw RFC 822 header block, as an IMAP server
+    /// would return it for `RFC822.HEADER`.
+    pub fn raw_header(&self) -> Vec<u8> {
+        const STRUCTURED: [&str; 6] = ["From", "To", "Cc", "Subject", "Message-ID", "In-Reply-To"];
+
+        let mut out = String::new();
+        if !self.from.is_empty() {
+            out.push_str(&format!("From: {}\r\n", self.from.join(", ")));
+        }
+        if !self.to.is_empty() {
+            out.push_str(&format!("To: {}\r\n", self.to.join(", ")));
+        }
+        if !self.cc.is_empty() {
+            out.push_str(&format!("Cc: {}\r\n", self.cc.join(", ")));
+        }
+        out.push_str(&format!("Subject: {}\r\n", self.subject));
+        if let Some(ref id) = self.message_id {
+            out.push_str(&format!("Message-ID: {}\r\n", id));
+        }
+        if let Some(ref parent) = self.in_reply_to {
+            out.push_str(&format!("In-Reply-To: {}\r\n", parent));
+        }
+        if !self.references.is_empty() {
+            out.push_str(&format!("References: {}\r\n", self.references.join(" ")));
+        }
+        for (name, value) in &self.headers {
+            if !STRUCTURED.contains(&name.as_str()) && name != "References" {
+                out.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        out.push_str(&self.mime_headers);
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
 }
 
 /// Record of a message move operation.
@@ -105,6 +263,8 @@ pub struct MoveRecord {
 pub struct VirtualMailbox {
     messages: HashMap<u32, MailboxMessage>,
     next_uid: u32,
+    uid_validity: u32,
+    highest_modseq: u64,
     labels: HashSet<String>,
     moves: Vec<MoveRecord>,
 }
@@ -122,6 +282,8 @@ impl VirtualMailbox {
         Self {
             messages: HashMap::new(),
             next_uid: 1,
+            uid_validity: 1,
+            highest_modseq: 1,
             labels,
             moves: Vec::new(),
         }
@@ -136,6 +298,7 @@ impl VirtualMailbox {
         message.seq = uid;
 
         self.messages.insert(uid, message);
+        self.touch(uid);
         uid
     }
 
@@ -162,10 +325,17 @@ impl VirtualMailbox {
             .collect()
     }
 
+    /// Messages visible when `folder` is selected. Folders are labels, except
+    /// All Mail, which holds everything.
+    pub fn folder_messages(&self, folder: &str) -> Vec<&MailboxMessage> {
+        self.messages.values().filter(|m| m.in_folder(folder)).collect()
+    }
+
     /// Add a label to a message.
     pub fn add_label(&mut self, uid: u32, label: &str) -> bool {
         if let Some(msg) = self.messages.get_mut(&uid) {
-            msg.labels.insert(label.to_string());
+            msg.labels.insert(folder_label(label).to_string());
+            self.touch(uid);
             true
         } else {
             false
@@ -175,7 +345,30 @@ impl VirtualMailbox {
     /// Remove a label from a message.
     pub fn remove_label(&mut self, uid: u32, label: &str) -> bool {
         if let Some(msg) = self.messages.get_mut(&uid) {
-            msg.labels.remove(label);
+            msg.labels.remove(folder_label(label));
+            self.touch(uid);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add a flag (e.g. `\Seen`) to a message.
+    pub fn add_flag(&mut self, uid: u32, flag: &str) -> bool {
+        if let Some(msg) = self.messages.get_mut(&uid) {
+            msg.flags.insert(flag.to_string());
+            self.touch(uid);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove a flag from a message.
+    pub fn remove_flag(&mut self, uid: u32, flag: &str) -> bool {
+        if let Some(msg) = self.messages.get_mut(&uid) {
+            msg.flags.remove(flag);
+            self.touch(uid);
             true
         } else {
             false
@@ -196,6 +389,7 @@ impl VirtualMailbox {
 
             // Ensure destination label exists
             self.labels.insert(to.to_string());
+            self.touch(uid);
 
             true
         } else {
@@ -208,6 +402,7 @@ impl VirtualMailbox {
         if let Some(msg) = self.messages.get_mut(&uid) {
             msg.deleted = true;
             msg.flags.insert("\\Deleted".to_string());
+            self.touch(uid);
             true
         } else {
             false
@@ -245,6 +440,87 @@ impl VirtualMailbox {
         self.labels.insert(label.to_string());
     }
 
+    /// Get every known label/folder name, sorted.
+    pub fn get_labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = self.labels.iter().cloned().collect();
+        labels.sort();
+        labels
+    }
+
+    /// What LIST reports: every folder name with its special-use attribute,
+    /// if any. A label carried by any message exists, and Gmail's special
+    /// folders always do. System flags such as `\Starred` are not folders.
+    pub fn list_mailboxes(&self) -> Vec<(String, Option<&'static str>)> {
+        let mut names: HashSet<String> = self.get_labels().into_iter().filter(|l| !l.starts_with('\\')).collect();
+        for msg in self.messages.values() {
+            names.extend(msg.labels.iter().filter(|l| !l.starts_with('\\')).cloned());
+        }
+        names.extend(SPECIAL_USE.iter().map(|(name, _)| name.to_string()));
+
+        let mut listed: Vec<(String, Option<&'static str>)> = names
+            .into_iter()
+            .map(|name| {
+                let special_use = SPECIAL_USE.iter().find(|(n, _)| *n == name).map(|(_, attr)| *attr);
+                (name, special_use)
+            })
+            .collect();
+        listed.sort();
+        listed
+    }
+
+    /// The UID the next added message will get (IMAP UIDNEXT).
+    pub fn uid_next(&self) -> u32 {
+        self.next_uid
+    }
+
+    /// The mailbox's UIDVALIDITY.
+    pub fn uid_validity(&self) -> u32 {
+        self.uid_validity
+    }
+
+    /// Change UIDVALIDITY, as a server does when it renumbers UIDs.
+    pub fn set_uid_validity(&mut self, uid_validity: u32) {
+        self.uid_validity = uid_validity;
+    }
+
+    /// Reassign UIDs from 1 in the current order and bump UIDVALIDITY, as a
+    /// server does after rebuilding its index.
+    pub fn renumber(&mut self) {
+        let mut old_uids: Vec<u32> = self.messages.keys().copied().collect();
+        old_uids.sort_unstable();
+
+        let mut messages = HashMap::new();
+        for (new_uid, old_uid) in (1..).zip(old_uids) {
+            let mut message = self.messages.remove(&old_uid).unwrap();
+            message.uid = new_uid;
+            message.seq = new_uid;
+            messages.insert(new_uid, message);
+        }
+        self.next_uid = messages.len() as u32 + 1;
+        self.messages = messages;
+        self.uid_validity += 1;
+    }
+
+    /// The mailbox's HIGHESTMODSEQ; bumped by every message change.
+    pub fn highest_modseq(&self) -> u64 {
+        self.highest_modseq
+    }
+
+    /// Messages (deleted ones included) changed after `modseq`.
+    pub fn changed_since(&self, modseq: u64) -> Vec<&MailboxMessage> {
+        let mut changed: Vec<&MailboxMessage> = self.messages.values().filter(|m| m.modseq > modseq).collect();
+        changed.sort_by_key(|m| m.uid);
+        changed
+    }
+
+    /// Give a message the next MODSEQ.
+    fn touch(&mut self, uid: u32) {
+        if let Some(msg) = self.messages.get_mut(&uid) {
+            self.highest_modseq += 1;
+            msg.modseq = self.highest_modseq;
+        }
+    }
+
     /// Get the count of non-deleted messages.
     pub fn message_count(&self) -> usize {
         self.messages.values().filter(|m| !m.deleted).count()
@@ -256,6 +532,15 @@ impl VirtualMailbox {
     }
 }
 
+/// Gmail reports the INBOX folder as the `\\Inbox` label; store it as the folder.
+fn folder_label(label: &str) -> &str {
+    if label == "\\Inbox" {
+        "INBOX"
+    } else {
+        label
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -384,6 +669,37 @@ mod tests {
         assert!(retrieved.flags.contains("\\Deleted"));
     }
 
+    #[test]
+    fn test_changes_bump_modseq() {
+        let mut mailbox = VirtualMailbox::new();
+        let first = mailbox.add_message(make_test_message());
+        let second = mailbox.add_message(make_test_message());
+        let since = mailbox.highest_modseq();
+
+        mailbox.add_flag(first, "\\Seen");
+        let changed: Vec<u32> = mailbox.changed_since(since).iter().map(|m| m.uid).collect();
+        assert_eq!(changed, vec![first]);
+
+        mailbox.delete_message(second);
+        assert_eq!(mailbox.changed_since(since).len(), 2);
+        assert_eq!(mailbox.get_message(second).unwrap().modseq, mailbox.highest_modseq());
+    }
+
+    #[test]
+    fn test_all_mail_holds_every_message() {
+        let mut mailbox = VirtualMailbox::new();
+        let inbox = mailbox.add_message(make_test_message().with_labels(&["INBOX"]));
+        let archived = mailbox.add_message(make_test_message().with_labels(&["Archive"]));
+
+        let mut uids: Vec<u32> = mailbox.folder_messages(ALL_MAIL).iter().map(|m| m.uid).collect();
+        uids.sort();
+        assert_eq!(uids, vec![inbox, archived]);
+
+        // Removing the \Inbox label takes the message out of the INBOX folder
+        mailbox.remove_label(inbox, "\\Inbox");
+        assert!(mailbox.folder_messages("INBOX").is_empty());
+    }
+
     #[test]
     fn test_deleted_messages_excluded_from_get_all() {
         let mut mailbox = VirtualMailbox::new();
@@ -439,6 +755,24 @@ mod tests {
         assert_eq!(msg.headers.get("X-Priority"), Some(&"1".to_string()));
     }
 
+    #[test]
+    fn test_raw_header_round_trips_fields() {
+        let msg = MailboxMessage::new(0, "Subject", "from@test.com", "to@test.com", "2024-01-15")
+            .with_cc(&["cc@test.com"])
+            .with_message_id("<msg-001@test.com>")
+            .with_references(&["<root@test.com>"])
+            .with_header("List-Id", "<list.test.com>");
+
+        let raw = String::from_utf8(msg.raw_header()).unwrap();
+        assert!(raw.contains("From: from@test.com\r\n"));
+        assert!(raw.contains("To: to@test.com\r\n"));
+        assert!(raw.contains("Cc: cc@test.com\r\n"));
+        assert!(raw.contains("Message-ID: <msg-001@test.com>\r\n"));
+        assert!(raw.contains("References: <root@test.com>\r\n"));
+        assert!(raw.contains("List-Id: <list.test.com>\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+    }
+
     #[test]
     fn test_create_label() {
         let mut mailbox = VirtualMailbox::new();
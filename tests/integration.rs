@@ -9,6 +9,7 @@ mod harness;
 mod harness_tests {
     use super::harness::*;
     use chrono::{Duration, Utc};
+    use imap_filter::client_ops::ChangeTracking;
     use std::sync::{Arc, RwLock};
 
     // ===== VirtualClock integration tests =====
@@ -613,7 +614,7 @@ mod harness_tests {
 
         // Add a message dated now
         let uid = harness
-            .add_fixture_dated("simple/direct-message.eml", &["INBOX", "Seen"], 0)
+            .add_fixture_dated("simple/direct-message.eml", &["INBOX", "\\Seen"], 0)
             .unwrap();
 
         // Initially in INBOX
@@ -680,7 +681,7 @@ mod harness_tests {
 
         // Add a message in Purgatory (already moved there)
         let uid = harness
-            .add_fixture_dated("simple/direct-message.eml", &["Purgatory", "Seen"], 3)
+            .add_fixture_dated("simple/direct-message.eml", &["Purgatory", "\\Seen"], 3)
             .unwrap();
 
         harness.assert_has_label(uid, "Purgatory");
@@ -708,7 +709,7 @@ mod harness_tests {
 
         // Step 1: Add fresh email
         let uid = harness
-            .add_fixture_dated("simple/newsletter.eml", &["INBOX", "Seen"], 0)
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX", "\\Seen"], 0)
             .unwrap();
 
         harness.assert_no_actions();
@@ -772,6 +773,1961 @@ mod harness_tests {
         assert_eq!(harness.delete_actions().len(), 1);
     }
 
+    // ===== Engine Tests (production IMAPFilter driven through MockIMAPClient) =====
+
+    fn inline_config(yaml: &str) -> imap_filter::cfg::config::Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_engine_stars_direct_message() {
+        let mut harness = TestHarness::new();
+        let direct = harness
+            .add_fixture_with_labels("simple/direct-message.eml", &["INBOX"])
+            .unwrap();
+        let cc = harness
+            .add_fixture_with_labels("simple/with-cc.eml", &["INBOX"])
+            .unwrap();
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - only-me-star:
+      to: ['me@example.com']
+      cc: []
+      action: Star
+state-filters: []
+"#,
+        );
+
+        harness.run_filters(config).unwrap();
+
+        harness.assert_starred(direct);
+        assert!(!harness.star_actions().iter().any(|a| a.is_star_for(cc)));
+    }
+
+    #[test]
+    fn test_engine_moves_whole_thread() {
+        let mut harness = TestHarness::new();
+        let fixtures = harness.load_fixtures_from_directory("threads/thread-01").unwrap();
+        let uids: Vec<u32> = fixtures
+            .into_iter()
+            .map(|f| harness.add_message_with_labels(f.message, &["INBOX"]))
+            .collect();
+
+        // Only the replies come from bob, but the whole conversation moves
+        let config = inline_config(
+            r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+state-filters: []
+"#,
+        );
+
+        harness.run_filters(config).unwrap();
+
+        for uid in uids {
+            harness.assert_moved_to(uid, "Projects");
+        }
+        harness.assert_message_count("INBOX", 0);
+        harness.assert_message_count("Projects", 3);
+    }
+
+    #[test]
+    fn test_engine_state_filters_use_virtual_clock() {
+        let start_time = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut harness = TestHarness::at_time(start_time);
+
+        let read = harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX", "\\Seen"], 0)
+            .unwrap();
+        let starred = harness
+            .add_fixture_dated("simple/important-from-boss.eml", &["INBOX", "Starred"], 0)
+            .unwrap();
+
+        // Nothing has aged yet
+        harness
+            .run_filters(harness.load_config("state-transitions.yml").unwrap())
+            .unwrap();
+        assert!(harness.move_actions().is_empty());
+
+        // Past the 7-day read TTL: the read message is culled, the starred one is kept
+        harness.advance_days(8);
+        harness
+            .run_filters(harness.load_config("state-transitions.yml").unwrap())
+            .unwrap();
+
+        harness.assert_moved_to(read, "Purgatory");
+        harness.assert_has_label(starred, "INBOX");
+        harness.assert_message_count("Purgatory", 1);
+    }
+
+    #[test]
+    fn test_engine_dry_run_leaves_mailbox_untouched() {
+        let start_time = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut harness = TestHarness::at_time(start_time);
+
+        let fixtures = harness.load_fixtures_from_directory("threads/thread-01").unwrap();
+        let uids: Vec<u32> = fixtures
+            .into_iter()
+            .map(|mut f| {
+                f.message.date = harness.now().to_rfc3339();
+                harness.add_message_with_labels(f.message, &["INBOX", "\\Seen"])
+            })
+            .collect();
+        harness.advance_days(30);
+
+        let config = harness.load_config("state-transitions.yml").unwrap();
+        let plan = harness.plan_filters(config).unwrap();
+
+        // Every action is planned, none is applied
+        assert!(plan.dry_run);
+        assert!(harness.move_actions().is_empty());
+        harness.assert_message_count("INBOX", 3);
+
+        // The whole thread is pulled in through the first message that matched
+        assert_eq!(plan.len(), 3);
+        let thread_id = plan.actions[0].thread_id.clone();
+        assert!(thread_id.is_some());
+        for action in &plan.actions {
+            assert!(uids.contains(&action.uid));
+            assert_eq!(action.phase, imap_filter::plan::Phase::StateFilter);
+            assert_eq!(action.filter, "Cull");
+            assert_eq!(action.op, imap_filter::plan::PlannedOp::Move("Purgatory".to_string()));
+            assert_eq!(action.thread_id, thread_id);
+            assert_eq!(action.matched_uid, plan.actions[0].matched_uid);
+        }
+
+        // A real run applies exactly what was planned
+        let config = harness.load_config("state-transitions.yml").unwrap();
+        let applied = harness.run_filters(config).unwrap();
+        assert!(!applied.dry_run);
+        let planned: Vec<_> = plan
+            .actions
+            .iter()
+            .map(|a| imap_filter::plan::PlannedAction {
+                outcome: imap_filter::plan::ActionOutcome::Applied,
+                ..a.clone()
+            })
+            .collect();
+        assert_eq!(applied.actions, planned);
+        harness.assert_message_count("Purgatory", 3);
+    }
+
+    // ===== Multi-Mailbox Tests =====
+
+    const PURGATORY_CONFIG: &str = r#"
+mailboxes: [INBOX, Purgatory]
+message-filters: []
+state-filters:
+  - Cull:
+      mailbox: INBOX
+      ttl: 7d
+      action: Purgatory
+  - Purge:
+      mailbox: Purgatory
+      ttl: 3d
+      action: Oblivion
+"#;
+
+    #[test]
+    fn test_state_filters_see_scanned_mailboxes() {
+        let mut harness = TestHarness::new();
+        let aging = harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX"], 10)
+            .unwrap();
+        let doomed = harness
+            .add_fixture_dated("simple/direct-message.eml", &["Purgatory"], 10)
+            .unwrap();
+
+        let plan = harness.run_filters(inline_config(PURGATORY_CONFIG)).unwrap();
+
+        harness.assert_moved_to(aging, "Purgatory");
+        harness.assert_moved_to(doomed, "Oblivion");
+        let sources: Vec<(u32, String)> = harness
+            .move_actions()
+            .iter()
+            .filter_map(|a| match a {
+                RecordedAction::Move { uid, from, .. } => Some((*uid, from.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            sources,
+            vec![(aging, "INBOX".to_string()), (doomed, "Purgatory".to_string())]
+        );
+        let planned: Vec<(&str, u32)> = plan.actions.iter().map(|a| (a.mailbox.as_str(), a.uid)).collect();
+        assert_eq!(planned, vec![("INBOX", aging), ("Purgatory", doomed)]);
+
+        // Moved into Purgatory during this run: not purged until a later one
+        harness.assert_has_label(aging, "Purgatory");
+    }
+
+    #[test]
+    fn test_filters_scoped_to_other_mailboxes_do_not_apply() {
+        let mut harness = TestHarness::new();
+        let kept = harness
+            .add_fixture_dated("simple/newsletter.eml", &["Purgatory"], 5)
+            .unwrap();
+
+        // Cull (7d) is scoped to INBOX, so Purge (3d) is the only candidate
+        // for a Purgatory message, and a 5-day-old one is purged
+        harness.run_filters(inline_config(PURGATORY_CONFIG)).unwrap();
+        harness.assert_moved_to(kept, "Oblivion");
+        assert_eq!(harness.move_actions().len(), 1);
+    }
+
+    #[test]
+    fn test_all_mail_moves_by_label() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let bob = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Design review", "bob@example.com", "me@example.com", &now),
+            &["INBOX", "Work"],
+        );
+        let stale = harness
+            .add_fixture_dated("simple/newsletter.eml", &["Purgatory"], 10)
+            .unwrap();
+
+        let config = format!(
+            r#"
+mailboxes: ["{}"]
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      label: INBOX
+      action: Projects
+state-filters:
+  - Purge:
+      label: Purgatory
+      ttl: 3d
+      action: Oblivion
+"#,
+            ALL_MAIL
+        );
+        harness.run_filters(inline_config(&config)).unwrap();
+
+        // No MOVE out of All Mail (that would trash the message): the
+        // destination label is added and the selecting labels removed
+        assert!(harness.move_actions().is_empty());
+        harness.assert_has_label(bob, "Projects");
+        harness.assert_has_label(bob, "Work");
+        harness.assert_not_has_label(bob, "INBOX");
+        harness.assert_has_label(stale, "Oblivion");
+        harness.assert_not_has_label(stale, "Purgatory");
+    }
+
+    // ===== Multi-Account Tests =====
+
+    const ACCOUNTS_CONFIG: &str = r#"
+imap-domain: imap.example.com
+filter-sets:
+  cleanup:
+    state-filters:
+      - Cull:
+          ttl: 7d
+          action: Purgatory
+accounts:
+  - personal:
+      imap-username: me@example.com
+      filter-sets: [cleanup]
+      message-filters:
+        - from-bob:
+            from: 'bob@*'
+            action: Star
+  - broken:
+      imap-username: nobody@example.com
+      filter-sets: cleanup
+  - work:
+      imap-username: work@example.com
+      filter-sets: [cleanup]
+"#;
+
+    #[test]
+    fn test_accounts_run_in_isolation() {
+        let accounts = inline_config(ACCOUNTS_CONFIG).into_accounts().unwrap();
+
+        let mut personal = TestHarness::new();
+        let from_bob = personal.add_message(inbox_message("Lunch?", "bob@example.com", "2024-01-15T10:00:00Z"));
+        let mut work = TestHarness::new();
+        let stale = work.add_fixture_dated("simple/newsletter.eml", &["INBOX"], 10).unwrap();
+
+        let report = imap_filter::report::RunReport::collect(&accounts, |account| {
+            match account.config.imap_username.as_deref() {
+                Some("me@example.com") => personal.run_filters(account.config.clone()),
+                Some("work@example.com") => work.run_filters(account.config.clone()),
+                _ => Err(eyre::eyre!("IMAP login failed: authentication rejected")),
+            }
+        });
+
+        // The failing account is reported and the ones after it still run
+        assert_eq!(report.failed(), vec!["broken"]);
+        personal.assert_starred(from_bob);
+        work.assert_moved_to(stale, "Purgatory");
+        assert!(work.star_actions().is_empty());
+
+        let text = report.to_string();
+        assert!(text.contains("== personal =="));
+        assert!(text.contains("== work =="));
+        assert!(text.contains("FAILED: IMAP login failed"));
+    }
+
+    // ===== Incremental Run Tests (persisted sync state) =====
+
+    #[test]
+    fn test_incremental_run_skips_processed_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+
+        let bob = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Design review", "bob@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+        let carol = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Newsletter", "carol@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+
+        let bob_only = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+state-filters: []
+"#;
+        harness
+            .run_filters_incremental(inline_config(bob_only), &state_path)
+            .unwrap();
+        harness.assert_moved_to(bob, "Projects");
+        harness.assert_has_label(carol, "INBOX");
+
+        // carol's first message was already processed, so a new filter only
+        // applies to mail that arrives afterwards
+        let carol_new = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Newsletter #2", "carol@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+        let with_carol = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+  - from-carol:
+      from: 'carol@*'
+      action: Lists
+state-filters: []
+"#;
+        let plan = harness
+            .run_filters_incremental(inline_config(with_carol), &state_path)
+            .unwrap();
+        assert_eq!(plan.actions.iter().map(|a| a.uid).collect::<Vec<_>>(), vec![carol_new]);
+        harness.assert_moved_to(carol_new, "Lists");
+        harness.assert_has_label(carol, "INBOX");
+
+        let state = imap_filter::sync_state::SyncState::load(&state_path, "test-account").unwrap();
+        let inbox = state.mailbox("INBOX").unwrap();
+        assert_eq!(inbox.uid_validity, Some(1));
+        assert_eq!(inbox.uid_next, Some(carol_new + 1));
+        // UIDs moved out during a run are forgotten on the next one
+        assert_eq!(
+            inbox.processed.iter().copied().collect::<Vec<_>>(),
+            vec![carol, carol_new]
+        );
+    }
+
+    #[test]
+    fn test_incremental_run_state_filters_see_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new();
+        let read = harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX", "\\Seen"], 0)
+            .unwrap();
+
+        harness
+            .run_filters_incremental(harness.load_config("state-transitions.yml").unwrap(), &state_path)
+            .unwrap();
+        assert!(harness.move_actions().is_empty());
+
+        // Already processed by the message filters, but still aged by the state filters
+        harness.advance_days(8);
+        harness
+            .run_filters_incremental(harness.load_config("state-transitions.yml").unwrap(), &state_path)
+            .unwrap();
+        harness.assert_moved_to(read, "Purgatory");
+    }
+
+    #[test]
+    fn test_incremental_run_resets_on_uid_validity_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let uid = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Ping", "carol@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+
+        harness
+            .run_filters_incremental(inline_config("message-filters: []\nstate-filters: []\n"), &state_path)
+            .unwrap();
+
+        let carol = r#"
+message-filters:
+  - from-carol:
+      from: 'carol@*'
+      action: Lists
+state-filters: []
+"#;
+        let plan = harness
+            .run_filters_incremental(inline_config(carol), &state_path)
+            .unwrap();
+        assert!(plan.is_empty());
+
+        // The server renumbered the mailbox: every message counts as new again
+        harness.mailbox.write().unwrap().set_uid_validity(2);
+        harness
+            .run_filters_incremental(inline_config(carol), &state_path)
+            .unwrap();
+        harness.assert_moved_to(uid, "Lists");
+    }
+
+    // ===== Change Tracking Tests (CONDSTORE/QRESYNC snapshot) =====
+
+    #[test]
+    fn test_change_tracking_fetches_headers_only_for_new_mail() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new().with_change_tracking(ChangeTracking::QResync);
+        let now = harness.now().to_rfc3339();
+        let alice = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Lunch?", "alice@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+        let bob = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Design review", "bob@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+
+        let bob_only = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+state-filters: []
+"#;
+        harness
+            .run_filters_incremental(inline_config(bob_only), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.header_fetches(), vec![alice, bob]);
+        harness.assert_moved_to(bob, "Projects");
+
+        // Nothing changed: the run is served entirely from the snapshot
+        harness
+            .run_filters_incremental(inline_config(bob_only), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.header_fetches(), vec![alice, bob]);
+
+        let carol = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Newsletter", "carol@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+        harness
+            .run_filters_incremental(inline_config(bob_only), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.header_fetches(), vec![alice, bob, carol]);
+
+        let state = imap_filter::sync_state::SyncState::load(&state_path, "test-account").unwrap();
+        let inbox = state.mailbox("INBOX").unwrap();
+        assert_eq!(inbox.snapshot.keys().copied().collect::<Vec<_>>(), vec![alice, carol]);
+        assert_eq!(inbox.snapshot[&alice].to_message(alice).subject, "Lunch?");
+        assert_eq!(
+            inbox.highest_modseq,
+            Some(harness.mailbox.read().unwrap().highest_modseq())
+        );
+    }
+
+    #[test]
+    fn test_change_tracking_snapshot_keeps_only_filtered_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new().with_change_tracking(ChangeTracking::QResync);
+        let now = harness.now().to_rfc3339();
+        let uid = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Weekly digest", "news@example.com", "me@example.com", &now)
+                .with_header("List-Id", "<digest.example.com>")
+                .with_header("X-Mailer", "Mailman"),
+            &["INBOX"],
+        );
+        let snapshot_headers = || {
+            let state = imap_filter::sync_state::SyncState::load(&state_path, "test-account").unwrap();
+            let inbox = state.mailbox("INBOX").unwrap();
+            inbox.snapshot[&uid]
+                .headers
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let bob_only = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+state-filters: []
+"#;
+        harness
+            .run_filters_incremental(inline_config(bob_only), &state_path)
+            .unwrap();
+        assert!(!snapshot_headers().contains(&"List-Id".to_string()));
+        assert!(!snapshot_headers().contains(&"X-Mailer".to_string()));
+
+        // A filter on a header the snapshot lacks has it taken again
+        let lists = r#"
+message-filters:
+  - lists:
+      headers: { list-id: ['*digest*'] }
+      action: Lists
+state-filters: []
+"#;
+        harness
+            .run_filters_incremental(inline_config(lists), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.header_fetches(), vec![uid, uid]);
+        assert!(snapshot_headers().contains(&"List-Id".to_string()));
+        assert!(!snapshot_headers().contains(&"X-Mailer".to_string()));
+    }
+
+    #[test]
+    fn test_change_tracking_sees_flag_changes_from_other_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new().with_change_tracking(ChangeTracking::CondStore);
+        let uid = harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX"], 8)
+            .unwrap();
+
+        // Unread and 8 days old: inside the 21-day unread TTL
+        harness
+            .run_filters_incremental(harness.load_config("state-transitions.yml").unwrap(), &state_path)
+            .unwrap();
+        assert!(harness.move_actions().is_empty());
+
+        // Read elsewhere: now past the 7-day read TTL
+        harness.mailbox.write().unwrap().add_flag(uid, "\\Seen");
+        harness
+            .run_filters_incremental(harness.load_config("state-transitions.yml").unwrap(), &state_path)
+            .unwrap();
+        harness.assert_moved_to(uid, "Purgatory");
+        assert_eq!(harness.client.header_fetches(), vec![uid]);
+    }
+
+    #[test]
+    fn test_change_tracking_advances_modseq_without_new_mail() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut harness = TestHarness::new().with_change_tracking(ChangeTracking::QResync);
+        let now = harness.now().to_rfc3339();
+        let uid = harness.add_message_with_labels(
+            MailboxMessage::new(0, "Lunch?", "alice@example.com", "me@example.com", &now),
+            &["INBOX"],
+        );
+        let config = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Projects
+state-filters: []
+"#;
+        harness
+            .run_filters_incremental(inline_config(config), &state_path)
+            .unwrap();
+        let before = harness.mailbox.read().unwrap().highest_modseq();
+
+        // Read elsewhere, no new mail: the changes are fetched once
+        harness.mailbox.write().unwrap().add_flag(uid, "\\Seen");
+        harness
+            .run_filters_incremental(inline_config(config), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.change_fetches(), vec![before]);
+        let state = imap_filter::sync_state::SyncState::load(&state_path, "test-account").unwrap();
+        assert_eq!(
+            state.mailbox("INBOX").unwrap().highest_modseq,
+            Some(harness.mailbox.read().unwrap().highest_modseq())
+        );
+
+        // ...and not again on the next run
+        harness
+            .run_filters_incremental(inline_config(config), &state_path)
+            .unwrap();
+        assert_eq!(harness.client.change_fetches(), vec![before]);
+        assert_eq!(harness.client.header_fetches(), vec![uid]);
+    }
+
+    #[test]
+    fn test_session_fetches_changes_since_modseq() {
+        use imap_filter::IMAPClientOps;
+
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let now = Utc::now().to_rfc3339();
+        let read = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Lunch?", "friend@example.com", &now));
+        let archived = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Disk full", "alerts@example.com", &now));
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let mut session = server.connect().unwrap();
+        assert_eq!(session.enable_change_tracking().unwrap(), ChangeTracking::QResync);
+        let status = session.select_mailbox("INBOX").unwrap();
+        let since = status.highest_modseq.unwrap();
+        assert_eq!(since, mailbox.read().unwrap().highest_modseq());
+
+        mailbox.write().unwrap().add_flag(read, "\\Seen");
+        mailbox.write().unwrap().move_message(archived, "INBOX", "Archive");
+
+        let changes = session.fetch_changes(since, true).unwrap();
+        assert_eq!(changes.updated.len(), 1);
+        assert_eq!(changes.updated[0].uid, read);
+        assert!(changes.updated[0].labels.contains(&"\\Seen".to_string()));
+        assert_eq!(changes.vanished, Some(vec![archived]));
+        assert!(server.commands().contains(&format!(
+            "UID FETCH 1:* (UID FLAGS X-GM-LABELS) (CHANGEDSINCE {} VANISHED)",
+            since
+        )));
+    }
+
+    #[test]
+    fn test_incremental_run_over_session_uses_change_tracking() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let harness = TestHarness::new();
+        let aged = (Utc::now() - Duration::days(8)).to_rfc3339();
+        let uid =
+            harness
+                .mailbox
+                .write()
+                .unwrap()
+                .add_message(inbox_message("Weekly digest", "news@example.com", &aged));
+        let server = StandInServer::start(Arc::clone(&harness.mailbox));
+
+        let run = || {
+            let state = imap_filter::sync_state::SyncState::load(&state_path, "test-account").unwrap();
+            imap_filter::IMAPFilter::new(
+                server.connect().unwrap(),
+                harness.load_config("state-transitions.yml").unwrap(),
+            )
+            .with_sync_state(state)
+            .execute()
+            .unwrap()
+        };
+
+        assert!(run().is_empty());
+        let header_fetches = || server.commands().iter().filter(|c| c.contains("RFC822.HEADER")).count();
+        assert_eq!(header_fetches(), 1);
+
+        // Read elsewhere: only the flag change is fetched, and the state
+        // filters see it
+        harness.mailbox.write().unwrap().add_flag(uid, "\\Seen");
+        let plan = run();
+        assert_eq!(plan.actions.iter().map(|a| a.uid).collect::<Vec<_>>(), vec![uid]);
+        harness.assert_has_label(uid, "Purgatory");
+        assert_eq!(header_fetches(), 1);
+        assert!(server.commands().iter().any(|c| c.starts_with("ENABLE QRESYNC")));
+    }
+
+    // ===== Batched Action Tests (UID sets against the stand-in server) =====
+
+    const CULL_CONFIG: &str = r#"
+message-filters:
+  - from-bob:
+      from: 'bob@*'
+      action: Star
+state-filters:
+  - Cull:
+      ttl: 7d
+      action: Purgatory
+"#;
+
+    /// Six aged INBOX messages; UIDs 1-3 and 5-6 expire, 4 is from bob.
+    fn aged_inbox() -> Arc<RwLock<VirtualMailbox>> {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let aged = (Utc::now() - Duration::days(10)).to_rfc3339();
+        for n in 1..=6 {
+            let from = if n == 4 { "bob@example.com" } else { "news@example.com" };
+            mailbox
+                .write()
+                .unwrap()
+                .add_message(inbox_message(&format!("Digest {}", n), from, &aged));
+        }
+        mailbox
+    }
+
+    fn commands_starting(server: &StandInServer, prefix: &str) -> Vec<String> {
+        server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with(prefix))
+            .collect()
+    }
+
+    #[test]
+    fn test_session_keeps_messages_without_addresses() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let aged = (Utc::now() - Duration::days(10)).to_rfc3339();
+        let mut draft = inbox_message("Unsent thoughts", "", &aged);
+        draft.from.clear();
+        draft.to.clear();
+        let draft = mailbox.write().unwrap().add_message(draft);
+        let digest = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Digest", "news@example.com", &aged));
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(CULL_CONFIG));
+        let plan = filter.execute().unwrap();
+        let mut uids: Vec<u32> = plan.actions.iter().map(|a| a.uid).collect();
+        uids.sort_unstable();
+        assert_eq!(uids, vec![draft, digest]);
+    }
+
+    #[test]
+    fn test_session_batches_actions_into_uid_sets() {
+        let mailbox = aged_inbox();
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(CULL_CONFIG));
+        let plan = filter.execute().unwrap();
+        assert_eq!(plan.len(), 6);
+
+        let moves = commands_starting(&server, "UID MOVE");
+        assert_eq!(moves.len(), 1);
+        assert!(moves[0].starts_with("UID MOVE 1:3,5:6 "));
+        let stores = commands_starting(&server, "UID STORE");
+        assert_eq!(
+            stores,
+            vec![r#"UID STORE 4 +X-GM-LABELS.SILENT ("\\Starred")"#.to_string()]
+        );
+        // Labels are listed once per session, not once per action
+        assert_eq!(commands_starting(&server, "LIST").len(), 1);
+        assert_eq!(plan.created_labels, vec!["Purgatory"]);
+        assert_eq!(commands_starting(&server, "CREATE"), vec![r#"CREATE "Purgatory""#]);
+        assert_eq!(mailbox.read().unwrap().folder_messages("Purgatory").len(), 5);
+    }
+
+    #[test]
+    fn test_failed_batch_falls_back_to_single_uids() {
+        let mailbox = aged_inbox();
+        let server = StandInServer::start(Arc::clone(&mailbox));
+        server.reject_uid(3);
+
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(CULL_CONFIG));
+        let plan = filter.execute().unwrap();
+        let failures = plan.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].uid, 3);
+        match &failures[0].outcome {
+            imap_filter::plan::ActionOutcome::Failed(err) => {
+                assert!(err.contains("failed for UIDs 3 (1 of 5)"), "{}", err)
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+
+        let moves = commands_starting(&server, "UID MOVE");
+        let sets: Vec<&str> = moves.iter().map(|c| c.split(' ').nth(2).unwrap()).collect();
+        assert_eq!(sets, vec!["1:3,5:6", "1", "2", "3", "5", "6"]);
+        let purgatory: Vec<u32> = mailbox
+            .read()
+            .unwrap()
+            .folder_messages("Purgatory")
+            .iter()
+            .map(|m| m.uid)
+            .collect();
+        assert_eq!(purgatory.len(), 4);
+        assert!(!purgatory.contains(&3));
+    }
+
+    // ===== Chunked Fetch Tests =====
+
+    #[test]
+    fn test_large_mailbox_is_fetched_in_uid_chunks() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let now = Utc::now().to_rfc3339();
+        for n in 1..=6 {
+            let mut message = inbox_message(&format!("Digest {}", n), "news@example.com", &now);
+            if n == 2 {
+                message = message.with_message_id("<root@example.com>");
+            }
+            if n == 5 {
+                message = message
+                    .with_message_id("<reply@example.com>")
+                    .with_in_reply_to("<root@example.com>")
+                    .with_header("List-Id", "<digest.example.com>");
+            }
+            mailbox.write().unwrap().add_message(message);
+        }
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - digests:
+      headers:
+        List-Id: ['*digest*']
+      action: Star
+"#,
+        );
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), config);
+        filter.fetch_chunk = 4;
+        let plan = filter.execute().unwrap();
+
+        let fetches = commands_starting(&server, "UID FETCH");
+        let sets: Vec<&str> = fetches.iter().map(|c| c.split(' ').nth(2).unwrap()).collect();
+        assert_eq!(sets, vec!["1:4", "5:6"]);
+        // The thread spans both chunks and the matched header survives trimming
+        let starred: Vec<u32> = plan.actions.iter().map(|a| a.uid).collect();
+        assert_eq!(starred, vec![2, 5]);
+        assert_eq!(
+            commands_starting(&server, "UID STORE"),
+            vec![r#"UID STORE 2,5 +X-GM-LABELS.SILENT ("\\Starred")"#.to_string()]
+        );
+    }
+
+    // ===== Multi-Action Tests =====
+
+    const LISTS_CONFIG: &str = r#"
+message-filters:
+  - lists:
+      from: '*@lists.example'
+      action: [Star, Flag, Lists]
+"#;
+
+    fn list_mail_server() -> (Arc<RwLock<VirtualMailbox>>, StandInServer) {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let now = Utc::now().to_rfc3339();
+        for n in 1..=3 {
+            mailbox
+                .write()
+                .unwrap()
+                .add_message(inbox_message(&format!("Issue {}", n), "dev@lists.example", &now));
+        }
+        let server = StandInServer::start(Arc::clone(&mailbox));
+        (mailbox, server)
+    }
+
+    fn outcomes_of(plan: &imap_filter::plan::Plan, uid: u32) -> Vec<imap_filter::plan::ActionOutcome> {
+        plan.actions
+            .iter()
+            .filter(|a| a.uid == uid)
+            .map(|a| a.outcome.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_every_action_applies_in_order() {
+        let (mailbox, server) = list_mail_server();
+
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(LISTS_CONFIG));
+        let plan = filter.execute().unwrap();
+
+        assert_eq!(plan.len(), 9);
+        assert!(plan.failures().is_empty());
+        let actions: Vec<String> = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID STORE") || c.starts_with("UID MOVE"))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                r#"UID STORE 1:3 +X-GM-LABELS.SILENT ("\\Starred")"#.to_string(),
+                r#"UID STORE 1:3 +X-GM-LABELS.SILENT ("\\Important")"#.to_string(),
+                r#"UID MOVE 1:3 "Lists""#.to_string(),
+            ]
+        );
+        assert_eq!(mailbox.read().unwrap().folder_messages("Lists").len(), 3);
+    }
+
+    #[test]
+    fn test_failed_action_skips_the_rest_for_that_message() {
+        let (mailbox, server) = list_mail_server();
+        server.reject_uid(2);
+
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(LISTS_CONFIG));
+        let plan = filter.execute().unwrap();
+
+        use imap_filter::plan::ActionOutcome::{Applied, Failed, Skipped};
+        assert!(matches!(
+            outcomes_of(&plan, 2).as_slice(),
+            [Failed(_), Skipped, Skipped]
+        ));
+        assert_eq!(outcomes_of(&plan, 1), vec![Applied, Applied, Applied]);
+        assert_eq!(plan.failures().len(), 3);
+        assert_eq!(commands_starting(&server, "UID MOVE"), vec![r#"UID MOVE 1,3 "Lists""#]);
+        assert!(mailbox.read().unwrap().get_message(2).unwrap().labels.contains("INBOX"));
+    }
+
+    #[test]
+    fn test_on_error_continue_tries_every_action() {
+        let (_mailbox, server) = list_mail_server();
+        server.reject_uid(2);
+
+        let config = inline_config(&format!("{}      on-error: continue\n", LISTS_CONFIG));
+        let mut filter = imap_filter::IMAPFilter::new(server.connect().unwrap(), config);
+        let plan = filter.execute().unwrap();
+
+        let outcomes = outcomes_of(&plan, 2);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes
+            .iter()
+            .all(|o| matches!(o, imap_filter::plan::ActionOutcome::Failed(_))));
+        assert_eq!(commands_starting(&server, "UID MOVE")[0], r#"UID MOVE 1:3 "Lists""#);
+    }
+
+    // ===== Filter Ordering Tests (stop, priority, groups) =====
+
+    fn filters_applied(plan: &imap_filter::plan::Plan, uid: u32) -> Vec<String> {
+        plan.actions
+            .iter()
+            .filter(|a| a.uid == uid)
+            .map(|a| format!("{}: {}", a.filter, a.op))
+            .collect()
+    }
+
+    #[test]
+    fn test_stop_false_lets_later_filters_act() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let notification = harness.add_message(
+            inbox_message("[repo] Build failed", "boss@example.com", &now).with_header("List-Id", "<repo.github.com>"),
+        );
+        let direct = harness.add_message(inbox_message("Lunch?", "boss@example.com", &now));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - manager:
+      from: 'boss@example.com'
+      stop: false
+      action: Star
+  - github:
+      headers:
+        List-Id: ['*github*']
+      action: GitHub
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        assert_eq!(
+            filters_applied(&plan, notification),
+            vec!["manager: Star", "github: Move → GitHub"]
+        );
+        harness.assert_starred(notification);
+        harness.assert_moved_to(notification, "GitHub");
+        assert_eq!(filters_applied(&plan, direct), vec!["manager: Star"]);
+    }
+
+    #[test]
+    fn test_priorities_and_groups_order_filters() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let urgent = harness.add_message(inbox_message("URGENT: prod is down", "boss@example.com", &now));
+        let routine = harness.add_message(inbox_message("Weekly sync", "boss@example.com", &now));
+        let listed = harness.add_message(inbox_message("Digest", "dev@lists.example", &now));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - catch-all:
+      from: '*'
+      action: Star
+  - lists:
+      priority: -2
+      from: '*@lists.example'
+      stop: false
+      action: Lists
+  - urgent:
+      priority: -1
+      subject: ['URGENT*']
+      group: triage
+      stop: false
+      action: Flag
+  - important:
+      priority: -1
+      from: 'boss@*'
+      group: triage
+      stop: false
+      action: Star
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        // Only the first match of the triage group acts
+        assert_eq!(filters_applied(&plan, urgent), vec!["urgent: Flag", "catch-all: Star"]);
+        // The catch-all's Star was already taken by the group
+        assert_eq!(filters_applied(&plan, routine), vec!["important: Star"]);
+        // A move ends evaluation even without `stop`
+        assert_eq!(filters_applied(&plan, listed), vec!["lists: Move → Lists"]);
+    }
+
+    // ===== Condition Tree Tests =====
+
+    #[test]
+    fn test_condition_tree_filters() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let boss = harness.add_message(inbox_message("Lunch?", "boss@example.com", &now));
+        let urgent = harness.add_message(inbox_message("URGENT: prod is down", "ann@example.com", &now));
+        let bot = harness.add_message(inbox_message("URGENT: build failed", "ci-bot@example.com", &now));
+        let routine = harness.add_message(inbox_message("Weekly sync", "ann@example.com", &now));
+        let release = harness
+            .add_message(inbox_message("v1.2 released", "ci-bot@example.com", &now).with_header("X-Release", "stable"));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - important:
+      any:
+        - from: 'boss@example.com'
+        - subject: ['URGENT*']
+      not:
+        from: '*bot*'
+      action: Star
+  - releases:
+      from: '*bot*'
+      any:
+        - headers:
+            X-Release: ['stable']
+      action: Releases
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        harness.assert_starred(boss);
+        harness.assert_starred(urgent);
+        assert!(filters_applied(&plan, routine).is_empty());
+        // headers named only inside the tree survive header trimming
+        harness.assert_moved_to(release, "Releases");
+        assert!(filters_applied(&plan, bot).is_empty());
+    }
+
+    // ===== Pattern Tests =====
+
+    #[test]
+    fn test_pattern_prefixes_match_case_insensitively() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let reply = harness.add_message(inbox_message("RE: Q3 plan", "Ann@Tatari.TV", &now));
+        let other = harness.add_message(inbox_message("Re: Q3 plan", "ann@example.com", &now));
+        let ops = harness.add_message(inbox_message("[ops] disk full", "alerts@example.com", &now));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - work:
+      from: 'iglob:*@tatari.tv'
+      subject: ['iglob:re: *']
+      action: Star
+  - ops:
+      subject: ['re:^\[(ops|infra)\]']
+      action: Ops
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        harness.assert_starred(reply);
+        assert!(filters_applied(&plan, other).is_empty());
+        harness.assert_moved_to(ops, "Ops");
+    }
+
+    #[test]
+    fn test_recipients_cover_delivered_to_but_to_does_not() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let bcc = harness.add_message(
+            MailboxMessage::new(0, "Offer", "promo@shop.example", "undisclosed-recipients:;", &now)
+                .with_header("Delivered-To", "me@example.com")
+                .with_labels(&["INBOX"]),
+        );
+        let reply = harness
+            .add_message(inbox_message("Minutes", "bot@example.com", &now).with_header("Reply-To", "ann@example.com"));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - answer-ann:
+      reply-to: ann@example.com
+      action: Ann
+  - only-to-me:
+      to: me@example.com
+      action: Star
+  - blind-copies:
+      recipients: me@example.com
+      to: []
+      action: Bcc
+"#,
+        );
+        harness.run_filters(config).unwrap();
+
+        harness.assert_moved_to(bcc, "Bcc");
+        harness.assert_moved_to(reply, "Ann");
+    }
+
+    #[test]
+    fn test_impersonated_display_names_are_quarantined() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let spoofed = harness.add_message(inbox_message(
+            "Urgent wire transfer",
+            "\"ceo@example.com\" <ceo.office@mail.example.net>",
+            &now,
+        ));
+        let lookalike = harness.add_message(inbox_message(
+            "Gift cards",
+            "\"Dana Chief (CEO)\" <dana.chief@gmail.example>",
+            &now,
+        ));
+        let genuine = harness.add_message(inbox_message("Offsite", "Dana Chief <dana@example.com>", &now));
+        let ci = harness.add_message(inbox_message("Build #12", "\"Jenkins\" <ci@example.com>", &now));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - spoofed:
+      spoofed-from: true
+      action: Quarantine
+  - ceo-impersonation:
+      from-name: ['*Dana Chief*']
+      not: { from: '*@example.com' }
+      action: Quarantine
+  - builds:
+      from-name: ['iglob:*jenkins*']
+      action: Builds
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        harness.assert_moved_to(spoofed, "Quarantine");
+        harness.assert_moved_to(lookalike, "Quarantine");
+        assert!(filters_applied(&plan, genuine).is_empty());
+        harness.assert_moved_to(ci, "Builds");
+    }
+
+    #[test]
+    fn test_domain_predicates_and_internal_sender() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let colleague = harness.add_message(inbox_message("Standup", "ann@mail.tatari.tv", &now));
+        let lookalike = harness.add_message(inbox_message("Invoice", "billing@nottatari.tv", &now));
+        let shop = harness.add_message(inbox_message("Your order", "orders@bücher.example", &now));
+
+        let config = inline_config(
+            r#"
+internal-domains: [tatari.tv]
+message-filters:
+  - internal:
+      internal-sender: true
+      action: Work
+  - books:
+      from-domain: xn--bcher-kva.example
+      action: Books
+  - outside:
+      internal-sender: false
+      to-domain: example.com
+      action: External
+"#,
+        );
+        harness.run_filters(config).unwrap();
+
+        harness.assert_moved_to(colleague, "Work");
+        harness.assert_moved_to(shop, "Books");
+        harness.assert_moved_to(lookalike, "External");
+    }
+
+    #[test]
+    fn test_encoded_and_folded_headers_match_decoded() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let resume = harness.add_message(inbox_message(
+            "=?UTF-8?B?UsOpc3Vtw6kgZm9y?= Ann",
+            "ann@example.com",
+            &now,
+        ));
+        let list = harness.add_message(
+            inbox_message("Weekly digest", "news@example.com", &now)
+                .with_header("list-id", "Rust\r\n\tannounce <announce.rust-lang.org>"),
+        );
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - resumes:
+      subject: ['Résumé *']
+      action: Star
+  - rust:
+      headers: { List-ID: ['Rust announce <*>'] }
+      action: Lists
+"#,
+        );
+        harness.run_filters(config).unwrap();
+
+        harness.assert_starred(resume);
+        harness.assert_moved_to(list, "Lists");
+    }
+
+    #[test]
+    fn test_debug_logging_truncates_multibyte_subjects() {
+        log::set_max_level(log::LevelFilter::Debug);
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        // 20 characters, 60 bytes once decoded: byte 50 is mid-character
+        let notice = harness.add_message(inbox_message(
+            "=?UTF-8?B?44CQ44GK55+l44KJ44Gb44CR44K344K544OG44Og44Oh44Oz44OG44OK44Oz44K544Gu44GU5qGI5YaF?=",
+            "info@example.com",
+            &now,
+        ));
+
+        let config = inline_config(
+            r#"
+message-filters: []
+state-filters:
+  - Notices:
+      ttl: Keep
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        assert!(plan.is_empty());
+        harness.assert_has_label(notice, "INBOX");
+    }
+
+    // ===== Body Matching Tests =====
+
+    const BODY_CONFIG: &str = r#"
+message-filters:
+  - build-failures:
+      from: 'ci@example.com'
+      body: ['ire:your build (has )?failed']
+      action: Builds
+  - tickets:
+      subject: ['*ticket*']
+      body: ['re:\bOPS-\d+\b']
+      action: Star
+"#;
+
+    #[test]
+    fn test_body_predicates_fetch_only_undecided_messages() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let failed = harness
+            .add_message(inbox_message("Build #12", "ci@example.com", &now).with_body("Sorry, your build failed."));
+        let passed = harness.add_message(inbox_message("Build #13", "ci@example.com", &now).with_body("All green."));
+        let html = harness.add_message(inbox_message("Your ticket", "desk@example.com", &now).with_mime_body(
+            "Content-Type: text/html; charset=utf-8\nContent-Transfer-Encoding: base64",
+            // <p>Ticket <b>OPS-42</b> was opened</p>
+            "PHA+VGlja2V0IDxiPk9QUy00MjwvYj4gd2FzIG9wZW5lZDwvcD4=\r\n",
+        ));
+        let chatter =
+            harness.add_message(inbox_message("Lunch", "ann@example.com", &now).with_body("your build failed, OPS-1"));
+
+        let plan = harness.run_filters(inline_config(BODY_CONFIG)).unwrap();
+
+        harness.assert_moved_to(failed, "Builds");
+        harness.assert_starred(html);
+        assert!(filters_applied(&plan, passed).is_empty());
+        assert!(filters_applied(&plan, chatter).is_empty());
+        // Header predicates rule out the chatter before any body is read
+        assert_eq!(harness.client.body_fetches(), vec![failed, passed, html]);
+    }
+
+    #[test]
+    fn test_body_fetch_skips_messages_an_earlier_filter_decides() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let outage = harness.add_message(inbox_message("Outage", "status@example.com", &now).with_body("OPS-7"));
+        let ticket = harness.add_message(inbox_message("New ticket", "desk@example.com", &now).with_body("OPS-8"));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - status:
+      from: 'status@example.com'
+      action: Status
+  - tickets:
+      body: ['re:\bOPS-\d+\b']
+      action: Star
+"#,
+        );
+        harness.run_filters(config).unwrap();
+
+        harness.assert_moved_to(outage, "Status");
+        harness.assert_starred(ticket);
+        // The move stops filtering before the body filter is reached
+        assert_eq!(harness.client.body_fetches(), vec![ticket]);
+    }
+
+    #[test]
+    fn test_body_fetch_peeks_without_setting_seen() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let now = Utc::now().to_rfc3339();
+        let uid = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Build #12", "ci@example.com", &now).with_body("Your build has FAILED"));
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let plan = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(BODY_CONFIG))
+            .execute()
+            .unwrap();
+
+        assert_eq!(filters_applied(&plan, uid), vec!["build-failures: Move → Builds"]);
+        let body_fetches = commands_starting(&server, "UID FETCH");
+        assert!(
+            body_fetches.iter().any(|c| c.contains("BODY.PEEK[TEXT]")),
+            "{:?}",
+            body_fetches
+        );
+        assert!(!mailbox
+            .read()
+            .unwrap()
+            .get_message(uid)
+            .unwrap()
+            .flags
+            .contains("\\Seen"));
+    }
+
+    // ===== MIME Structure Tests =====
+
+    const MIME_CONFIG: &str = r#"
+message-filters:
+  - invoices:
+      attachment:
+        filename: 'iglob:*.pdf'
+        type: ['application/pdf']
+      action: Invoices
+  - huge:
+      attachment:
+        size: { min: 5MB }
+      action: Star
+  - invites:
+      mime-type: ['text/calendar']
+      action: Calendar
+  - bare:
+      has-attachment: false
+      size: { max: 1KB }
+      action: Short
+"#;
+
+    #[test]
+    fn test_attachment_and_mime_type_predicates() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let invoice = harness.add_message(
+            inbox_message("Invoice", "billing@example.com", &now)
+                .with_body("Attached.")
+                .with_attachment("INV-7.PDF", "application/pdf", 40_000),
+        );
+        let video = harness.add_message(
+            inbox_message("Demo", "ann@example.com", &now)
+                .with_body("Recording attached.")
+                .with_attachment("demo.mp4", "video/mp4", 8 << 20),
+        );
+        let invite = harness.add_message(
+            inbox_message("Standup", "cal@example.com", &now)
+                .with_body("You're invited")
+                .with_inline_part("text/calendar", 900),
+        );
+        let note = harness.add_message(inbox_message("Lunch?", "bob@example.com", &now).with_body("Noon?"));
+        let essay =
+            harness.add_message(inbox_message("Thoughts", "bob@example.com", &now).with_body(&"x".repeat(2000)));
+
+        let plan = harness.run_filters(inline_config(MIME_CONFIG)).unwrap();
+
+        harness.assert_moved_to(invoice, "Invoices");
+        harness.assert_starred(video);
+        harness.assert_moved_to(invite, "Calendar");
+        harness.assert_moved_to(note, "Short");
+        // `size` bounds the whole message, text included
+        assert!(filters_applied(&plan, essay).is_empty());
+        // the structure comes with the headers, so no body is downloaded
+        assert!(harness.client.body_fetches().is_empty());
+    }
+
+    #[test]
+    fn test_bodystructure_is_parsed_from_the_header_fetch() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let now = Utc::now().to_rfc3339();
+        let invoice = mailbox.write().unwrap().add_message(
+            inbox_message("Invoice", "billing@example.com", &now)
+                .with_body("Attached.")
+                .with_attachment("inv-7.pdf", "application/pdf", 40_000),
+        );
+        let invite = mailbox.write().unwrap().add_message(
+            inbox_message("Standup", "cal@example.com", &now)
+                .with_body("You're invited")
+                .with_inline_part("text/calendar", 900),
+        );
+        let essay = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Thoughts", "bob@example.com", &now).with_body(&"x".repeat(2000)));
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let plan = imap_filter::IMAPFilter::new(server.connect().unwrap(), inline_config(MIME_CONFIG))
+            .execute()
+            .unwrap();
+
+        assert_eq!(filters_applied(&plan, invoice), vec!["invoices: Move → Invoices"]);
+        assert_eq!(filters_applied(&plan, invite), vec!["invites: Move → Calendar"]);
+        // RFC822.SIZE comes back with the same fetch; unparsed it would be 0
+        assert!(filters_applied(&plan, essay).is_empty());
+        let fetches = commands_starting(&server, "UID FETCH");
+        assert!(fetches.iter().all(|c| !c.contains("[TEXT]")), "{:?}", fetches);
+    }
+
+    #[test]
+    fn test_size_and_thread_size_conditions() {
+        let mut harness = TestHarness::new();
+        let then = (harness.now() - chrono::Duration::days(3)).to_rfc3339();
+        let heavy: Vec<u32> = (0..2)
+            .map(|_| {
+                harness.add_message(
+                    inbox_message("Build artifacts", "ci@example.com", &then)
+                        .with_thread_id("artifacts")
+                        .with_attachment("build.zip", "application/zip", 6 << 20),
+                )
+            })
+            .collect();
+        let video = harness.add_message(inbox_message("Demo", "ann@example.com", &then).with_attachment(
+            "demo.mp4",
+            "video/mp4",
+            25 << 20,
+        ));
+        let scan = harness.add_message(inbox_message("Scan", "copier@example.com", &then).with_attachment(
+            "scan.pdf",
+            "application/pdf",
+            6 << 20,
+        ));
+        let note = harness.add_message(inbox_message("Lunch?", "bob@example.com", &then).with_body("Noon?"));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - huge:
+      size: { over: 20MB }
+      action: Star
+state-filters:
+  - heavy-threads:
+      thread-size: { over: 10MB }
+      ttl: 1d
+      action: Purge
+  - large:
+      size: { over: 5MB, under: 20MB }
+      ttl: 2d
+      action: Large
+  - rest:
+      ttl: 30d
+      action: Archive
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        // 6MB each, but 12MB together: the thread expires as one
+        for uid in heavy {
+            harness.assert_moved_to(uid, "Purge");
+        }
+        harness.assert_moved_to(scan, "Large");
+        harness.assert_starred(video);
+        // the video is over `large`'s bound but not part of a heavy thread
+        assert_eq!(filters_applied(&plan, video), vec!["huge: Star"]);
+        assert!(filters_applied(&plan, note).is_empty());
+    }
+
+    // ===== Date Predicate Tests =====
+
+    #[test]
+    fn test_age_and_weekday_predicates_follow_the_virtual_clock() {
+        // A Monday morning
+        let start = chrono::DateTime::parse_from_rfc3339("2024-06-03T09:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut harness = TestHarness::at_time(start);
+        let now = harness.now().to_rfc3339();
+        let receipt = harness.add_message(inbox_message("Your receipt", "noreply@shop.example", &now));
+        // written on Saturday, delivered on Monday
+        let hike = harness.add_message(
+            inbox_message("Hike?", "ann@example.com", &now).with_header("Date", "Sat, 1 Jun 2024 10:00:00 +0200"),
+        );
+
+        let config = r#"
+message-filters:
+  - stale-noreply:
+      from: 'noreply@*'
+      age: { over: 2d }
+      action: Archive
+  - weekend:
+      date: header
+      weekday: [Sat, Sun]
+      action: Weekend
+"#;
+        harness.run_filters(inline_config(config)).unwrap();
+        harness.assert_moved_to(hike, "Weekend");
+        harness.assert_message_count("Archive", 0);
+
+        harness.advance_days(3);
+        harness.run_filters(inline_config(config)).unwrap();
+        harness.assert_moved_to(receipt, "Archive");
+    }
+
+    // ===== Flag Predicate Tests =====
+
+    #[test]
+    fn test_flag_predicates_read_imap_flags() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let aged = (Utc::now() - Duration::days(10)).to_rfc3339();
+        let add = |message: MailboxMessage| mailbox.write().unwrap().add_message(message);
+        let waiting = add(inbox_message("Contract?", "ann@example.com", &aged).with_flags(&["\\Seen"]));
+        let replied = add(inbox_message("Lunch?", "bob@example.com", &aged).with_flags(&["\\Seen", "\\Answered"]));
+        let forwarded =
+            add(inbox_message("Specs", "cat@example.com", &aged).with_flags(&["\\Seen", "\\Answered", "$Forwarded"]));
+        let server = StandInServer::start(Arc::clone(&mailbox));
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - nudge:
+      to: 'me@example.com'
+      answered: false
+      age: { over: 3d }
+      action: Flag
+state-filters:
+  - keep-forwarded:
+      keywords: ['$Forwarded']
+      ttl: Keep
+  - read:
+      seen: true
+      answered: true
+      ttl: 7d
+      action: Archive
+"#,
+        );
+        let plan = imap_filter::IMAPFilter::new(server.connect().unwrap(), config)
+            .execute()
+            .unwrap();
+
+        assert_eq!(filters_applied(&plan, waiting), vec!["nudge: Flag"]);
+        assert_eq!(filters_applied(&plan, replied), vec!["read: Move → Archive"]);
+        assert!(filters_applied(&plan, forwarded).is_empty());
+    }
+
+    // ===== Label Registry Tests =====
+
+    #[test]
+    fn test_special_use_destinations_resolve() {
+        let mut harness = TestHarness::new();
+        let now = harness.now().to_rfc3339();
+        let spam = harness.add_message(inbox_message("You won!", "prize@spam.example", &now));
+        let stale = harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX"], 10)
+            .unwrap();
+
+        let config = inline_config(
+            r#"
+message-filters:
+  - prizes:
+      from: '*@spam.example'
+      action: '\Junk'
+state-filters:
+  - Cull:
+      ttl: 7d
+      action: '\Trash'
+"#,
+        );
+        let plan = harness.run_filters(config).unwrap();
+
+        harness.assert_moved_to(spam, SPAM);
+        harness.assert_moved_to(stale, TRASH);
+        assert!(plan.created_labels.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_special_use_fails_before_acting() {
+        let mut harness = TestHarness::new();
+        harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX"], 10)
+            .unwrap();
+
+        let config = inline_config(
+            r#"
+message-filters: []
+state-filters:
+  - Cull:
+      ttl: 7d
+      action: '\Archive'
+"#,
+        );
+        let err = harness.run_filters(config).unwrap_err().to_string();
+
+        assert!(err.contains("Filter 'Cull'"), "{}", err);
+        harness.assert_no_actions();
+    }
+
+    #[test]
+    fn test_plan_reports_created_labels() {
+        let mut harness = TestHarness::new();
+        harness
+            .add_fixture_dated("simple/newsletter.eml", &["INBOX"], 10)
+            .unwrap();
+        let config = || {
+            inline_config(
+                r#"
+message-filters: []
+state-filters:
+  - Cull:
+      ttl: 7d
+      action: Purgatory
+"#,
+            )
+        };
+
+        let preview = harness.plan_filters(config()).unwrap();
+        assert_eq!(preview.created_labels, vec!["Purgatory"]);
+        assert!(preview.to_string().contains("Would create label(s): Purgatory"));
+        assert!(!harness.label_exists("Purgatory"));
+
+        let applied = harness.run_filters(config()).unwrap();
+        assert_eq!(applied.created_labels, vec!["Purgatory"]);
+        assert!(harness.label_exists("Purgatory"));
+    }
+
+    // ===== Daemon Tests (IDLE against the stand-in server) =====
+
+    fn daemon_options() -> imap_filter::daemon::DaemonOptions {
+        imap_filter::daemon::DaemonOptions {
+            sweep_interval: std::time::Duration::from_secs(3600),
+            idle_timeout: std::time::Duration::from_millis(300),
+            shutdown_poll: std::time::Duration::from_millis(300),
+            initial_backoff: std::time::Duration::from_millis(10),
+            max_backoff: std::time::Duration::from_millis(50),
+            dry_run: false,
+        }
+    }
+
+    fn inbox_message(subject: &str, from: &str, date: &str) -> MailboxMessage {
+        MailboxMessage::new(0, subject, from, "me@example.com", date).with_labels(&["INBOX"])
+    }
+
+    /// Run the daemon against `server` on a background thread.
+    /// Returns the shutdown flag and the thread handle.
+    fn spawn_daemon(
+        server: &Arc<StandInServer>,
+        config: imap_filter::cfg::config::Config,
+        clock: VirtualClock,
+        options: imap_filter::daemon::DaemonOptions,
+    ) -> (
+        Arc<std::sync::atomic::AtomicBool>,
+        std::thread::JoinHandle<eyre::Result<()>>,
+    ) {
+        let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = Arc::clone(&shutdown);
+        let server = Arc::clone(server);
+        let handle = std::thread::spawn(move || {
+            imap_filter::daemon::run_daemon(|| server.connect(), config, clock, &options, &flag)
+        });
+        (shutdown, handle)
+    }
+
+    fn stop_daemon(shutdown: Arc<std::sync::atomic::AtomicBool>, handle: std::thread::JoinHandle<eyre::Result<()>>) {
+        shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
+        handle.join().unwrap().unwrap();
+    }
+
+    const ALERTS_CONFIG: &str = r#"
+message-filters:
+  - alerts:
+      from: 'alerts@*'
+      action: Alerts
+state-filters: []
+"#;
+
+    #[test]
+    fn test_daemon_filters_new_mail_pushed_by_idle() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let clock = VirtualClock::new();
+        let now = clock.now().to_rfc3339();
+        let existing = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Disk full", "alerts@example.com", &now));
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+
+        let options = imap_filter::daemon::DaemonOptions {
+            idle_timeout: std::time::Duration::from_secs(30),
+            ..daemon_options()
+        };
+        let (shutdown, handle) = spawn_daemon(&server, inline_config(ALERTS_CONFIG), clock, options);
+
+        // Mail already in INBOX at start-up is left alone
+        let in_alerts = |uid: u32| {
+            let mailbox = Arc::clone(&mailbox);
+            move || {
+                mailbox
+                    .read()
+                    .unwrap()
+                    .get_message(uid)
+                    .unwrap()
+                    .labels
+                    .contains("Alerts")
+            }
+        };
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .commands()
+            .iter()
+            .any(|c| c == "IDLE")));
+        assert!(!in_alerts(existing)());
+
+        // New mail wakes the IDLE well before its 30s timeout
+        let arrived = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("CPU hot", "alerts@example.com", &now));
+        let ignored = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Lunch?", "friend@example.com", &now));
+        assert!(wait_until(std::time::Duration::from_secs(5), in_alerts(arrived)));
+        assert!(server.exists_pushed() >= 1);
+        assert!(mailbox
+            .read()
+            .unwrap()
+            .get_message(ignored)
+            .unwrap()
+            .labels
+            .contains("INBOX"));
+
+        // The existing UID was fetched once, by the start-up sweep; the
+        // message filters only fetched new UIDs
+        let fetched: Vec<Vec<u32>> = server
+            .commands()
+            .iter()
+            .filter_map(|c| c.strip_prefix("UID FETCH "))
+            .map(|c| {
+                c.split(' ')
+                    .next()
+                    .unwrap()
+                    .split(',')
+                    .flat_map(|part| match part.split_once(':') {
+                        Some((lo, hi)) => (lo.parse().unwrap()..=hi.parse().unwrap()).collect(),
+                        None => vec![part.parse().unwrap()],
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(fetched.iter().filter(|set| set.contains(&existing)).count(), 1);
+        assert!(fetched.iter().any(|set| set.contains(&arrived)));
+
+        // Shutdown is noticed when the IDLE returns; push one more change to wake it
+        shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
+        mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Wake", "friend@example.com", &now));
+        handle.join().unwrap().unwrap();
+        assert!(server.commands().iter().any(|c| c == "LOGOUT"));
+    }
+
+    #[test]
+    fn test_daemon_logs_out_when_shutdown_flips_during_idle() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+        // IDLE would run for a minute; the shutdown poll cuts it short
+        let options = imap_filter::daemon::DaemonOptions {
+            idle_timeout: std::time::Duration::from_secs(60),
+            shutdown_poll: std::time::Duration::from_millis(100),
+            ..daemon_options()
+        };
+        let (shutdown, handle) = spawn_daemon(&server, inline_config(ALERTS_CONFIG), VirtualClock::new(), options);
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .commands()
+            .iter()
+            .any(|c| c == "IDLE")));
+
+        shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(wait_until(std::time::Duration::from_secs(5), || handle.is_finished()));
+        handle.join().unwrap().unwrap();
+        assert!(server.commands().iter().any(|c| c == "LOGOUT"));
+    }
+
+    #[test]
+    fn test_daemon_sweeps_state_filters_on_interval() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let clock = VirtualClock::new();
+        let aged = (clock.now() - Duration::days(8)).to_rfc3339();
+        let first = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Old news", "news@example.com", &aged).with_flags(&["\\Seen"]));
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+
+        let config = TestHarness::new().load_config("state-transitions.yml").unwrap();
+        let options = imap_filter::daemon::DaemonOptions {
+            sweep_interval: std::time::Duration::from_millis(200),
+            ..daemon_options()
+        };
+        let (shutdown, handle) = spawn_daemon(&server, config, clock, options);
+
+        let in_purgatory = |uid: u32| {
+            let mailbox = Arc::clone(&mailbox);
+            move || {
+                mailbox
+                    .read()
+                    .unwrap()
+                    .get_message(uid)
+                    .unwrap()
+                    .labels
+                    .contains("Purgatory")
+            }
+        };
+        assert!(wait_until(std::time::Duration::from_secs(5), in_purgatory(first)));
+
+        // A later sweep picks up mail that was already old when it arrived
+        let second = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Older news", "news@example.com", &aged).with_flags(&["\\Seen"]));
+        assert!(wait_until(std::time::Duration::from_secs(5), in_purgatory(second)));
+
+        stop_daemon(shutdown, handle);
+    }
+
+    #[test]
+    fn test_daemon_reconnects_after_connection_lost() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let clock = VirtualClock::new();
+        let now = clock.now().to_rfc3339();
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+        let (shutdown, handle) = spawn_daemon(&server, inline_config(ALERTS_CONFIG), clock, daemon_options());
+
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .commands()
+            .iter()
+            .any(|c| c == "IDLE")));
+        server.drop_connections();
+
+        let uid = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("After the drop", "alerts@example.com", &now));
+        assert!(wait_until(std::time::Duration::from_secs(5), || mailbox
+            .read()
+            .unwrap()
+            .get_message(uid)
+            .unwrap()
+            .labels
+            .contains("Alerts")));
+        assert!(server.connection_count() >= 2);
+
+        stop_daemon(shutdown, handle);
+    }
+
+    #[test]
+    fn test_daemon_survives_a_failed_pass() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let clock = VirtualClock::new();
+        let now = clock.now().to_rfc3339();
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+        let (shutdown, handle) = spawn_daemon(&server, inline_config(ALERTS_CONFIG), clock, daemon_options());
+
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .commands()
+            .iter()
+            .any(|c| c == "IDLE")));
+
+        // The first fetch of the new mail fails; the next wake-up retries it
+        server.fail_fetches(1);
+        let uid = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("Disk full", "alerts@example.com", &now));
+        assert!(wait_until(std::time::Duration::from_secs(5), || mailbox
+            .read()
+            .unwrap()
+            .get_message(uid)
+            .unwrap()
+            .labels
+            .contains("Alerts")));
+        assert!(!handle.is_finished());
+        assert_eq!(server.connection_count(), 1);
+
+        stop_daemon(shutdown, handle);
+    }
+
+    #[test]
+    fn test_daemon_reseeds_after_uid_validity_change() {
+        let mailbox = Arc::new(RwLock::new(VirtualMailbox::new()));
+        let clock = VirtualClock::new();
+        let now = clock.now().to_rfc3339();
+        for subject in ["One", "Two", "Three"] {
+            mailbox
+                .write()
+                .unwrap()
+                .add_message(inbox_message(subject, "friend@example.com", &now));
+        }
+        let server = Arc::new(StandInServer::start(Arc::clone(&mailbox)));
+        let (shutdown, handle) = spawn_daemon(&server, inline_config(ALERTS_CONFIG), clock, daemon_options());
+
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .commands()
+            .iter()
+            .any(|c| c == "IDLE")));
+
+        // The server rebuilds its index while the daemon is away: new mail
+        // now gets a UID below the daemon's old watermark of 3
+        {
+            let mut mailbox = mailbox.write().unwrap();
+            mailbox.delete_message(1);
+            mailbox.delete_message(2);
+            mailbox.expunge();
+            mailbox.renumber();
+        }
+        server.drop_connections();
+        assert!(wait_until(std::time::Duration::from_secs(5), || server
+            .connection_count()
+            >= 2));
+        let issued = server.commands().len();
+        assert!(wait_until(std::time::Duration::from_secs(5), || server.commands()
+            [issued..]
+            .iter()
+            .any(|c| c == "IDLE")));
+
+        let uid = mailbox
+            .write()
+            .unwrap()
+            .add_message(inbox_message("After the rebuild", "alerts@example.com", &now));
+        assert_eq!(uid, 2);
+        assert!(wait_until(std::time::Duration::from_secs(5), || mailbox
+            .read()
+            .unwrap()
+            .get_message(uid)
+            .unwrap()
+            .labels
+            .contains("Alerts")));
+
+        stop_daemon(shutdown, handle);
+    }
+
     // ===== Error Handling Tests =====
 
     #[test]